@@ -0,0 +1,29 @@
+use axum_demo::repo::db::{InMemoryDatabase, KVDatabase};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+
+/// Compares a multi-get that clones each value with one that visits the values by reference.
+fn bench_multi_get(c: &mut Criterion) {
+    let mut db = InMemoryDatabase::new();
+    let keys: Vec<String> = (0..100).map(|i| format!("key:{}", i)).collect();
+    for key in &keys {
+        db.upsert(key, "x".repeat(64 * 1024));
+    }
+
+    c.bench_function("multi_get_cloned", |b| {
+        b.iter(|| {
+            let values: Vec<Option<String>> = keys.iter().map(|key| db.read(key)).collect();
+            black_box(values.iter().flatten().map(String::len).sum::<usize>())
+        })
+    });
+    c.bench_function("multi_get_ref", |b| {
+        b.iter(|| {
+            let mut total = 0;
+            db.get_many_ref(&keys, &mut |_, value| total += value.map_or(0, String::len));
+            black_box(total)
+        })
+    });
+}
+
+criterion_group!(benches, bench_multi_get);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+use std::sync::Arc;
+use axum::body::Body;
+use axum::http::Request;
+use axum_demo::app::build_app;
+use axum_demo::configuration::get_configuration;
+use axum_demo::dependency::ApplicationState;
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::runtime::Runtime;
+use tower::ServiceExt;
+use tracing::Level;
+
+/// Compares the per-request overhead of a traced route with one excluded from tracing.
+fn bench_trace_exclude(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    // Format every event as in local, but discard the output.
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::sink)
+        .with_max_level(Level::TRACE)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    for (name, trace_exclude) in [("traced", vec![]), ("excluded", vec!["/".to_string()])] {
+        let mut settings = get_configuration().expect("Failed to read configuration.");
+        settings.application.trace_exclude = trace_exclude;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        c.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| {
+                app.clone()
+                    .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_trace_exclude);
+criterion_main!(benches);
@@ -0,0 +1,38 @@
+use crate::dependency::ApplicationState;
+use axum::extract::FromRequestParts;
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use tracing::warn;
+
+/// Extractor that only succeeds if the request carries the configured admin token
+/// as `Authorization: Bearer <token>`.
+///
+/// The admin API is disabled entirely if no token is configured.
+pub struct AdminAuth;
+
+impl FromRequestParts<ApplicationState> for AdminAuth {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &ApplicationState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(expected) = state.config.application.admin_token.as_deref() else {
+            return Err((StatusCode::FORBIDDEN, "Admin API is disabled."));
+        };
+
+        let provided = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        if provided == Some(expected) {
+            Ok(AdminAuth)
+        } else {
+            warn!("Rejected admin request to {} with missing or invalid token.", parts.uri);
+            Err((StatusCode::UNAUTHORIZED, "Invalid admin token."))
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::admin::auth::AdminAuth;
+use crate::admin::model::{ConcurrencyLimit, Reloaded};
+use crate::configuration::get_configuration;
+use crate::dependency::{ApplicationState, ReloadOutcome};
+use crate::response::JsonResponse;
+use axum::extract::{Json, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::Router;
+use tracing::info;
+
+pub fn get_admin_routes() -> Router<ApplicationState> {
+    Router::new()
+        .route("/concurrency", get(read_concurrency_limit).post(update_concurrency_limit))
+        .route("/reload", post(reload_configuration))
+}
+
+/// Handler function to read the current concurrency limit.
+/// # Arguments
+/// * `state`: The application state.
+async fn read_concurrency_limit(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+) -> JsonResponse<ConcurrencyLimit> {
+    JsonResponse::new(
+        ConcurrencyLimit {
+            limit: state.limiter.limit(),
+        },
+        &state.config.application,
+    )
+}
+
+/// Handler function to change the concurrency limit at runtime.
+/// # Arguments
+/// * `state`: The application state.
+/// * `payload`: The request payload that contains the new limit.
+async fn update_concurrency_limit(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Json(payload): Json<ConcurrencyLimit>,
+) -> JsonResponse<ConcurrencyLimit> {
+    let previous = state.limiter.limit();
+    state.limiter.set_limit(payload.limit);
+    info!("Concurrency limit changed from {} to {}.", previous, payload.limit);
+
+    JsonResponse::new(payload, &state.config.application)
+}
+
+/// Handler function to re-read the configuration and apply the hot-reloadable settings, see
+/// `ApplicationState::reload`.
+/// # Arguments
+/// * `state`: The application state.
+async fn reload_configuration(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+) -> Result<JsonResponse<Reloaded>, (StatusCode, String)> {
+    let settings = get_configuration().map_err(|err| {
+        let message = format!("Failed to reload configuration: {}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, message)
+    })?;
+    let ReloadOutcome {
+        changed,
+        restart_required,
+    } = state.reload(&settings);
+    info!(
+        "Configuration reloaded, {} settings applied, {} require a restart.",
+        changed.len(),
+        restart_required.len()
+    );
+
+    let reloaded = Reloaded {
+        changed,
+        restart_required,
+    };
+    Ok(JsonResponse::new(reloaded, &state.config.application))
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::build_app;
+    use crate::test_util::{body_string, send, test_settings};
+    use axum::body::Body;
+    use axum::http::{Method, Request, StatusCode};
+    use std::sync::Arc;
+
+    fn set_limit_request(token: &str, limit: usize) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/admin/concurrency")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(format!(r#"{{"limit": {}}}"#, limit)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_update_concurrency_limit() {
+        let mut settings = test_settings();
+        settings.application.admin_token = Some("secret".to_string());
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        let root = || Request::get("/").body(Body::empty()).unwrap();
+
+        // Lowering the limit to zero sheds every request.
+        let response = send(&app, set_limit_request("secret", 0)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(send(&app, root()).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = send(&app, set_limit_request("secret", 2)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(send(&app, root()).await.status(), StatusCode::OK);
+
+        let request = Request::get("/admin/concurrency")
+            .header("Authorization", "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(body_string(send(&app, request).await).await, r#"{"limit":2}"#);
+    }
+
+    #[tokio::test]
+    async fn test_admin_token_required() {
+        let mut settings = test_settings();
+        settings.application.admin_token = Some("secret".to_string());
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        let response = send(&app, set_limit_request("wrong", 0)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let response = send(&app, set_limit_request("secret", 0)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    // Note: Reloading itself is tested with `ApplicationState::reload`, since this handler reads the
+    //  configuration files and the environment variables of the process.
+    #[tokio::test]
+    async fn test_reload_requires_admin_token() {
+        let mut settings = test_settings();
+        settings.application.admin_token = Some("secret".to_string());
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        let request = Request::post("/admin/reload")
+            .header("Authorization", "Bearer wrong")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct ConcurrencyLimit {
+    pub limit: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Reloaded {
+    /// Names of the settings that changed and were applied.
+    pub changed: Vec<&'static str>,
+    /// Names of the settings that changed but only take effect on restart.
+    pub restart_required: Vec<String>,
+}
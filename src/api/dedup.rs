@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of tracked keys above which expired entries are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Remembers when each key was last upserted, so that an identical upsert (same key and value) received
+/// again within a short window can be suppressed, e.g. when a client double-submits.
+///
+/// Whether the value is identical is decided by comparing it with the stored value, so a key changed in
+/// between by any other write path, or that expired, is written again. The value replaced by the write is
+/// kept until the window passes, so a suppressed duplicate reports the same previous value as the original.
+#[derive(Debug)]
+pub struct DedupWindow {
+    window: Duration,
+    /// Time of the latest upsert of each key, and the value it replaced.
+    recent: Mutex<HashMap<String, (Instant, Option<String>)>>,
+}
+
+impl DedupWindow {
+    /// Creates a new de-dup window. A zero duration disables de-duplication.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether de-duplication is enabled, i.e. upserts need to be recorded with the value they replaced.
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// Looks up the latest upsert of the key within the window.
+    /// # Returns
+    /// * `Option<Option<String>>`: The value replaced by the upsert, or `None` if the key wasn't upserted
+    ///   within the window.
+    pub fn recent(&self, key: &str) -> Option<Option<String>> {
+        if self.window.is_zero() {
+            return None;
+        }
+        let recent = self.lock();
+        recent
+            .get(key)
+            .filter(|(written_at, _)| written_at.elapsed() < self.window)
+            .map(|(_, previous)| previous.clone())
+    }
+
+    /// Records an upsert of the key.
+    /// # Arguments
+    /// * `key`: The key being written.
+    /// * `previous`: The value replaced by the write, or `None` if the key was new.
+    pub fn record(&self, key: &str, previous: &Option<String>) {
+        if self.window.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+
+        let mut recent = self.lock();
+        if recent.len() >= PRUNE_THRESHOLD {
+            recent.retain(|_, (written_at, _)| now.duration_since(*written_at) < self.window);
+        }
+        recent.insert(key.to_string(), (now, previous.clone()));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Instant, Option<String>)>> {
+        self.recent
+            .lock()
+            // Note: This is just a hacky way to bypass mutex poisoning for demo purposes.
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
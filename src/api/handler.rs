@@ -1,53 +1,1771 @@
-use crate::api::model::Value;
+use crate::api::model::{
+    is_json_number, BatchEntryResult, BatchUpsert, BatchUpsertResult, Count, CountQuery, Exists, KeyPage,
+    LimitStatus, Limits, MergeRequest, PreviousValue, TouchQuery, Ttl, UpsertQuery, Value, ValueQuery,
+};
+use crate::api::json::DepthLimitedJson;
+use crate::api::key::{is_reserved_key, normalize_key, Key};
+use crate::api::merge::{MergeError, MergeFunction};
+use crate::api::msgpack::{
+    accepts_msgpack, msgpack_response, MsgPackScalar, MsgPackValue, ValuePayload,
+};
+use crate::api::pagination::Pagination;
+use crate::api::validation::{normalize_value, validate_value};
+use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::ops::RangeInclusive;
+use std::time::Duration;
 use axum::Router;
-use axum::extract::{Json, Path, State};
-use axum::http::StatusCode;
-use axum::routing::{get, post};
-use tracing::info;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::header::{
+    ACCEPT, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE, VARY,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use futures_util::stream;
+use tracing::field::Empty;
+use tracing::{debug, info, info_span};
+use crate::admin::auth::AdminAuth;
+use crate::configuration::{ApplicationSettings, MemoryLimitPolicy, ValueType};
+use crate::repo::db::KVDatabase;
+use crate::context::RequestContext;
 use crate::dependency::ApplicationState;
+use crate::limiter::ConcurrencyLimiter;
+use crate::middleware::key_for_logs;
+use crate::response::JsonResponse;
+
+/// Size of the chunks of streamed values, see `ApplicationSettings::stream_threshold_bytes`.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of keys serialized per chunk of streamed listings.
+const STREAM_KEYS_PER_CHUNK: usize = 1024;
 
 pub fn get_api_routes() -> Router<ApplicationState> {
     Router::new()
+        .route("/", get(list_keys))
+        .route("/b64/{encoded_key}", get(read_by_key).post(upsert_by_key))
+        .route("/batch", post(batch_upsert))
+        .route("/count", get(count_keys))
+        .route("/limits", get(read_limits))
+        .route("/prefix/{prefix}", delete(clear_prefix))
         .route("/{key}", get(read_by_key))
-        .route("/{key}", post(upsert_by_key))
+        .route("/{key}", post(upsert_by_key).put(upsert_by_query))
+        .route("/{key}/append", post(append_by_key))
+        .route("/{key}/exists", get(exists_by_key))
+        .route("/{key}/merge", post(merge_by_key))
+        .route("/{key}/setnx", post(set_if_absent))
+        .route("/{key}/touch", post(touch_by_key))
+        .route("/{key}/ttl", get(read_ttl))
 }
 
 // Note: https://github.com/tokio-rs/axum/tree/main/examples/customize-extractor-error
 
 /// Handler function to read a value by key from the database.
+///
+/// Responses for stored keys have an `X-KV-Exists: true` header, so a stored empty value (`200` with an empty
+/// body) can't be mistaken for a missing key (`404`).
+/// With `Accept: application/msgpack`, the value is returned as a MessagePack `{"value": ...}` map.
+/// With a `Range: bytes=...` header, only the requested bytes are returned with `206`, or `416` if the range
+/// is outside the value.
+/// With `ValueType::Number`, numeric values are returned as JSON numbers.
+/// Values larger than `ApplicationSettings::stream_threshold_bytes` are streamed in chunks rather than copied
+/// into a single response buffer.
+/// Responses have a weak `ETag` of the value and a `Cache-Control` header with
+/// `ApplicationSettings::read_cache_max_age_s`, and vary by the `Accept` header since it selects the format.
+/// With a matching `If-None-Match` header, `304` is returned without a body.
 /// # Arguments
 /// * `state`: The application state.
+/// * `context`: The request context.
 /// * `key`: The key to look up in the database.
+/// * `headers`: The request headers, to negotiate the response format.
 async fn read_by_key(
     State(state): State<ApplicationState>,
-    Path(key): Path<String>,
-) -> Result<String, StatusCode> {
+    context: RequestContext,
+    key: Key,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let key = key.into_string();
     let db = state.db.read().unwrap();
 
-    if let Some(value) = db.read(&key) {
-        Ok(value)
+    let Some(value) = db.read(&key) else {
+        debug!(
+            "Key '{}' not found (trace ID: {}, client: {:?}, elapsed: {:?}).",
+            key_for_logs(&key, &state.config.application),
+            context.trace_id,
+            context.client_ip,
+            context.started_at.elapsed()
+        );
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let etag = weak_etag(&value);
+    let mut response = if if_none_match(&headers, &etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        value_response(value, &headers, &state.config.application)
+    };
+    let max_age = state.config.application.read_cache_max_age_s;
+    let cache_control = if max_age > 0 {
+        format!("max-age={}", max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let response_headers = response.headers_mut();
+    response_headers.insert("X-KV-Exists", HeaderValue::from_static("true"));
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        response_headers.insert(ETAG, etag);
+    }
+    if let Ok(cache_control) = HeaderValue::from_str(&cache_control) {
+        response_headers.insert(CACHE_CONTROL, cache_control);
+    }
+    // Note: Appended, so caches also see the `Accept-Encoding` added by the compression layer.
+    response_headers.append(VARY, HeaderValue::from_static("Accept"));
+    Ok(response)
+}
+
+/// Computes a weak entity tag of a value, e.g. `W/"0123456789abcdef"`.
+///
+/// It's weak since the same value has several representations, e.g. MessagePack or a byte range.
+fn weak_etag(value: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// Whether the `If-None-Match` header matches the entity tag, using the weak comparison.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// Builds the response of a read in the format negotiated by the request headers.
+fn value_response(value: String, headers: &HeaderMap, config: &ApplicationSettings) -> Response {
+    if accepts_msgpack(headers) {
+        return msgpack_response(&MsgPackValue {
+            value: MsgPackScalar::String(value),
+        });
+    }
+    let range = headers.get(RANGE).and_then(|value| value.to_str().ok());
+    match parse_range(range, value.len()) {
+        ByteRange::Full => {}
+        ByteRange::Partial(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start(), range.end(), value.len());
+            let bytes = Bytes::from(value).slice(range);
+            return (
+                StatusCode::PARTIAL_CONTENT,
+                [(CONTENT_RANGE, content_range), (ACCEPT_RANGES, "bytes".to_string())],
+                bytes,
+            )
+                .into_response();
+        }
+        ByteRange::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(CONTENT_RANGE, format!("bytes */{}", value.len()))],
+            )
+                .into_response();
+        }
+    }
+
+    if config.value_type == ValueType::Number && is_json_number(&value) {
+        ([(CONTENT_TYPE, "application/json")], value).into_response()
+    } else if let Some(json) = parse_json_value(&value, headers, config) {
+        ([(CONTENT_TYPE, "application/json")], json).into_response()
+    } else if value.len() > config.stream_threshold_bytes {
+        ([(CONTENT_TYPE, "text/plain; charset=utf-8")], stream_chunks(value)).into_response()
+    } else {
+        value.into_response()
+    }
+}
+
+/// Parses a value that looks like a JSON object or array, if enabled with
+/// `ApplicationSettings::auto_parse_json_values` and the client accepts JSON.
+/// # Returns
+/// * `Option<String>`: The re-serialized JSON, or `None` to return the value as-is.
+fn parse_json_value(value: &str, headers: &HeaderMap, config: &ApplicationSettings) -> Option<String> {
+    if !config.auto_parse_json_values {
+        return None;
+    }
+    let accepts_json = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| media_type.split(';').next().unwrap_or_default().trim() == "application/json");
+    let looks_like_json = value.trim_start().starts_with(['{', '[']);
+    if !accepts_json || !looks_like_json {
+        return None;
+    }
+    serde_json::from_str::<serde_json::Value>(value).ok().map(|json| json.to_string())
+}
+
+/// Byte range requested by the `Range` header of a read.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range, or one that's malformed or has multiple parts, which are ignored.
+    Full,
+    Partial(RangeInclusive<usize>),
+    /// A range outside of the value.
+    Unsatisfiable,
+}
+
+/// Parses a single-part `Range` header, e.g. `bytes=0-99`, `bytes=100-` or `bytes=-100` for the last 100 bytes.
+/// # Arguments
+/// * `header`: Value of the `Range` header, if any.
+/// * `len`: Length of the value in bytes.
+fn parse_range(header: Option<&str>, len: usize) -> ByteRange {
+    let Some((start, end)) = header
+        .and_then(|header| header.trim().strip_prefix("bytes="))
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let last = len.saturating_sub(1);
+    let range = if start.is_empty() {
+        // Suffix range: the last `end` bytes.
+        match end.parse::<usize>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => len.saturating_sub(suffix)..=last,
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        match (start.parse::<usize>(), end) {
+            (Ok(start), "") => start..=last,
+            (Ok(start), end) => match end.parse::<usize>() {
+                Ok(end) if start <= end => start..=end.min(last),
+                _ => return ByteRange::Full,
+            },
+            (Err(_), _) => return ByteRange::Full,
+        }
+    };
+    if len == 0 || *range.start() >= len {
+        ByteRange::Unsatisfiable
     } else {
-        Err(StatusCode::NOT_FOUND)
+        ByteRange::Partial(range)
     }
 }
 
+/// Builds a response body that yields the value in chunks of `STREAM_CHUNK_SIZE`.
+fn stream_chunks(value: String) -> Body {
+    // Note: `Bytes::slice` shares the underlying buffer, so chunking doesn't copy the value.
+    let bytes = Bytes::from(value);
+    let chunks = (0..bytes.len()).step_by(STREAM_CHUNK_SIZE).map(move |start| {
+        let end = bytes.len().min(start + STREAM_CHUNK_SIZE);
+        Ok::<_, Infallible>(bytes.slice(start..end))
+    });
+    Body::from_stream(stream::iter(chunks))
+}
+
+/// Builds a compact JSON `KeyPage` body that serializes the keys a chunk at a time as it's sent, rather than
+/// all at once.
+fn stream_key_page(keys: Vec<String>, next_cursor: Option<String>) -> Body {
+    let len = keys.len();
+    let chunks = (0..len).step_by(STREAM_KEYS_PER_CHUNK).map(move |start| {
+        let mut chunk = String::new();
+        for (i, key) in keys[start..len.min(start + STREAM_KEYS_PER_CHUNK)].iter().enumerate() {
+            if start + i > 0 {
+                chunk.push(',');
+            }
+            chunk.push_str(&serde_json::to_string(key).expect("Strings serialize to JSON"));
+        }
+        chunk
+    });
+    let tail = format!(
+        r#"],"next_cursor":{}}}"#,
+        serde_json::to_string(&next_cursor).expect("Strings serialize to JSON")
+    );
+    let parts = std::iter::once(r#"{"keys":["#.to_string()).chain(chunks).chain(std::iter::once(tail));
+    Body::from_stream(stream::iter(parts.map(|part| Ok::<_, Infallible>(Bytes::from(part)))))
+}
+
+/// Handler function to list keys in lexicographic order, a page at a time.
+///
+/// Pages are capped at `ApplicationSettings::max_response_entries`. A page cut short by the cap is returned
+/// with `206` and `X-Truncated: true`, and its cursor resumes the listing.
+/// Internal entries in `ApplicationSettings::reserved_key_prefix` aren't listed.
+/// Pages larger than `ApplicationSettings::stream_threshold_bytes` are streamed.
+/// # Arguments
+/// * `state`: The application state.
+/// * `pagination`: The page to return. The cursor is the last key of the previous page.
+async fn list_keys(
+    State(state): State<ApplicationState>,
+    pagination: Pagination,
+) -> Response {
+    let limit = pagination.limit.min(state.config.application.max_response_entries);
+    let mut keys = state.db.read().unwrap().keys();
+    keys.retain(|key| !is_reserved_key(key, &state.config.application));
+    keys.sort_unstable();
+
+    let start = match &pagination.cursor {
+        Some(cursor) => keys.partition_point(|key| key <= cursor),
+        None => 0,
+    };
+    // Note: Take one more key than requested to tell whether there's a next page.
+    let mut keys: Vec<_> = keys
+        .into_iter()
+        .skip(start + pagination.offset)
+        .take(limit + 1)
+        .collect();
+    let next_cursor = if keys.len() > limit {
+        keys.truncate(limit);
+        keys.last().cloned()
+    } else {
+        None
+    };
+
+    let truncated = next_cursor.is_some() && limit < pagination.limit;
+    // Note: Approximates the JSON size by the keys and their quotes and separators, ignoring escapes.
+    let size: usize = keys.iter().map(|key| key.len() + 3).sum();
+    let mut response = if size > state.config.application.stream_threshold_bytes {
+        ([(CONTENT_TYPE, "application/json")], stream_key_page(keys, next_cursor)).into_response()
+    } else {
+        JsonResponse::new(KeyPage { keys, next_cursor }, &state.config.application).into_response()
+    };
+    if truncated {
+        debug!("Listing truncated to {} keys.", limit);
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response.headers_mut().insert("X-Truncated", HeaderValue::from_static("true"));
+    }
+    response
+}
+
+/// Handler function to count the keys starting with `?prefix=...`, without listing them.
+/// Internal entries in `ApplicationSettings::reserved_key_prefix` aren't counted.
+/// # Arguments
+/// * `state`: The application state.
+/// * `query`: The query parameters.
+async fn count_keys(
+    State(state): State<ApplicationState>,
+    Query(query): Query<CountQuery>,
+) -> JsonResponse<Count> {
+    let config = &state.config.application;
+    let prefix = normalize_key(query.prefix, config);
+    let reserved = &config.reserved_key_prefix;
+    let db = state.db.read().unwrap();
+
+    // Note: Internal entries only match prefixes that are in the reserved namespace or a prefix of it.
+    let count = if is_reserved_key(&prefix, config) {
+        0
+    } else if !reserved.is_empty() && reserved.starts_with(&prefix) {
+        db.count_by_prefix(&prefix) - db.count_by_prefix(reserved)
+    } else {
+        db.count_by_prefix(&prefix)
+    };
+    JsonResponse::new(Count { count }, config)
+}
+
+/// Handler function to remove all keys starting with a prefix under a single write lock, which is much
+/// cheaper than listing and deleting them one by one. Requires the admin token, see `AdminAuth`.
+///
+/// The prefix is validated like a key, see `Key::parse_prefix`, so it can't remove internal entries.
+/// # Arguments
+/// * `state`: The application state.
+/// * `prefix`: The prefix of the keys to remove.
+async fn clear_prefix(
+    _: AdminAuth,
+    State(state): State<ApplicationState>,
+    Path(prefix): Path<String>,
+) -> Result<JsonResponse<Count>, (StatusCode, String)> {
+    let prefix = Key::parse_prefix(prefix, &state.config.application).map_err(|error| {
+        info!("Rejected prefix: {}", error);
+        (StatusCode::BAD_REQUEST, error.to_string())
+    })?;
+    let prefix = prefix.into_string();
+    let count = state.db.write().unwrap().clear_prefix(&prefix);
+    info!("Removed {} keys with prefix {}.", count, key_for_logs(&prefix, &state.config.application));
+
+    Ok(JsonResponse::new(Count { count }, &state.config.application))
+}
+
+/// Handler function to report the concurrency limits and how much of them is in use, so clients can
+/// throttle themselves before getting `503`s. The in-flight counts include this request.
+/// # Arguments
+/// * `state`: The application state.
+async fn read_limits(State(state): State<ApplicationState>) -> JsonResponse<Limits> {
+    let status = |limiter: &ConcurrencyLimiter| LimitStatus {
+        limit: limiter.limit(),
+        in_flight: limiter.in_flight(),
+    };
+
+    JsonResponse::new(
+        Limits {
+            concurrency: status(&state.limiter),
+            reads: status(&state.read_limiter),
+            writes: status(&state.write_limiter),
+        },
+        &state.config.application,
+    )
+}
+
+/// Handler function to check whether a key exists in the database.
+///
+/// Responds with `200` and a JSON boolean in both cases, for clients that treat `404` as an error.
+/// # Arguments
+/// * `state`: The application state.
+/// * `key`: The key to look up in the database.
+async fn exists_by_key(
+    State(state): State<ApplicationState>,
+    key: Key,
+) -> JsonResponse<Exists> {
+    let key = key.into_string();
+    let db = state.db.read().unwrap();
+
+    JsonResponse::new(
+        Exists {
+            exists: db.contains_key(&key),
+        },
+        &state.config.application,
+    )
+}
+
 /// Handler function to upsert a value by key in the database.
+///
+/// With `?return_prev=true`, the replaced value is returned as JSON instead of a message. A duplicate
+/// suppressed by the de-dup window returns the value replaced by the original upsert.
+/// With `?ttl=N`, the key expires after `N` seconds, otherwise after `ApplicationSettings::default_ttl_s`.
+/// A TTL of 0 means the key never expires.
 /// # Arguments
 /// * `state`: The application state.
 /// * `key`: The key to upsert in the database.
-/// * `payload`: The request payload that contains the value.
+/// * `query`: The query parameters.
+/// * `payload`: The request payload that contains the value, in JSON or MessagePack.
 async fn upsert_by_key(
     State(state): State<ApplicationState>,
-    Path(key): Path<String>,
-    Json(payload): Json<Value>,
-) -> Result<String, StatusCode> {
+    key: Key,
+    Query(query): Query<UpsertQuery>,
+    ValuePayload(mut payload): ValuePayload,
+) -> Result<Response, (StatusCode, String)> {
+    let key = key.into_string();
     let mut db = state.db.write().unwrap();
+    payload.value = normalize_value(payload.value, &state.config.application.value_normalization);
 
-    if payload.value.is_empty() {
-        info!("Value for key '{}' is empty, skipping upsert...", key);
-        Err(StatusCode::BAD_REQUEST)
+    if let Err(error) = validate_value(&payload.value, &state.config.application) {
+        let logged_key = key_for_logs(&key, &state.config.application);
+        info!("Value for key '{}' is invalid, skipping upsert: {}", logged_key, error);
+        return Err((StatusCode::BAD_REQUEST, error.to_string()));
+    }
+    // Note: Compared under the write lock, so a value changed in between by any write path isn't masked.
+    let duplicate = state
+        .dedup
+        .recent(&key)
+        .filter(|_| db.read(&key).as_ref() == Some(&payload.value));
+    let previous = if let Some(previous) = duplicate {
+        let logged_key = key_for_logs(&key, &state.config.application);
+        info!("Duplicate upsert for key '{}' within the de-dup window, skipping write...", logged_key);
+        previous
     } else {
-        db.upsert(&key, payload.value);
-        Ok(format!("Value written for key: {}", key))
+        // Note: Only reserved once the value is going to be written, so a suppressed duplicate never evicts.
+        reserve_memory(&mut *db, &key, payload.value.len(), &state.config.application)
+            .map_err(|message| (StatusCode::INSUFFICIENT_STORAGE, message))?;
+        if query.return_prev || state.dedup.is_enabled() {
+            let previous = db.swap(&key, payload.value);
+            state.dedup.record(&key, &previous);
+            previous
+        } else {
+            db.upsert(&key, payload.value);
+            None
+        }
+    };
+    let ttl = query.ttl.unwrap_or(state.config.application.default_ttl_s);
+    if ttl > 0 {
+        db.touch(&key, Duration::from_secs(ttl));
+    }
+
+    if query.return_prev {
+        Ok(JsonResponse::new(PreviousValue { previous }, &state.config.application).into_response())
+    } else {
+        Ok(format!("Value written for key: {}", key).into_response())
+    }
+}
+
+/// Handler function to upsert the value given as `?value=...`, for quick manual testing from a browser.
+///
+/// Only enabled with `ApplicationSettings::query_upserts`, and responds with `404` otherwise. The value is
+/// validated, and `?return_prev` and `?ttl` are applied, as by `upsert_by_key`.
+/// # Arguments
+/// * `state`: The application state.
+/// * `key`: The key to upsert in the database.
+/// * `query`: The query parameters.
+/// * `value`: The query parameter that contains the value.
+async fn upsert_by_query(
+    State(state): State<ApplicationState>,
+    key: Key,
+    query: Query<UpsertQuery>,
+    Query(value): Query<ValueQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    if !state.config.application.query_upserts {
+        return Err((StatusCode::NOT_FOUND, String::new()));
+    }
+    let Some(value) = value.value else {
+        return Err((StatusCode::BAD_REQUEST, "Query parameter `value` is required.".to_string()));
+    };
+    upsert_by_key(State(state), key, query, ValuePayload(Value { value })).await
+}
+
+/// Handler function to write a value only if the key doesn't exist yet, e.g. to take a lock.
+///
+/// Responds with `201` and the value if it was written, or `409` if the key already existed. The check and the
+/// write happen under the same write lock, so only one of concurrent callers succeeds. The value is validated,
+/// and `ApplicationSettings::default_ttl_s` is applied, as by `upsert_by_key`.
+/// # Arguments
+/// * `state`: The application state.
+/// * `key`: The key to write.
+/// * `payload`: The request payload that contains the value, in JSON or MessagePack.
+async fn set_if_absent(
+    State(state): State<ApplicationState>,
+    key: Key,
+    ValuePayload(payload): ValuePayload,
+) -> Result<Response, (StatusCode, String)> {
+    let key = key.into_string();
+    let value = normalize_value(payload.value, &state.config.application.value_normalization);
+    validate_value(&value, &state.config.application)
+        .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+    let mut db = state.db.write().unwrap();
+
+    if db.contains_key(&key) {
+        let logged_key = key_for_logs(&key, &state.config.application);
+        debug!("Key '{}' already exists, skipping set-if-absent.", logged_key);
+        return Err((StatusCode::CONFLICT, format!("Key '{}' already exists.", key)));
+    }
+    reserve_memory(&mut *db, &key, value.len(), &state.config.application)
+        .map_err(|message| (StatusCode::INSUFFICIENT_STORAGE, message))?;
+    db.upsert(&key, value.clone());
+    if state.config.application.default_ttl_s > 0 {
+        db.touch(&key, Duration::from_secs(state.config.application.default_ttl_s));
+    }
+    Ok((StatusCode::CREATED, value).into_response())
+}
+
+/// Makes room to write a value at a key within `ApplicationSettings::max_memory_bytes`, by evicting the least
+/// recently used entries with `MemoryLimitPolicy::EvictLru`.
+/// # Arguments
+/// * `db`: The database, locked for writing.
+/// * `key`: The key about to be written.
+/// * `value_size`: Size of the value about to be written, in bytes.
+/// * `config`: The application settings that hold the memory limit.
+/// # Returns
+/// * `Result<(), String>`: The message of a `507` response if the value doesn't fit.
+fn reserve_memory(
+    db: &mut dyn KVDatabase<String, String>,
+    key: &String,
+    value_size: usize,
+    config: &ApplicationSettings,
+) -> Result<(), String> {
+    let Some(limit) = config.max_memory_bytes else {
+        return Ok(());
+    };
+    loop {
+        let Some(used) = db.memory_bytes() else {
+            return Ok(());
+        };
+        let replaced = db.read(key).map_or(0, |value| key.len() + value.len()) as u64;
+        if used.saturating_sub(replaced) + (key.len() + value_size) as u64 <= limit {
+            return Ok(());
+        }
+        if config.memory_limit_policy == MemoryLimitPolicy::Reject {
+            return Err(format!("Storage is full, the write exceeds the memory limit of {} bytes.", limit));
+        }
+        match db.evict_lru() {
+            Some(evicted) => {
+                info!("Evicted key '{}' to stay within the memory limit.", key_for_logs(&evicted, config))
+            }
+            None => return Err(format!("Value exceeds the memory limit of {} bytes.", limit)),
+        }
+    }
+}
+
+/// Handler function to append the posted JSON element to the JSON array stored at a key, without a
+/// read-modify-write round trip on the client.
+///
+/// A missing key is created as an empty array first, expiring after `ApplicationSettings::default_ttl_s`.
+/// With `ApplicationSettings::max_append_length`, the oldest elements are dropped to keep the array within the
+/// limit. Responds with `409` if the stored value isn't a JSON array.
+/// # Arguments
+/// * `state`: The application state.
+/// * `key`: The key of the array.
+/// * `element`: The request payload, which is the element to append.
+async fn append_by_key(
+    State(state): State<ApplicationState>,
+    key: Key,
+    DepthLimitedJson(element): DepthLimitedJson<serde_json::Value>,
+) -> Result<String, (StatusCode, String)> {
+    let key = key.into_string();
+    // Note: The write lock is held from the read to the write, so concurrent appends aren't lost.
+    let mut db = state.db.write().unwrap();
+
+    let existing = db.read(&key);
+    let mut array = match &existing {
+        Some(value) => match serde_json::from_str::<serde_json::Value>(value) {
+            Ok(serde_json::Value::Array(array)) => array,
+            _ => {
+                let message = format!("Value for key '{}' is not a JSON array.", key);
+                return Err((StatusCode::CONFLICT, message));
+            }
+        },
+        None => Vec::new(),
+    };
+    array.push(element);
+    if let Some(max_length) = state.config.application.max_append_length
+        && array.len() > max_length
+    {
+        array.drain(..array.len() - max_length);
+    }
+
+    let value = serde_json::Value::Array(array).to_string();
+    validate_value(&value, &state.config.application)
+        .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+    reserve_memory(&mut *db, &key, value.len(), &state.config.application)
+        .map_err(|message| (StatusCode::INSUFFICIENT_STORAGE, message))?;
+    // Note: `update` keeps the expiry of an existing key.
+    if existing.is_some() {
+        db.update(&key, value);
+    } else {
+        db.upsert(&key, value);
+        if state.config.application.default_ttl_s > 0 {
+            db.touch(&key, Duration::from_secs(state.config.application.default_ttl_s));
+        }
+    }
+    Ok(format!("Value appended for key: {}", key))
+}
+
+/// Handler function to merge the posted value into the value stored at a key with a server-side function, e.g.
+/// `{"function": "sum", "value": 2}`, without a read-modify-write round trip on the client.
+///
+/// A missing key is created with the posted value, expiring after `ApplicationSettings::default_ttl_s`.
+/// Responds with the merged value, `400` for an unknown function, or `409` if either value has the wrong type
+/// for the function, see `MergeFunction`.
+/// # Arguments
+/// * `state`: The application state.
+/// * `key`: The key to merge into.
+/// * `payload`: The request payload that contains the function name and the value.
+async fn merge_by_key(
+    State(state): State<ApplicationState>,
+    key: Key,
+    DepthLimitedJson(payload): DepthLimitedJson<MergeRequest>,
+) -> Result<String, (StatusCode, String)> {
+    let key = key.into_string();
+    let function: MergeFunction = payload
+        .function
+        .parse()
+        .map_err(|error: MergeError| (StatusCode::BAD_REQUEST, error.to_string()))?;
+    // Note: The write lock is held from the read to the write, so concurrent merges aren't lost.
+    let mut db = state.db.write().unwrap();
+
+    let existing = db.read(&key);
+    let value = function.apply(existing.as_deref(), &payload.value).map_err(|error| {
+        info!("Failed to merge into key '{}': {}", key_for_logs(&key, &state.config.application), error);
+        (StatusCode::CONFLICT, error.to_string())
+    })?;
+    validate_value(&value, &state.config.application)
+        .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))?;
+    reserve_memory(&mut *db, &key, value.len(), &state.config.application)
+        .map_err(|message| (StatusCode::INSUFFICIENT_STORAGE, message))?;
+    // Note: `update` keeps the expiry of an existing key.
+    if existing.is_some() {
+        db.update(&key, value.clone());
+    } else {
+        db.upsert(&key, value.clone());
+        if state.config.application.default_ttl_s > 0 {
+            db.touch(&key, Duration::from_secs(state.config.application.default_ttl_s));
+        }
+    }
+    Ok(value)
+}
+
+/// Handler function to reset the expiry of a key to `?ttl=N` seconds from now, without rewriting its value.
+/// # Arguments
+/// * `state`: The application state.
+/// * `key`: The key to refresh.
+/// * `query`: The query parameters.
+async fn touch_by_key(
+    State(state): State<ApplicationState>,
+    key: Key,
+    Query(query): Query<TouchQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let key = key.into_string();
+    let mut db = state.db.write().unwrap();
+
+    if db.touch(&key, Duration::from_secs(query.ttl)) {
+        Ok(format!("TTL refreshed for key: {}", key))
+    } else {
+        Err((StatusCode::NOT_FOUND, String::new()))
+    }
+}
+
+/// Handler function to read the remaining time to live of a key, e.g. to verify TTLs without waiting
+/// for them to expire.
+/// # Arguments
+/// * `state`: The application state.
+/// * `key`: The key to look up in the database.
+async fn read_ttl(
+    State(state): State<ApplicationState>,
+    key: Key,
+) -> Result<JsonResponse<Ttl>, StatusCode> {
+    let key = key.into_string();
+    let db = state.db.read().unwrap();
+
+    let ttl = db.ttl(&key).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(JsonResponse::new(
+        Ttl {
+            ttl: ttl.map(|ttl| ttl.as_secs_f64().ceil() as u64),
+        },
+        &state.config.application,
+    ))
+}
+
+/// Handler function to upsert multiple values in one request.
+///
+/// Entries are validated and written independently, so a `207 Multi-Status` response is returned
+/// with a result per entry that clients can use to retry only the failed ones.
+/// Each entry is processed in a `batch_entry` child span of the request span, with its index, key (hashed with
+/// `ApplicationSettings::hash_keys_in_logs`) and resulting status, so traces show the per-key timing.
+/// # Arguments
+/// * `state`: The application state.
+/// * `payload`: The request payload that contains the entries to write.
+async fn batch_upsert(
+    State(state): State<ApplicationState>,
+    DepthLimitedJson(payload): DepthLimitedJson<BatchUpsert>,
+) -> (StatusCode, JsonResponse<BatchUpsertResult>) {
+    let mut db = state.db.write().unwrap();
+
+    let results = payload
+        .entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let logged_key = key_for_logs(&entry.key, &state.config.application);
+            let span = info_span!("batch_entry", index, key = %logged_key, status = Empty);
+            let _entered = span.enter();
+
+            let (key, parsed) = match Key::parse(entry.key.clone(), &state.config.application) {
+                Ok(key) => (key.into_string(), Ok(())),
+                Err(error) => (entry.key, Err((StatusCode::BAD_REQUEST, error.to_string()))),
+            };
+            let value = normalize_value(entry.value, &state.config.application.value_normalization);
+            let valid = parsed
+                .and_then(|()| {
+                    validate_value(&value, &state.config.application)
+                        .map_err(|error| (StatusCode::BAD_REQUEST, error.to_string()))
+                })
+                .and_then(|()| {
+                    reserve_memory(&mut *db, &key, value.len(), &state.config.application)
+                        .map_err(|message| (StatusCode::INSUFFICIENT_STORAGE, message))
+                });
+            let result = match valid {
+                Ok(()) => {
+                    db.upsert(&key, value);
+                    if state.config.application.default_ttl_s > 0 {
+                        db.touch(&key, Duration::from_secs(state.config.application.default_ttl_s));
+                    }
+                    BatchEntryResult {
+                        key,
+                        status: StatusCode::OK.as_u16(),
+                        error: None,
+                    }
+                }
+                Err((status, error)) => {
+                    debug!("Batch entry rejected: {}", error);
+                    BatchEntryResult {
+                        key,
+                        status: status.as_u16(),
+                        error: Some(error),
+                    }
+                }
+            };
+            span.record("status", result.status);
+            result
+        })
+        .collect();
+
+    (
+        StatusCode::MULTI_STATUS,
+        JsonResponse::new(BatchUpsertResult { results }, &state.config.application),
+    )
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::build_app;
+    use crate::configuration::KeyEncoding;
+    use crate::test_util::{body_string, send, test_settings, TraceCapture};
+    use axum::http::header::CONTENT_LENGTH;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn upsert_request(key: &str, value: &str) -> Request<Body> {
+        Request::post(format!("/api/{}", key))
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::json!({ "value": value }).to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_upsert_is_suppressed() {
+        let mut settings = test_settings();
+        settings.application.dedup_window_ms = 60_000;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let key = "key".to_string();
+
+        let ttl = || state.db.read().unwrap().ttl(&key);
+
+        assert_eq!(send(&app, upsert_request("key", "x")).await.status(), StatusCode::OK);
+        // A write clears the expiry, so set one to observe whether the next upsert is written.
+        state.db.write().unwrap().touch(&key, Duration::from_secs(60));
+        let response = send(&app, upsert_request("key", "x")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(ttl().unwrap().is_some());
+
+        // A value changed in between, e.g. by another write path, is written again.
+        state.db.write().unwrap().upsert(&key, "y".to_string());
+        assert_eq!(send(&app, upsert_request("key", "x")).await.status(), StatusCode::OK);
+        assert_eq!(state.db.read().unwrap().read(&key), Some("x".to_string()));
+
+        // A different value is not a duplicate.
+        assert_eq!(send(&app, upsert_request("key", "z")).await.status(), StatusCode::OK);
+        assert_eq!(state.db.read().unwrap().read(&key), Some("z".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_upsert_returns_original_previous_value() {
+        let mut settings = test_settings();
+        settings.application.dedup_window_ms = 60_000;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        let upsert = |value: &str| {
+            Request::post("/api/key?return_prev=true")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::json!({ "value": value }).to_string()))
+                .unwrap()
+        };
+
+        send(&app, upsert("first")).await;
+        let response = send(&app, upsert("second")).await;
+        assert_eq!(body_string(response).await, r#"{"previous":"first"}"#);
+        // The retry is suppressed, and reports the value replaced by the original upsert.
+        let response = send(&app, upsert("second")).await;
+        assert_eq!(body_string(response).await, r#"{"previous":"first"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_upsert_returns_previous_value() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let upsert = |value: &str| {
+            Request::post("/api/key?return_prev=true")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::json!({ "value": value }).to_string()))
+                .unwrap()
+        };
+
+        let response = send(&app, upsert("first")).await;
+        assert_eq!(body_string(response).await, r#"{"previous":null}"#);
+
+        let response = send(&app, upsert("second")).await;
+        assert_eq!(body_string(response).await, r#"{"previous":"first"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_exists() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        send(&app, upsert_request("present", "value")).await;
+
+        for (key, expected) in [("present", r#"{"exists":true}"#), ("absent", r#"{"exists":false}"#)] {
+            let request = Request::get(format!("/api/{}/exists", key)).body(Body::empty()).unwrap();
+            let response = send(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_string(response).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_touch() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let touch = |key: &str| Request::post(format!("/api/{}/touch?ttl=60", key)).body(Body::empty()).unwrap();
+
+        assert_eq!(send(&app, touch("key")).await.status(), StatusCode::NOT_FOUND);
+
+        let request = Request::post("/api/key?ttl=1")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"value": "value"}"#))
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::OK);
+        let response = send(&app, touch("key")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "TTL refreshed for key: key");
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let ttl = |key: &str| Request::get(format!("/api/{}/ttl", key)).body(Body::empty()).unwrap();
+
+        assert_eq!(send(&app, ttl("missing")).await.status(), StatusCode::NOT_FOUND);
+
+        send(&app, upsert_request("forever", "value")).await;
+        assert_eq!(body_string(send(&app, ttl("forever")).await).await, r#"{"ttl":null}"#);
+
+        let request = Request::post("/api/session?ttl=60")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"value": "value"}"#))
+            .unwrap();
+        send(&app, request).await;
+        assert_eq!(body_string(send(&app, ttl("session")).await).await, r#"{"ttl":60}"#);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_keys_are_rejected() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+
+        let response = send(&app, upsert_request("__sys:idempotency", "value")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Keys starting with '__sys:' are reserved.");
+
+        // Internal entries can't be read or deleted by users either.
+        let response = send(&app, Request::get("/api/__sys:idempotency").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(send(&app, upsert_request("sys:key", "value")).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_keys_are_hidden() {
+        let mut settings = test_settings();
+        settings.application.admin_token = Some("secret".to_string());
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        state.db.write().unwrap().upsert(&"__sys:idempotency".to_string(), "value".to_string());
+        send(&app, upsert_request("_user", "value")).await;
+
+        let response = send(&app, Request::get("/api").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, r#"{"keys":["_user"],"next_cursor":null}"#);
+        for (prefix, expected) in [("", 1), ("_", 1), ("__sys:", 0), ("__sys:idem", 0)] {
+            let request = Request::get(format!("/api/count?prefix={}", prefix)).body(Body::empty()).unwrap();
+            assert_eq!(body_string(send(&app, request).await).await, format!(r#"{{"count":{}}}"#, expected));
+        }
+
+        // Prefixes that would match internal entries can't be cleared either.
+        for prefix in ["_", "__sys", "__sys:"] {
+            let request = Request::delete(format!("/api/prefix/{}", prefix))
+                .header("Authorization", "Bearer secret")
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(send(&app, request).await.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(state.db.read().unwrap().contains_key(&"__sys:idempotency".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_keys_are_rejected_consistently() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let message =
+            "Key contains the invalid character ' ', whitespace and control characters aren't allowed.";
+
+        for request in [
+            Request::get("/api/a%20b").body(Body::empty()).unwrap(),
+            Request::get("/api/a%20b/ttl").body(Body::empty()).unwrap(),
+            upsert_request("a%20b", "value"),
+        ] {
+            let response = send(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(body_string(response).await, message);
+        }
+
+        let request = Request::post("/api/batch")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"entries": [{"key": "a b", "value": "value"}]}"#))
+            .unwrap();
+        let response = send(&app, request).await;
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["results"][0]["status"], 400);
+        assert_eq!(body["results"][0]["error"], message);
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let mut settings = test_settings();
+        settings.application.max_append_length = Some(3);
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let append = |key: &str, element: &str| {
+            Request::post(format!("/api/{}/append", key))
+                .header("Content-Type", "application/json")
+                .body(Body::from(element.to_string()))
+                .unwrap()
+        };
+        let read = |key: &str| state.db.read().unwrap().read(&key.to_string()).unwrap();
+
+        // Appending to a new key creates the array.
+        let response = send(&app, append("list", "1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read("list"), "[1]");
+
+        send(&app, append("list", r#"{"a":"b"}"#)).await;
+        assert_eq!(read("list"), r#"[1,{"a":"b"}]"#);
+
+        // The oldest elements are dropped beyond the cap.
+        send(&app, append("list", "3")).await;
+        send(&app, append("list", "4")).await;
+        assert_eq!(read("list"), r#"[{"a":"b"},3,4]"#);
+
+        send(&app, upsert_request("text", "not an array")).await;
+        let response = send(&app, append("text", "1")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(read("text"), "not an array");
+    }
+
+    #[tokio::test]
+    async fn test_append_to_new_key_applies_default_ttl() {
+        let mut settings = test_settings();
+        settings.application.default_ttl_s = 60;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let append = || {
+            Request::post("/api/list/append")
+                .header("Content-Type", "application/json")
+                .body(Body::from("1"))
+                .unwrap()
+        };
+        let ttl = || state.db.read().unwrap().ttl(&"list".to_string()).unwrap().unwrap();
+
+        send(&app, append()).await;
+        assert!(ttl() <= Duration::from_secs(60));
+
+        // Appending to the existing key keeps its expiry.
+        state.db.write().unwrap().touch(&"list".to_string(), Duration::from_secs(3600));
+        send(&app, append()).await;
+        assert!(ttl() > Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_set_if_absent() {
+        let state = ApplicationState::new(Arc::new(test_settings()));
+        let app = build_app(state.clone());
+        let setnx = |value: &str| {
+            Request::post("/api/lock/setnx")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::json!({ "value": value }).to_string()))
+                .unwrap()
+        };
+
+        let response = send(&app, setnx("owner-a")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(body_string(response).await, "owner-a");
+
+        let response = send(&app, setnx("owner-b")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(state.db.read().unwrap().read(&"lock".to_string()), Some("owner-a".to_string()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_set_if_absent_has_a_single_winner() {
+        let state = ApplicationState::new(Arc::new(test_settings()));
+        let app = build_app(state.clone());
+
+        let callers = (0..32).map(|i| {
+            let app = app.clone();
+            tokio::spawn(async move {
+                let request = Request::post("/api/lock/setnx")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(serde_json::json!({ "value": format!("owner-{}", i) }).to_string()))
+                    .unwrap();
+                let response = send(&app, request).await;
+                (response.status(), body_string(response).await)
+            })
+        });
+        let mut winners = Vec::new();
+        for caller in callers.collect::<Vec<_>>() {
+            let (status, body) = caller.await.unwrap();
+            match status {
+                StatusCode::CREATED => winners.push(body),
+                status => assert_eq!(status, StatusCode::CONFLICT),
+            }
+        }
+
+        assert_eq!(winners.len(), 1);
+        assert_eq!(state.db.read().unwrap().read(&"lock".to_string()), Some(winners[0].clone()));
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let state = ApplicationState::new(Arc::new(test_settings()));
+        let app = build_app(state.clone());
+        let merge = |key: &str, function: &str, value: serde_json::Value| {
+            Request::post(format!("/api/{}/merge", key))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::json!({ "function": function, "value": value }).to_string()))
+                .unwrap()
+        };
+        let cases = [
+            ("counter", "sum", serde_json::json!(5), "5"),
+            ("counter", "sum", serde_json::json!(-2), "3"),
+            ("high", "max", serde_json::json!(4), "4"),
+            ("high", "max", serde_json::json!(9), "9"),
+            ("low", "min", serde_json::json!(4), "4"),
+            ("low", "min", serde_json::json!(1.5), "1.5"),
+            ("text", "concat", serde_json::json!("foo"), "foo"),
+            ("text", "concat", serde_json::json!("bar"), "foobar"),
+            ("tags", "set_union", serde_json::json!(["a", "b"]), r#"["a","b"]"#),
+            ("tags", "set_union", serde_json::json!(["b", "c"]), r#"["a","b","c"]"#),
+        ];
+        for (key, function, value, expected) in cases {
+            let response = send(&app, merge(key, function, value)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{} of {}", function, key);
+            assert_eq!(body_string(response).await, expected);
+            assert_eq!(state.db.read().unwrap().read(&key.to_string()), Some(expected.to_string()));
+        }
+
+        let response = send(&app, merge("counter", "product", serde_json::json!(2))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(&app, merge("text", "sum", serde_json::json!(2))).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(body_string(response).await, "Merge function `sum` requires JSON numbers.");
+        assert_eq!(state.db.read().unwrap().read(&"text".to_string()), Some("foobar".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_merge_into_new_key_applies_default_ttl() {
+        let mut settings = test_settings();
+        settings.application.default_ttl_s = 60;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let merge = || {
+            Request::post("/api/counter/merge")
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"function": "sum", "value": 1}"#))
+                .unwrap()
+        };
+        let ttl = || state.db.read().unwrap().ttl(&"counter".to_string()).unwrap().unwrap();
+
+        send(&app, merge()).await;
+        assert!(ttl() <= Duration::from_secs(60));
+
+        // Merging into the existing key keeps its expiry.
+        state.db.write().unwrap().touch(&"counter".to_string(), Duration::from_secs(3600));
+        send(&app, merge()).await;
+        assert!(ttl() > Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_etag() {
+        let mut settings = test_settings();
+        settings.application.read_cache_max_age_s = 60;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        send(&app, upsert_request("key", "value")).await;
+
+        let response = send(&app, Request::get("/api/key").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=60");
+        // Shared caches must not serve a MessagePack or JSON representation to clients that asked for another.
+        assert!(response.headers().get_all(VARY).iter().any(|value| value == "Accept"));
+        let etag = response.headers()[ETAG].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+
+        let conditional = |etag: &HeaderValue| {
+            Request::get("/api/key")
+                .header(IF_NONE_MATCH, etag)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = send(&app, conditional(&etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag);
+        assert_eq!(body_string(response).await, "");
+
+        // The tag no longer matches once the value changes.
+        send(&app, upsert_request("key", "changed")).await;
+        let response = send(&app, conditional(&etag)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "changed");
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit() {
+        let mut settings = test_settings();
+        // Room for two entries of a 1-byte key and a 5-byte value.
+        settings.application.max_memory_bytes = Some(12);
+        let state = ApplicationState::new(Arc::new(settings.clone()));
+        let app = build_app(state.clone());
+
+        assert_eq!(send(&app, upsert_request("a", "value")).await.status(), StatusCode::OK);
+        assert_eq!(send(&app, upsert_request("b", "value")).await.status(), StatusCode::OK);
+        let response = send(&app, upsert_request("c", "value")).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(
+            body_string(response).await,
+            "Storage is full, the write exceeds the memory limit of 12 bytes."
+        );
+        // Replacing a value of the same size still fits.
+        assert_eq!(send(&app, upsert_request("a", "other")).await.status(), StatusCode::OK);
+
+        settings.application.memory_limit_policy = MemoryLimitPolicy::EvictLru;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        send(&app, upsert_request("a", "value")).await;
+        send(&app, upsert_request("b", "value")).await;
+        send(&app, Request::get("/api/a").body(Body::empty()).unwrap()).await;
+
+        assert_eq!(send(&app, upsert_request("c", "value")).await.status(), StatusCode::OK);
+        let db = state.db.read().unwrap();
+        assert!(db.contains_key(&"a".to_string()));
+        assert!(!db.contains_key(&"b".to_string()));
+        assert!(db.contains_key(&"c".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_list_keys() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        for key in ["c", "a", "d", "b"] {
+            send(&app, upsert_request(key, "value")).await;
+        }
+
+        let response = send(&app, Request::get("/api?limit=3").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, r#"{"keys":["a","b","c"],"next_cursor":"c"}"#);
+        let response = send(&app, Request::get("/api?limit=3&cursor=c").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, r#"{"keys":["d"],"next_cursor":null}"#);
+
+        let response = send(&app, Request::get("/api?limit=-1").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_listing_is_truncated() {
+        let mut settings = test_settings();
+        settings.application.max_response_entries = 2;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        for key in ["c", "a", "b"] {
+            send(&app, upsert_request(key, "value")).await;
+        }
+
+        let response = send(&app, Request::get("/api").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["X-Truncated"], "true");
+        assert_eq!(body_string(response).await, r#"{"keys":["a","b"],"next_cursor":"b"}"#);
+
+        let response = send(&app, Request::get("/api?cursor=b").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("X-Truncated").is_none());
+        assert_eq!(body_string(response).await, r#"{"keys":["c"],"next_cursor":null}"#);
+    }
+
+    #[tokio::test]
+    async fn test_upsert_by_query() {
+        for (query_upserts, expected) in [(true, StatusCode::OK), (false, StatusCode::NOT_FOUND)] {
+            let mut settings = test_settings();
+            settings.application.query_upserts = query_upserts;
+            let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+            let request = Request::put("/api/key?value=hello%20world").body(Body::empty()).unwrap();
+            assert_eq!(send(&app, request).await.status(), expected);
+            let response = send(&app, Request::get("/api/key").body(Body::empty()).unwrap()).await;
+            if query_upserts {
+                assert_eq!(body_string(response).await, "hello world");
+            } else {
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            }
+        }
+
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let response = send(&app, Request::put("/api/key?value=").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Value is empty.");
+        let response = send(&app, Request::put("/api/key").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_auto_parse_json_values() {
+        let mut settings = test_settings();
+        settings.application.auto_parse_json_values = true;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        send(&app, upsert_request("json", r#"{ "a": [1, 2] }"#)).await;
+        send(&app, upsert_request("plain", "{not json")).await;
+        let read = |key: &str, accept: &str| {
+            Request::get(format!("/api/{}", key)).header("Accept", accept).body(Body::empty()).unwrap()
+        };
+
+        let response = send(&app, read("json", "application/json")).await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(body_string(response).await, r#"{"a":[1,2]}"#);
+
+        let response = send(&app, read("plain", "application/json")).await;
+        assert_ne!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(body_string(response).await, "{not json");
+
+        // Clients that don't accept JSON get the value as stored.
+        let response = send(&app, read("json", "text/plain")).await;
+        assert_eq!(body_string(response).await, r#"{ "a": [1, 2] }"#);
+    }
+
+    #[tokio::test]
+    async fn test_value_size_metrics() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        send(&app, upsert_request("small", &"x".repeat(10))).await;
+        send(&app, upsert_request("large", &"x".repeat(1000))).await;
+        // Overwritten values no longer count towards the stored bytes, which include the keys.
+        send(&app, upsert_request("small", &"x".repeat(20))).await;
+
+        let response = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        let body = body_string(response).await;
+        assert!(body.contains("kv_value_size_bytes_bucket{le=\"64\"} 2\n"));
+        assert!(body.contains("kv_value_size_bytes_bucket{le=\"1024\"} 3\n"));
+        assert!(body.contains("kv_value_size_bytes_sum 1030\n"));
+        assert!(body.contains("kv_stored_bytes 1030\n"));
+    }
+
+    #[tokio::test]
+    async fn test_count_by_prefix() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        for key in ["user:1", "user:2", "order:1"] {
+            send(&app, upsert_request(key, "value")).await;
+        }
+
+        for (prefix, expected) in [("user:", r#"{"count":2}"#), ("item:", r#"{"count":0}"#)] {
+            let request = Request::get(format!("/api/count?prefix={}", prefix)).body(Body::empty()).unwrap();
+            assert_eq!(body_string(send(&app, request).await).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clear_prefix() {
+        let mut settings = test_settings();
+        settings.application.admin_token = Some("secret".to_string());
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        for key in ["user:1", "user:1:settings", "user:2", "order:1"] {
+            send(&app, upsert_request(key, "value")).await;
+        }
+        let request = |token: &str| {
+            Request::delete("/api/prefix/user:1")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(send(&app, request("wrong")).await.status(), StatusCode::UNAUTHORIZED);
+        let response = send(&app, request("secret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, r#"{"count":2}"#);
+
+        let mut keys = state.db.read().unwrap().keys();
+        keys.sort();
+        assert_eq!(keys, ["order:1", "user:2"]);
+
+        // Prefixes are validated like keys.
+        let request = Request::delete("/api/prefix/user%201")
+            .header("Authorization", "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body_string(response).await,
+            "Key contains the invalid character ' ', whitespace and control characters aren't allowed."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_keys() {
+        for (case_insensitive, expected) in [(true, StatusCode::OK), (false, StatusCode::NOT_FOUND)] {
+            let mut settings = test_settings();
+            settings.application.case_insensitive_keys = case_insensitive;
+            let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+            send(&app, upsert_request("Foo", "value")).await;
+            let response = send(&app, Request::get("/api/foo").body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_ttl() {
+        let mut settings = test_settings();
+        settings.application.default_ttl_s = 60;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let upsert = |uri: &str| {
+            Request::post(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"value": "value"}"#))
+                .unwrap()
+        };
+
+        send(&app, upsert("/api/default")).await;
+        send(&app, upsert("/api/custom?ttl=3600")).await;
+        send(&app, upsert("/api/persistent?ttl=0")).await;
+
+        let ttls: HashMap<_, _> = state
+            .db
+            .read()
+            .unwrap()
+            .entries()
+            .into_iter()
+            .map(|(key, _, ttl)| (key, ttl.map(|ttl| ttl.as_secs().div_ceil(60))))
+            .collect();
+        // TTLs rounded up to minutes.
+        assert_eq!(ttls["default"], Some(1));
+        assert_eq!(ttls["custom"], Some(60));
+        assert_eq!(ttls["persistent"], None);
+    }
+
+    #[tokio::test]
+    async fn test_limits_report_in_flight_requests() {
+        let state = ApplicationState::new(Arc::new(test_settings()));
+        let app = build_app(state.clone());
+        // Requests in flight elsewhere.
+        let _permits = [state.limiter.try_acquire().unwrap(), state.write_limiter.try_acquire().unwrap()];
+
+        let response = send(&app, Request::get("/api/limits").body(Body::empty()).unwrap()).await;
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "concurrency": {"limit": 10240, "in_flight": 2},
+                "reads": {"limit": 10240, "in_flight": 1},
+                "writes": {"limit": 10240, "in_flight": 1}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_msgpack_round_trip() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let upsert = |key: &str, value: MsgPackScalar| {
+            Request::post(format!("/api/{}", key))
+                .header(CONTENT_TYPE, "application/msgpack")
+                .body(Body::from(rmp_serde::to_vec_named(&MsgPackValue { value }).unwrap()))
+                .unwrap()
+        };
+        let read = |key: &str| {
+            Request::get(format!("/api/{}", key))
+                .header("Accept", "application/msgpack")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for (key, value, expected) in [
+            ("text", MsgPackScalar::String("héllo".to_string()), "héllo"),
+            ("number", MsgPackScalar::Integer(-42), "-42"),
+        ] {
+            assert_eq!(send(&app, upsert(key, value)).await.status(), StatusCode::OK);
+
+            let response = send(&app, read(key)).await;
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/msgpack");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let MsgPackValue { value } = rmp_serde::from_slice(&body).unwrap();
+            assert!(matches!(value, MsgPackScalar::String(value) if value == expected));
+        }
+
+        // JSON remains the default.
+        let response = send(&app, Request::get("/api/text").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, "héllo");
+    }
+
+    #[tokio::test]
+    async fn test_stored_empty_value_is_distinct_from_missing_key() {
+        let state = ApplicationState::new(Arc::new(test_settings()));
+        let app = build_app(state.clone());
+        // Note: The API rejects empty values, so write it to the backend directly.
+        state.db.write().unwrap().upsert(&"empty".to_string(), String::new());
+
+        let response = send(&app, Request::get("/api/empty").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-KV-Exists"], "true");
+        assert_eq!(body_string(response).await, "");
+
+        let response = send(&app, Request::get("/api/missing").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().get("X-KV-Exists").is_none());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 10), ByteRange::Full);
+        assert_eq!(parse_range(Some("bytes=2-4"), 10), ByteRange::Partial(2..=4));
+        assert_eq!(parse_range(Some("bytes=8-"), 10), ByteRange::Partial(8..=9));
+        assert_eq!(parse_range(Some("bytes=5-100"), 10), ByteRange::Partial(5..=9));
+        assert_eq!(parse_range(Some("bytes=-3"), 10), ByteRange::Partial(7..=9));
+        assert_eq!(parse_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,4-5"), 10), ByteRange::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 10), ByteRange::Full);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        send(&app, upsert_request("key", "0123456789")).await;
+        let read = |range: &str| {
+            Request::get("/api/key")
+                .header(RANGE, range)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = send(&app, read("bytes=2-4")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(body_string(response).await, "234");
+
+        let response = send(&app, read("bytes=0-")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 0-9/10");
+        assert_eq!(body_string(response).await, "0123456789");
+
+        let response = send(&app, read("bytes=20-30")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn test_large_value_is_streamed() {
+        let mut settings = test_settings();
+        settings.application.stream_threshold_bytes = 100;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let large = "0123456789".repeat(STREAM_CHUNK_SIZE / 4);
+        let small = "0123456789".repeat(10);
+        state.db.write().unwrap().upsert(&"large".to_string(), large.clone());
+        state.db.write().unwrap().upsert(&"small".to_string(), small.clone());
+
+        // Streamed bodies have no `Content-Length`, so they're sent with chunked transfer encoding.
+        let response = send(&app, Request::get("/api/large").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        assert_eq!(body_string(response).await, large);
+
+        let response = send(&app, Request::get("/api/small").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.headers()[CONTENT_LENGTH], "100");
+        assert_eq!(body_string(response).await, small);
+    }
+
+    #[tokio::test]
+    async fn test_large_listing_is_streamed() {
+        let mut settings = test_settings();
+        settings.application.stream_threshold_bytes = 100;
+        settings.application.max_page_size = 2 * STREAM_KEYS_PER_CHUNK;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let keys: Vec<String> = (0..STREAM_KEYS_PER_CHUNK + 10).map(|i| format!("key-{:05}", i)).collect();
+        for key in &keys {
+            state.db.write().unwrap().upsert(key, "value".to_string());
+        }
+
+        let request = Request::get(format!("/api?limit={}", keys.len())).body(Body::empty()).unwrap();
+        let response = send(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body, serde_json::json!({ "keys": keys, "next_cursor": null }));
+
+        let response = send(&app, Request::get("/api?limit=2").body(Body::empty()).unwrap()).await;
+        assert!(response.headers().get(CONTENT_LENGTH).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_numeric_values_round_trip() {
+        let mut settings = test_settings();
+        settings.application.value_type = ValueType::Number;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        // Larger than `u64::MAX`, and a float with a trailing zero.
+        for number in ["123456789012345678901234567890", "-1.50"] {
+            let request = Request::post("/api/number")
+                .header("Content-Type", "application/json")
+                .body(Body::from(format!(r#"{{"value": {}}}"#, number)))
+                .unwrap();
+            assert_eq!(send(&app, request).await.status(), StatusCode::OK);
+
+            let response = send(&app, Request::get("/api/number").body(Body::empty()).unwrap()).await;
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+            assert_eq!(body_string(response).await, number);
+        }
+
+        // Non-numeric strings are still returned as plain text.
+        send(&app, upsert_request("text", "12 apples")).await;
+        let response = send(&app, Request::get("/api/text").body(Body::empty()).unwrap()).await;
+        assert_ne!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(body_string(response).await, "12 apples");
+    }
+
+    #[tokio::test]
+    async fn test_batch_upsert_reports_per_entry_results() {
+        let mut settings = test_settings();
+        settings.application.max_value_length = 5;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+
+        let request = Request::post("/api/batch")
+            .header("Content-Type", "application/json")
+            .body(Body::from(
+                r#"{"entries": [
+                    {"key": "a", "value": "ok"},
+                    {"key": "b", "value": ""},
+                    {"key": "c", "value": "too long"}
+                ]}"#,
+            ))
+            .unwrap();
+        let response = send(&app, request).await;
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"results": [
+                {"key": "a", "status": 200},
+                {"key": "b", "status": 400, "error": "Value is empty."},
+                {"key": "c", "status": 400, "error": "Value exceeds the maximum length of 5 bytes."}
+            ]})
+        );
+
+        let db = state.db.read().unwrap();
+        assert_eq!(db.read(&"a".to_string()), Some("ok".to_string()));
+        assert_eq!(db.read(&"b".to_string()), None);
+        assert_eq!(db.read(&"c".to_string()), None);
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_hashed_in_logs() {
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let mut settings = test_settings();
+        settings.application.hash_keys_in_logs = true;
+        settings.application.dedup_window_ms = 60_000;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        send(&app, Request::get("/api/jane.doe").body(Body::empty()).unwrap()).await;
+        send(&app, upsert_request("jane.doe", "")).await;
+        send(&app, upsert_request("jane.doe", "value")).await;
+        send(&app, upsert_request("jane.doe", "value")).await;
+        send(&app, Request::get("/api/jane%20doe").body(Body::empty()).unwrap()).await;
+
+        let logged: Vec<_> = capture
+            .events()
+            .into_iter()
+            .chain(capture.spans("request"))
+            .flat_map(|trace| trace.fields.into_values())
+            .collect();
+        assert!(logged.iter().any(|field| field.contains("Duplicate upsert for key '#")));
+        assert!(logged.iter().any(|field| field.starts_with("Rejected key in /api/#")));
+        assert!(logged.iter().all(|field| !field.contains("jane")), "{:?}", logged);
+    }
+
+    #[tokio::test]
+    async fn test_batch_entries_have_child_spans() {
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+
+        let request = Request::post("/api/batch")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"entries": [{"key": "a", "value": "ok"}, {"key": "b", "value": ""}]}"#))
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::MULTI_STATUS);
+
+        let mut spans = capture.spans("batch_entry");
+        spans.sort_by(|a, b| a.fields["index"].cmp(&b.fields["index"]));
+        let fields: Vec<_> = spans
+            .iter()
+            .map(|span| {
+                let field = |name: &str| span.fields[name].as_str();
+                (field("index"), field("key"), field("status"))
+            })
+            .collect();
+        assert_eq!(fields, [("0", "a", "200"), ("1", "b", "400")]);
+    }
+
+    #[tokio::test]
+    async fn test_encoded_keys() {
+        let mut settings = test_settings();
+        let state = ApplicationState::new(Arc::new(settings.clone()));
+        let app = build_app(state.clone());
+
+        // `a/b` in URL-safe base64.
+        assert_eq!(send(&app, upsert_request("b64/YS9i", "value")).await.status(), StatusCode::OK);
+        assert_eq!(state.db.read().unwrap().read(&"a/b".to_string()), Some("value".to_string()));
+        let response = send(&app, Request::get("/api/b64/YS9i").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, "value");
+
+        let response = send(&app, Request::get("/api/b64/not*base64").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Key is not valid URL-safe base64 of UTF-8 text.");
+
+        settings.application.key_encoding = KeyEncoding::Percent;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        assert_eq!(send(&app, upsert_request("b64/a%2Fb", "value")).await.status(), StatusCode::OK);
+        let response = send(&app, Request::get("/api/b64/a%2Fb").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, "value");
+    }
+
+    #[tokio::test]
+    async fn test_over_nested_payload_is_rejected() {
+        let mut settings = test_settings();
+        settings.application.max_json_depth = 3;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        let request = |body: &str| {
+            Request::post("/api/key")
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = send(&app, request(r#"{"value": "x", "extra": [[{}]]}"#)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "JSON payload exceeds the maximum nesting depth of 3.");
+
+        let response = send(&app, request(r#"{"value": "x", "extra": [[]]}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_is_rejected() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let upsert = |content_type: &str, body: &'static str| {
+            Request::post("/api/key").header("Content-Type", content_type).body(Body::from(body)).unwrap()
+        };
+
+        let empty_bodies = [("application/json", ""), ("application/json", " \n"), ("application/msgpack", "")];
+        for (content_type, body) in empty_bodies {
+            let response = send(&app, upsert(content_type, body)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(body_string(response).await, "Request body is required.");
+        }
+
+        let response = send(&app, upsert("application/json", "{}")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Request body is missing the `value` field.");
+    }
+
+    #[tokio::test]
+    async fn test_custom_value_field() {
+        let mut settings = test_settings();
+        settings.application.value_field = "data".to_string();
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        let request = |body: &str| {
+            Request::post("/api/key")
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = send(&app, request(r#"{"value": "x"}"#)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Request body is missing the `data` field.");
+
+        let response = send(&app, request(r#"{"data": "x"}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&app, Request::get("/api/key").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, "x");
     }
 }
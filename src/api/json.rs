@@ -0,0 +1,96 @@
+use crate::dependency::ApplicationState;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Json, Request};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+
+/// JSON extractor that rejects bodies nested deeper than `ApplicationSettings::max_json_depth` with `400`.
+///
+/// Empty bodies are rejected with a clear `400` too, rather than the generic deserialization error.
+///
+/// The depth is checked in a single pass over the raw bytes before anything is deserialized, so an
+/// over-nested payload never reaches the recursive deserializer.
+pub(crate) struct DepthLimitedJson<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest<ApplicationState> for DepthLimitedJson<T> {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &ApplicationState) -> Result<Self, Self::Rejection> {
+        let headers = request.headers().clone();
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        if bytes.trim_ascii().is_empty() {
+            return Err(empty_body());
+        }
+        let max_depth = state.config.application.max_json_depth;
+        if exceeds_depth(&bytes, max_depth) {
+            let message = format!("JSON payload exceeds the maximum nesting depth of {}.", max_depth);
+            return Err((StatusCode::BAD_REQUEST, message).into_response());
+        }
+
+        // Note: Rebuild the request so `Json` still checks the `Content-Type` header.
+        let mut request = Request::new(bytes.into());
+        *request.headers_mut() = headers;
+        let Json(value) = Json::<T>::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(DepthLimitedJson(value))
+    }
+}
+
+/// Response to a request without a body where one is required.
+pub(crate) fn empty_body() -> Response {
+    (StatusCode::BAD_REQUEST, "Request body is required.").into_response()
+}
+
+/// Whether the JSON text nests arrays and objects deeper than `max_depth`.
+///
+/// Brackets inside strings are ignored. Malformed JSON is left to the deserializer to reject.
+fn exceeds_depth(json: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceeds_depth() {
+        assert!(!exceeds_depth(br#"{"value": "x"}"#, 1));
+        assert!(exceeds_depth(br#"{"value": ["x"]}"#, 1));
+        assert!(!exceeds_depth(br#"{"value": "[[[{{{"}"#, 1));
+        assert!(!exceeds_depth(br#"{"value": "\"[["}"#, 1));
+        assert!(!exceeds_depth(br#"[[]] [[]]"#, 2));
+    }
+}
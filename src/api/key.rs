@@ -0,0 +1,181 @@
+use crate::api::validation::KeyError;
+use crate::configuration::{ApplicationSettings, KeyEncoding};
+use crate::dependency::ApplicationState;
+use crate::middleware::uri_for_logs;
+use axum::extract::{FromRequestParts, MatchedPath, OriginalUri, Path};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use base64::alphabet::URL_SAFE;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use std::collections::HashMap;
+use tracing::{error, info};
+
+/// URL-safe base64 that accepts keys with or without padding.
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Key of an entry, normalized with `normalize_key` and validated with `Key::parse`.
+///
+/// As an extractor for the `{key}` path parameter, invalid keys are rejected with `400`. Use this rather than
+/// `Path<String>`, so all handlers agree on which entry a key refers to and which keys are valid.
+/// The `{encoded_key}` path parameter is decoded with `ApplicationSettings::key_encoding` first.
+#[derive(Debug)]
+pub struct Key(String);
+
+impl Key {
+    /// Normalizes a key from a request and checks that it's non-empty, within
+    /// `ApplicationSettings::max_key_length`, free of whitespace and control characters, and outside of
+    /// `ApplicationSettings::reserved_key_prefix`.
+    /// # Arguments
+    /// * `key`: The key as sent by the client.
+    /// * `config`: The application settings that hold the key rules.
+    pub(crate) fn parse(key: String, config: &ApplicationSettings) -> Result<Self, KeyError> {
+        let key = normalize_key(key, config);
+        if key.is_empty() {
+            Err(KeyError::Empty)
+        } else if key.len() > config.max_key_length {
+            Err(KeyError::TooLong(config.max_key_length))
+        } else if let Some(c) = key.chars().find(|c| c.is_whitespace() || c.is_control()) {
+            Err(KeyError::InvalidCharacter(c))
+        } else if is_reserved_key(&key, config) {
+            Err(KeyError::Reserved(config.reserved_key_prefix.clone()))
+        } else {
+            Ok(Key(key))
+        }
+    }
+
+    /// Normalizes a key prefix from a request and checks it like a key with `Key::parse`. Prefixes of
+    /// `ApplicationSettings::reserved_key_prefix` are rejected too, since they match internal entries.
+    /// # Arguments
+    /// * `prefix`: The prefix as sent by the client.
+    /// * `config`: The application settings that hold the key rules.
+    pub(crate) fn parse_prefix(prefix: String, config: &ApplicationSettings) -> Result<Self, KeyError> {
+        let prefix = Key::parse(prefix, config)?;
+        if config.reserved_key_prefix.starts_with(prefix.as_str()) {
+            Err(KeyError::ReservedPrefix(config.reserved_key_prefix.clone()))
+        } else {
+            Ok(prefix)
+        }
+    }
+
+    /// The normalized key.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Consumes the key, returning the normalized key.
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl FromRequestParts<ApplicationState> for Key {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &ApplicationState,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(mut params) = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let key = match (params.remove("key"), params.remove("encoded_key")) {
+            (Some(key), _) => Ok(key),
+            (None, Some(encoded)) => decode_key(encoded, state.config.application.key_encoding),
+            (None, None) => {
+                error!("Route of {} has no key parameter.", parts.uri);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        };
+        key.and_then(|key| Key::parse(key, &state.config.application)).map_err(|error| {
+            // Note: The URI of nested routes lacks the prefix of the matched route, unlike the original URI.
+            let uri = parts.extensions.get::<OriginalUri>().map_or(&parts.uri, |OriginalUri(uri)| uri);
+            let route = parts.extensions.get::<MatchedPath>().map(MatchedPath::as_str);
+            info!("Rejected key in {}: {}", uri_for_logs(uri, route, &state.config.application), error);
+            (StatusCode::BAD_REQUEST, error.to_string()).into_response()
+        })
+    }
+}
+
+/// Decodes a key of the `{encoded_key}` path parameter.
+/// # Arguments
+/// * `encoded`: The path parameter, already percent-decoded by the router.
+/// * `encoding`: How the key is encoded.
+fn decode_key(encoded: String, encoding: KeyEncoding) -> Result<String, KeyError> {
+    match encoding {
+        KeyEncoding::Percent => Ok(encoded),
+        KeyEncoding::Base64Url => BASE64_URL
+            .decode(&encoded)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(KeyError::InvalidEncoding("URL-safe base64 of UTF-8 text")),
+    }
+}
+
+/// Normalizes a key before it's passed to the backend, e.g. lowercases it with
+/// `ApplicationSettings::case_insensitive_keys`.
+pub(crate) fn normalize_key(key: String, config: &ApplicationSettings) -> String {
+    if config.case_insensitive_keys {
+        key.to_lowercase()
+    } else {
+        key
+    }
+}
+
+/// Whether a normalized key is in `ApplicationSettings::reserved_key_prefix`, i.e. an internal entry that
+/// users can't see or modify.
+pub(crate) fn is_reserved_key(key: &str, config: &ApplicationSettings) -> bool {
+    !config.reserved_key_prefix.is_empty() && key.starts_with(&config.reserved_key_prefix)
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_settings;
+
+    #[test]
+    fn test_parse_key() {
+        let mut config = test_settings().application;
+        config.max_key_length = 8;
+        let parse = |key: &str| Key::parse(key.to_string(), &config).map(Key::into_string);
+
+        assert_eq!(parse("user:1"), Ok("user:1".to_string()));
+        assert_eq!(parse(""), Err(KeyError::Empty));
+        assert_eq!(parse("123456789"), Err(KeyError::TooLong(8)));
+        assert_eq!(parse("a b"), Err(KeyError::InvalidCharacter(' ')));
+        assert_eq!(parse("a\u{0}"), Err(KeyError::InvalidCharacter('\0')));
+        assert_eq!(parse("__sys:a"), Err(KeyError::Reserved("__sys:".to_string())));
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        let config = test_settings().application;
+        let parse = |prefix: &str| Key::parse_prefix(prefix.to_string(), &config).map(Key::into_string);
+
+        assert_eq!(parse("user:"), Ok("user:".to_string()));
+        assert_eq!(parse("_user"), Ok("_user".to_string()));
+        assert_eq!(parse("a b"), Err(KeyError::InvalidCharacter(' ')));
+        assert_eq!(parse("__sys:a"), Err(KeyError::Reserved("__sys:".to_string())));
+        for prefix in ["_", "__", "__sys"] {
+            assert_eq!(parse(prefix), Err(KeyError::ReservedPrefix("__sys:".to_string())));
+        }
+    }
+
+    #[test]
+    fn test_decode_key() {
+        let decode = |encoded: &str| decode_key(encoded.to_string(), KeyEncoding::Base64Url);
+        assert_eq!(decode("YS9i"), Ok("a/b".to_string()));
+        assert_eq!(decode("YS8"), Ok("a/".to_string()));
+        assert_eq!(decode("YS8="), Ok("a/".to_string()));
+        assert!(decode("not base64!").is_err());
+        // Valid base64 of invalid UTF-8.
+        assert!(decode("_w").is_err());
+        assert_eq!(decode_key("a/b".to_string(), KeyEncoding::Percent), Ok("a/b".to_string()));
+    }
+}
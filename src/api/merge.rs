@@ -0,0 +1,182 @@
+use serde_json::{Number, Value};
+use std::collections::HashSet;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Server-side function that merges a provided value into the value stored at a key, see
+/// `POST /api/{key}/merge`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MergeFunction {
+    /// Adds the provided number to the stored number.
+    Sum,
+    /// Keeps the larger of the stored and provided numbers.
+    Max,
+    /// Keeps the smaller of the stored and provided numbers.
+    Min,
+    /// Appends the provided string to the stored text.
+    Concat,
+    /// Adds the elements of the provided JSON array missing from the stored JSON array, in order.
+    SetUnion,
+}
+
+/// Reasons for failing to merge values.
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum MergeError {
+    #[error("Unknown merge function '{0}', use one of `sum`, `max`, `min`, `concat` or `set_union`.")]
+    UnknownFunction(String),
+    #[error("Merge function `{0}` requires {1}.")]
+    TypeMismatch(&'static str, &'static str),
+}
+
+impl FromStr for MergeFunction {
+    type Err = MergeError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "sum" => Ok(MergeFunction::Sum),
+            "max" => Ok(MergeFunction::Max),
+            "min" => Ok(MergeFunction::Min),
+            "concat" => Ok(MergeFunction::Concat),
+            "set_union" => Ok(MergeFunction::SetUnion),
+            _ => Err(MergeError::UnknownFunction(name.to_string())),
+        }
+    }
+}
+
+impl MergeFunction {
+    fn name(self) -> &'static str {
+        match self {
+            MergeFunction::Sum => "sum",
+            MergeFunction::Max => "max",
+            MergeFunction::Min => "min",
+            MergeFunction::Concat => "concat",
+            MergeFunction::SetUnion => "set_union",
+        }
+    }
+
+    /// Merges the provided value into the stored one.
+    /// # Arguments
+    /// * `stored`: The value stored at the key, or `None` if the key is missing, in which case the provided
+    ///   value is stored as-is.
+    /// * `provided`: The value sent by the client.
+    /// # Returns
+    /// * `Result<String, MergeError>`: The merged value to store, or `TypeMismatch` if either value has the
+    ///   wrong type for the function.
+    pub(crate) fn apply(self, stored: Option<&str>, provided: &Value) -> Result<String, MergeError> {
+        match self {
+            MergeFunction::Sum | MergeFunction::Max | MergeFunction::Min => {
+                let mismatch = || MergeError::TypeMismatch(self.name(), "JSON numbers");
+                let provided = provided.as_number().ok_or_else(mismatch)?;
+                let Some(stored) = stored else {
+                    return Ok(provided.to_string());
+                };
+                let stored: Number = serde_json::from_str(stored).map_err(|_| mismatch())?;
+                self.merge_numbers(&stored, provided).map(|n| n.to_string()).ok_or_else(mismatch)
+            }
+            MergeFunction::Concat => {
+                let provided = provided.as_str().ok_or(MergeError::TypeMismatch(self.name(), "a JSON string"))?;
+                Ok(format!("{}{}", stored.unwrap_or_default(), provided))
+            }
+            MergeFunction::SetUnion => {
+                let mismatch = || MergeError::TypeMismatch(self.name(), "JSON arrays");
+                let provided = provided.as_array().ok_or_else(mismatch)?;
+                let mut union = match stored {
+                    Some(stored) => match serde_json::from_str(stored) {
+                        Ok(Value::Array(array)) => array,
+                        _ => return Err(mismatch()),
+                    },
+                    None => Vec::new(),
+                };
+                // Note: Elements are compared by their serialization through a set, since `Vec::contains` would
+                //       make the union quadratic while the write lock is held.
+                let mut seen: HashSet<String> = union.iter().map(Value::to_string).collect();
+                for element in provided {
+                    if seen.insert(element.to_string()) {
+                        union.push(element.clone());
+                    }
+                }
+                Ok(Value::Array(union).to_string())
+            }
+        }
+    }
+
+    /// Merges two numbers, exactly if both are integers.
+    /// # Returns
+    /// * `Option<Number>`: The result, or `None` if it overflows or isn't finite.
+    fn merge_numbers(self, a: &Number, b: &Number) -> Option<Number> {
+        if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+            return match self {
+                MergeFunction::Sum => a.checked_add(b),
+                MergeFunction::Max => Some(a.max(b)),
+                _ => Some(a.min(b)),
+            }
+            .map(Number::from);
+        }
+        let (a, b) = (a.as_f64()?, b.as_f64()?);
+        let merged = match self {
+            MergeFunction::Sum => a + b,
+            MergeFunction::Max => a.max(b),
+            _ => a.min(b),
+        };
+        Number::from_f64(merged)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn merge(function: &str, stored: Option<&str>, provided: Value) -> Result<String, MergeError> {
+        function.parse::<MergeFunction>()?.apply(stored, &provided)
+    }
+
+    #[test]
+    fn test_sum() {
+        assert_eq!(merge("sum", Some("40"), json!(2)), Ok("42".to_string()));
+        assert_eq!(merge("sum", Some("1.5"), json!(1)), Ok("2.5".to_string()));
+        assert_eq!(merge("sum", None, json!(7)), Ok("7".to_string()));
+        assert!(merge("sum", Some(&i64::MAX.to_string()), json!(1)).is_err());
+        assert_eq!(
+            merge("sum", Some("text"), json!(1)),
+            Err(MergeError::TypeMismatch("sum", "JSON numbers"))
+        );
+    }
+
+    #[test]
+    fn test_max_and_min() {
+        assert_eq!(merge("max", Some("3"), json!(5)), Ok("5".to_string()));
+        assert_eq!(merge("max", Some("3"), json!(-5)), Ok("3".to_string()));
+        assert_eq!(merge("min", Some("3"), json!(5)), Ok("3".to_string()));
+        assert_eq!(merge("min", Some("3"), json!(2.5)), Ok("2.5".to_string()));
+        assert!(merge("min", Some("3"), json!("2")).is_err());
+    }
+
+    #[test]
+    fn test_concat() {
+        assert_eq!(merge("concat", Some("foo"), json!("bar")), Ok("foobar".to_string()));
+        assert_eq!(merge("concat", None, json!("bar")), Ok("bar".to_string()));
+        assert!(merge("concat", Some("foo"), json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_set_union() {
+        assert_eq!(merge("set_union", Some("[1,2]"), json!([2, 3, 3])), Ok("[1,2,3]".to_string()));
+        assert_eq!(merge("set_union", None, json!(["a"])), Ok(r#"["a"]"#.to_string()));
+        assert_eq!(
+            merge("set_union", Some(r#"[{"a":1,"b":2}]"#), json!([{"b": 2, "a": 1}, {"a": 2}])),
+            Ok(r#"[{"a":1,"b":2},{"a":2}]"#.to_string())
+        );
+        assert!(merge("set_union", Some("not an array"), json!([1])).is_err());
+    }
+
+    #[test]
+    fn test_unknown_function() {
+        assert_eq!(
+            merge("product", Some("1"), json!(1)),
+            Err(MergeError::UnknownFunction("product".to_string()))
+        );
+    }
+}
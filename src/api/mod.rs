@@ -1,2 +1,9 @@
+pub mod dedup;
 pub mod handler;
+mod json;
+pub mod key;
+mod merge;
 mod model;
+mod msgpack;
+pub mod pagination;
+mod validation;
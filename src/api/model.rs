@@ -1,6 +1,144 @@
-use serde::Deserialize;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
 
-#[derive(Deserialize)]
+/// Value of an upsert, read from the `ApplicationSettings::value_field` field of the payload.
 pub(crate) struct Value {
     pub value: String,
 }
+
+#[derive(Deserialize)]
+pub(crate) struct UpsertQuery {
+    /// Whether to return the value replaced by the upsert.
+    #[serde(default)]
+    pub return_prev: bool,
+    /// Seconds after which the key expires, overriding `ApplicationSettings::default_ttl_s`.
+    /// The key never expires when 0.
+    pub ttl: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ValueQuery {
+    /// Value to upsert, as an alternative to the request body.
+    pub value: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TouchQuery {
+    /// Seconds from now after which the key expires.
+    pub ttl: u64,
+}
+
+/// Payload of `POST /api/{key}/merge`.
+#[derive(Deserialize)]
+pub(crate) struct MergeRequest {
+    /// Name of the merge function, e.g. `sum`.
+    pub function: String,
+    /// The value to merge into the stored one.
+    pub value: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PreviousValue {
+    pub previous: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CountQuery {
+    /// Only keys starting with this prefix are counted.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Count {
+    pub count: usize,
+}
+
+/// Current limit and utilization of a concurrency limiter.
+#[derive(Serialize)]
+pub(crate) struct LimitStatus {
+    pub limit: usize,
+    pub in_flight: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Limits {
+    /// All application requests.
+    pub concurrency: LimitStatus,
+    /// Read-only API requests.
+    pub reads: LimitStatus,
+    /// Mutating API requests.
+    pub writes: LimitStatus,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Exists {
+    pub exists: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Ttl {
+    /// Remaining seconds before the key expires, rounded up, or `None` if it never expires.
+    pub ttl: Option<u64>,
+}
+
+/// A page of keys.
+#[derive(Serialize)]
+pub(crate) struct KeyPage {
+    pub keys: Vec<String>,
+    /// Cursor to request the next page with, or `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BatchUpsert {
+    pub entries: Vec<BatchEntry>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BatchEntry {
+    pub key: String,
+    #[serde(deserialize_with = "deserialize_string_or_number")]
+    pub value: String,
+}
+
+/// Outcome of a single entry in a batch upsert.
+#[derive(Serialize)]
+pub(crate) struct BatchEntryResult {
+    pub key: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BatchUpsertResult {
+    pub results: Vec<BatchEntryResult>,
+}
+
+/// Deserializes a JSON string, or a JSON number as its literal text.
+///
+/// Numbers are never converted to `f64`, so large integers and trailing zeros are preserved.
+fn deserialize_string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let raw = Box::<RawValue>::deserialize(deserializer)?;
+    string_or_number(&raw).ok_or_else(|| D::Error::custom("expected a string or a number"))
+}
+
+/// Reads a raw JSON string, or a JSON number as its literal text, see `deserialize_string_or_number`.
+/// # Returns
+/// * `Option<String>`: The text, or `None` for any other JSON type.
+pub(crate) fn string_or_number(raw: &RawValue) -> Option<String> {
+    if is_json_number(raw.get()) {
+        Some(raw.get().to_string())
+    } else {
+        serde_json::from_str(raw.get()).ok()
+    }
+}
+
+/// Checks whether the text is exactly a JSON number literal, e.g. `-12`, `3.50` or `1e10`.
+pub(crate) fn is_json_number(text: &str) -> bool {
+    text.starts_with(|c: char| c == '-' || c.is_ascii_digit())
+        && text.trim_end() == text
+        && serde_json::from_str::<&RawValue>(text).is_ok()
+}
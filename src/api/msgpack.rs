@@ -0,0 +1,104 @@
+use crate::api::json::{empty_body, DepthLimitedJson};
+use crate::api::model::{string_or_number, Value};
+use crate::dependency::ApplicationState;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Request};
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+
+/// Media type of MessagePack request and response bodies.
+pub(crate) const MSGPACK: &str = "application/msgpack";
+
+/// Body of an upsert or read in MessagePack, with the default value field.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct MsgPackValue {
+    pub value: MsgPackScalar,
+}
+
+/// A string or number, as MessagePack has distinct types for each width of number.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum MsgPackScalar {
+    String(String),
+    Integer(i64),
+    Unsigned(u64),
+    Float(f64),
+}
+
+/// Extractor for the upsert payload, decoded as MessagePack with `Content-Type: application/msgpack`,
+/// or as JSON otherwise.
+///
+/// The value is read from the `ApplicationSettings::value_field` field, e.g. `{"value": "text"}`, and the
+/// payload is rejected with `400` if it's missing.
+pub(crate) struct ValuePayload(pub Value);
+
+impl FromRequest<ApplicationState> for ValuePayload {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &ApplicationState) -> Result<Self, Self::Rejection> {
+        let field = &state.config.application.value_field;
+        let missing_field = || {
+            let message = format!("Request body is missing the `{}` field.", field);
+            (StatusCode::BAD_REQUEST, message).into_response()
+        };
+
+        if !is_msgpack(request.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok())) {
+            let DepthLimitedJson(mut fields) =
+                DepthLimitedJson::<HashMap<String, Box<RawValue>>>::from_request(request, state).await?;
+            let raw = fields.remove(field).ok_or_else(missing_field)?;
+            let value = string_or_number(&raw).ok_or_else(|| {
+                let message = format!("Field `{}` must be a string or a number.", field);
+                (StatusCode::BAD_REQUEST, message).into_response()
+            })?;
+            return Ok(ValuePayload(Value { value }));
+        }
+
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if bytes.is_empty() {
+            return Err(empty_body());
+        }
+        let mut fields: HashMap<String, MsgPackScalar> = rmp_serde::from_slice(&bytes).map_err(|err| {
+            let message = format!("Failed to decode the MessagePack body: {}", err);
+            (StatusCode::BAD_REQUEST, message).into_response()
+        })?;
+        let value = match fields.remove(field).ok_or_else(missing_field)? {
+            MsgPackScalar::String(value) => value,
+            MsgPackScalar::Integer(value) => value.to_string(),
+            MsgPackScalar::Unsigned(value) => value.to_string(),
+            MsgPackScalar::Float(value) => value.to_string(),
+        };
+        Ok(ValuePayload(Value { value }))
+    }
+}
+
+/// Whether the client asked for a MessagePack response with the `Accept` header.
+pub(crate) fn accepts_msgpack(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| is_msgpack(Some(media_type)))
+}
+
+/// Encodes a value as a MessagePack response body.
+pub(crate) fn msgpack_response<T: Serialize>(value: &T) -> Response {
+    // Note: `to_vec_named` encodes structs as maps rather than arrays, so fields are identified by name.
+    match rmp_serde::to_vec_named(value) {
+        Ok(body) => ([(CONTENT_TYPE, MSGPACK)], body).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn is_msgpack(media_type: Option<&str>) -> bool {
+    media_type.is_some_and(|media_type| {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        essence.eq_ignore_ascii_case(MSGPACK)
+    })
+}
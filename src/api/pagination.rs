@@ -0,0 +1,103 @@
+use crate::dependency::ApplicationState;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use serde::Deserialize;
+
+/// Extractor for the `limit`, `offset` and `cursor` query parameters of listing endpoints.
+///
+/// `limit` defaults to, and may not exceed, `ApplicationSettings::max_page_size`.
+#[derive(Debug, PartialEq)]
+pub struct Pagination {
+    /// Maximum number of items to return.
+    pub limit: usize,
+    /// Number of items to skip, after the cursor if any.
+    pub offset: usize,
+    /// Opaque position returned by the previous page. Listing resumes after it.
+    pub cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PaginationQuery {
+    // Note: Signed so negative values are reported as such, rather than as a parsing error.
+    limit: Option<i64>,
+    offset: Option<i64>,
+    cursor: Option<String>,
+}
+
+impl FromRequestParts<ApplicationState> for Pagination {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &ApplicationState,
+    ) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<PaginationQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|rejection| (StatusCode::BAD_REQUEST, rejection.body_text()))?;
+        let max_page_size = state.config.application.max_page_size;
+
+        let limit = match query.limit {
+            None => max_page_size,
+            Some(limit) if limit < 1 => {
+                return Err((StatusCode::BAD_REQUEST, "limit must be at least 1.".to_string()));
+            }
+            Some(limit) if limit as u64 > max_page_size as u64 => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("limit must be at most {}.", max_page_size),
+                ));
+            }
+            Some(limit) => limit as usize,
+        };
+        let offset = match query.offset {
+            None => 0,
+            Some(offset) if offset < 0 => {
+                return Err((StatusCode::BAD_REQUEST, "offset must not be negative.".to_string()));
+            }
+            Some(offset) => offset as usize,
+        };
+
+        Ok(Pagination {
+            limit,
+            offset,
+            cursor: query.cursor,
+        })
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_settings;
+    use axum::http::Request;
+    use std::sync::Arc;
+
+    async fn extract(query: &str) -> Result<Pagination, (StatusCode, String)> {
+        let mut settings = test_settings();
+        settings.application.max_page_size = 100;
+        let state = ApplicationState::new(Arc::new(settings));
+        let (mut parts, _) = Request::get(format!("/api?{}", query)).body(()).unwrap().into_parts();
+        Pagination::from_request_parts(&mut parts, &state).await
+    }
+
+    #[tokio::test]
+    async fn test_pagination() {
+        let pagination = extract("").await.unwrap();
+        assert_eq!(pagination, Pagination { limit: 100, offset: 0, cursor: None });
+
+        let pagination = extract("limit=10&offset=20&cursor=key").await.unwrap();
+        assert_eq!(pagination, Pagination { limit: 10, offset: 20, cursor: Some("key".to_string()) });
+
+        for (query, error) in [
+            ("limit=0", "limit must be at least 1."),
+            ("limit=101", "limit must be at most 100."),
+            ("offset=-1", "offset must not be negative."),
+        ] {
+            assert_eq!(extract(query).await.unwrap_err(), (StatusCode::BAD_REQUEST, error.to_string()));
+        }
+        assert_eq!(extract("limit=ten").await.unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+}
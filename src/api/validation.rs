@@ -0,0 +1,99 @@
+use crate::configuration::{ApplicationSettings, Normalization};
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+/// Reasons for rejecting a value on write.
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum ValueError {
+    #[error("Value is empty.")]
+    Empty,
+    #[error("Value exceeds the maximum length of {0} bytes.")]
+    TooLong(usize),
+}
+
+/// Reasons for rejecting a key, see `Key::parse`.
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum KeyError {
+    #[error("Key is empty.")]
+    Empty,
+    #[error("Key exceeds the maximum length of {0} bytes.")]
+    TooLong(usize),
+    #[error("Key contains the invalid character {0:?}, whitespace and control characters aren't allowed.")]
+    InvalidCharacter(char),
+    #[error("Keys starting with '{0}' are reserved.")]
+    Reserved(String),
+    #[error("Prefix would match keys starting with '{0}', which are reserved.")]
+    ReservedPrefix(String),
+    #[error("Key is not valid {0}.")]
+    InvalidEncoding(&'static str),
+}
+
+/// Checks that a value can be written to the database.
+/// # Arguments
+/// * `value`: The value to validate.
+/// * `config`: The application settings that hold the validation limits.
+pub(crate) fn validate_value(value: &str, config: &ApplicationSettings) -> Result<(), ValueError> {
+    if value.is_empty() {
+        Err(ValueError::Empty)
+    } else if value.len() > config.max_value_length {
+        Err(ValueError::TooLong(config.max_value_length))
+    } else {
+        Ok(())
+    }
+}
+
+/// Applies the configured normalizations to a value, in order.
+///
+/// Runs before `validate_value`, so e.g. a whitespace-only value is rejected as empty once trimmed.
+/// # Arguments
+/// * `value`: The value to normalize.
+/// * `normalizations`: The normalizations to apply.
+pub(crate) fn normalize_value(value: String, normalizations: &[Normalization]) -> String {
+    normalizations
+        .iter()
+        .fold(value, |value, normalization| match normalization {
+            Normalization::Trim => value.trim().to_string(),
+            Normalization::CollapseWhitespace => {
+                let mut collapsed = String::with_capacity(value.len());
+                for c in value.chars() {
+                    if !c.is_whitespace() {
+                        collapsed.push(c);
+                    } else if !collapsed.ends_with(' ') {
+                        collapsed.push(' ');
+                    }
+                }
+                collapsed
+            }
+            Normalization::Nfc => value.nfc().collect(),
+        })
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_value() {
+        let value = || " a \t\n b ".to_string();
+
+        assert_eq!(normalize_value(value(), &[]), " a \t\n b ");
+        assert_eq!(normalize_value(value(), &[Normalization::Trim]), "a \t\n b");
+        assert_eq!(normalize_value(value(), &[Normalization::CollapseWhitespace]), " a b ");
+        assert_eq!(
+            normalize_value(value(), &[Normalization::Trim, Normalization::CollapseWhitespace]),
+            "a b"
+        );
+        // "e" followed by a combining acute accent composes into "é".
+        assert_eq!(normalize_value("e\u{301}".to_string(), &[Normalization::Nfc]), "\u{e9}");
+    }
+
+    #[test]
+    fn test_whitespace_only_value_is_empty_once_trimmed() {
+        let config = crate::test_util::test_settings().application;
+        let value = normalize_value("   ".to_string(), &[Normalization::Trim]);
+
+        assert_eq!(validate_value(&value, &config), Err(ValueError::Empty));
+    }
+}
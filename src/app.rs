@@ -0,0 +1,453 @@
+use crate::configuration::ApplicationSettings;
+use crate::dependency::ApplicationState;
+use crate::middleware::Middleware;
+use crate::route::ApplicationRoute;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::{ConnectInfo, Request};
+use axum::response::Response;
+use axum::serve::{IncomingStream, Listener};
+use axum::Router;
+use http_body::{Frame, SizeHint};
+use std::convert::Infallible;
+use std::future::{ready, Future, Ready};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Notify};
+use tokio::time::{Instant, Sleep};
+use tower::Service;
+use tracing::{info, warn};
+
+/// Builds the application router with all routes and global middleware attached.
+/// # Arguments
+/// * `state`: The application state shared by all handlers.
+pub fn build_app(state: ApplicationState) -> Router {
+    let config = state.config.clone();
+    // Note: Middleware added with `Router::layer` only wraps the routes that already exist,
+    //       so routes must be added first.
+    Router::new()
+        .add_routes(&state)
+        .add_middleware(config)
+        // Ref: https://docs.rs/axum/latest/axum/struct.Router.html#returning-routers-with-states-from-functions
+        .with_state(state)
+}
+
+/// Binds the listener for the server on the configured host and port.
+///
+/// With port `0`, the OS picks a free port, e.g. for tests and dynamic deployments, so the actual address is
+/// returned and logged rather than the configured one.
+/// # Arguments
+/// * `config`: The application settings.
+/// # Returns
+/// * `(TcpListener, SocketAddr)`: The listener and the address it's bound to.
+pub async fn bind(config: &ApplicationSettings) -> io::Result<(TcpListener, SocketAddr)> {
+    let listener = TcpListener::bind((config.host.as_str(), config.port)).await?;
+    let address = listener.local_addr()?;
+    info!("Listening on {}...", address);
+    Ok((listener, address))
+}
+
+/// Serves the application until `shutdown` completes, then waits for the open connections to drain.
+///
+/// Connections are closed after `ApplicationSettings::idle_timeout_s` without activity between requests.
+/// On shutdown, connections that haven't drained within `ApplicationSettings::force_shutdown_after_s`, e.g.
+/// long-lived streams, are force-closed along with their in-flight requests, so no handler still runs once
+/// this returns.
+/// Connections that don't close within `FORCE_CLOSE_GRACE` of being force-closed, e.g. with a handler blocking
+/// its thread, are abandoned and logged.
+/// # Arguments
+/// * `listener`: The listener returned by `bind`.
+/// * `router`: The application router.
+/// * `shutdown`: Completes when the server should stop accepting connections.
+/// * `config`: The application settings.
+pub async fn serve(
+    listener: TcpListener,
+    router: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+    config: &ApplicationSettings,
+) -> io::Result<()> {
+    let seconds = |seconds: u64| (seconds > 0).then(|| Duration::from_secs(seconds));
+    let force_after = seconds(config.force_shutdown_after_s);
+    let open = Arc::new(AtomicUsize::new(0));
+    let (force_close, force_closed) = watch::channel(false);
+    let listener = ConnectionListener {
+        inner: listener,
+        open: open.clone(),
+        force_closed,
+        idle_timeout: seconds(config.idle_timeout_s),
+    };
+    let shutdown_started = Arc::new(Notify::new());
+    let started = shutdown_started.clone();
+    let server = axum::serve(listener, MakeConnectionService { router })
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            started.notify_one();
+        })
+        .into_future();
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return result,
+        _ = shutdown_started.notified() => {}
+    }
+    let Some(force_after) = force_after else {
+        return server.await;
+    };
+    if let Ok(result) = tokio::time::timeout(force_after, &mut server).await {
+        return result;
+    }
+    let remaining = open.load(Ordering::SeqCst);
+    warn!("Force-closing {} connections that didn't drain within {:?}.", remaining, force_after);
+    force_close.send_replace(true);
+    // Note: The graceful shutdown completes once the force-closed connections are dropped.
+    match tokio::time::timeout(FORCE_CLOSE_GRACE, &mut server).await {
+        Ok(result) => {
+            info!("Force-closed {} connections.", remaining);
+            result
+        }
+        Err(_) => {
+            warn!(
+                "Abandoning {} connections that didn't close within {:?} of being force-closed.",
+                open.load(Ordering::SeqCst),
+                FORCE_CLOSE_GRACE
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Time force-closed connections are given to be dropped, see `serve`.
+const FORCE_CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+/// Listener that keeps count of its open connections, and applies the idle timeout to them.
+struct ConnectionListener {
+    inner: TcpListener,
+    open: Arc<AtomicUsize>,
+    /// Set to `true` when the open connections are force-closed on shutdown.
+    force_closed: watch::Receiver<bool>,
+    idle_timeout: Option<Duration>,
+}
+
+impl Listener for ConnectionListener {
+    type Io = Connection;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, address) = Listener::accept(&mut self.inner).await;
+        self.open.fetch_add(1, Ordering::SeqCst);
+        let mut force_closed = self.force_closed.clone();
+        let connection = Connection {
+            inner: stream,
+            open: self.open.clone(),
+            force_closed: Box::pin(async move {
+                let _ = force_closed.wait_for(|closed| *closed).await;
+            }),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            idle_paused: false,
+            idle: self.idle_timeout.map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout)))),
+        };
+        (connection, address)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Connection accepted by a `ConnectionListener`, which is no longer counted once dropped.
+struct Connection {
+    inner: TcpStream,
+    open: Arc<AtomicUsize>,
+    /// Completes when the connection is force-closed on shutdown.
+    force_closed: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// Number of requests being handled, or whose response is being sent, during which the idle timer is
+    /// paused.
+    in_flight: Arc<AtomicUsize>,
+    /// Whether the idle timer was paused by a request in flight, so it's restarted once none is left.
+    idle_paused: bool,
+    /// The idle timeout, and the timer reset whenever bytes are read or written.
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl Connection {
+    /// Fails the I/O once the connection is force-closed, so the server drops the connection along with its
+    /// in-flight requests, which closes the socket.
+    fn poll_force_closed(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        self.force_closed
+            .as_mut()
+            .poll(cx)
+            .map(|()| io::Error::new(io::ErrorKind::ConnectionAborted, "connection is force-closed"))
+    }
+
+    /// Resets the idle timer after a read or write made progress, then passes the result through.
+    /// While the I/O is pending, fails it once the idle timer expires, so the server closes the connection.
+    ///
+    /// The timer is paused while a request is in flight, since the server keeps polling the socket for EOF
+    /// during slow handlers, which isn't the connection being idle.
+    fn track<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let Some((timeout, timer)) = &mut self.idle else {
+            return poll;
+        };
+        if self.in_flight.load(Ordering::SeqCst) > 0 {
+            self.idle_paused = true;
+            return poll;
+        }
+        if poll.is_ready() || std::mem::take(&mut self.idle_paused) {
+            timer.as_mut().reset(Instant::now() + *timeout);
+            return poll;
+        }
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "connection is idle"))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Poll::Ready(err) = this.poll_force_closed(cx) {
+            return Poll::Ready(Err(err));
+        }
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.track(cx, poll)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Poll::Ready(err) = this.poll_force_closed(cx) {
+            return Poll::Ready(Err(err));
+        }
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.track(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Makes the service of each connection, exposing the client address as `ConnectInfo<SocketAddr>`, e.g. to
+/// `RequestContext`, and counting the requests in flight on the connection.
+struct MakeConnectionService {
+    router: Router,
+}
+
+impl Service<IncomingStream<'_, ConnectionListener>> for MakeConnectionService {
+    type Response = ConnectionService;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: IncomingStream<'_, ConnectionListener>) -> Self::Future {
+        ready(Ok(ConnectionService {
+            router: self.router.clone(),
+            remote: *stream.remote_addr(),
+            in_flight: stream.io().in_flight.clone(),
+        }))
+    }
+}
+
+/// Serves the requests of a connection with the router, see `MakeConnectionService`.
+#[derive(Clone)]
+struct ConnectionService {
+    router: Router,
+    remote: SocketAddr,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Service<Request> for ConnectionService {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<Request>::poll_ready(&mut self.router, cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        request.extensions_mut().insert(ConnectInfo(self.remote));
+        let in_flight = InFlight::new(self.in_flight.clone());
+        let response = self.router.call(request);
+        Box::pin(async move {
+            let response = response.await?;
+            // The request stays in flight until its response body is sent, e.g. a slow stream.
+            Ok(response.map(|body| Body::new(InFlightBody { inner: body, _in_flight: in_flight })))
+        })
+    }
+}
+
+/// Counts a request in flight on its connection until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Response body that keeps its request counted in flight until the body is sent or dropped.
+struct InFlightBody {
+    inner: Body,
+    _in_flight: InFlight,
+}
+
+impl HttpBody for InFlightBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{test_settings, TraceCapture};
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_bind_ephemeral_port() {
+        let mut settings = test_settings();
+        settings.application.port = 0;
+        let (listener, address) = bind(&settings.application).await.unwrap();
+        assert_ne!(address.port(), 0);
+
+        let router = build_app(ApplicationState::new(Arc::new(settings)));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_force_closes_hanging_connections() {
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let mut settings = test_settings();
+        settings.application.port = 0;
+        let (listener, address) = bind(&settings.application).await.unwrap();
+        let router = Router::new().route("/hang", get(std::future::pending::<()>));
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = async move {
+            let _ = shutdown_rx.await;
+        };
+        settings.application.force_shutdown_after_s = 1;
+        let config = settings.application;
+        let server = tokio::spawn(async move { serve(listener, router, shutdown, &config).await });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /hang HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown_tx.send(()).unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), server).await;
+        assert!(result.expect("shutdown hung").unwrap().is_ok());
+        let events = capture.events();
+        assert!(events.iter().any(|event| event.fields["message"].starts_with("Force-closing 1 connections")));
+        assert!(events.iter().any(|event| event.fields["message"] == "Force-closed 1 connections."));
+
+        // The socket was closed rather than abandoned.
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut response)).await;
+        assert_eq!(read.expect("connection wasn't closed").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections_are_closed() {
+        let mut settings = test_settings();
+        settings.application.port = 0;
+        settings.application.idle_timeout_s = 1;
+        let (listener, address) = bind(&settings.application).await.unwrap();
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let config = settings.application;
+        tokio::spawn(async move { serve(listener, router, std::future::pending(), &config).await });
+
+        // A keep-alive connection that goes idle after its first request.
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let started_at = Instant::now();
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+        assert!(read.expect("idle connection wasn't closed").is_ok());
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 200 OK"));
+        assert!(started_at.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_slow_requests_are_not_idle() {
+        let mut settings = test_settings();
+        settings.application.port = 0;
+        settings.application.idle_timeout_s = 1;
+        let (listener, address) = bind(&settings.application).await.unwrap();
+        let router = Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(2_500)).await;
+                "done"
+            }),
+        );
+        let config = settings.application;
+        tokio::spawn(async move { serve(listener, router, std::future::pending(), &config).await });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response)).await;
+        assert!(read.expect("slow request hung").is_ok());
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("done"));
+    }
+}
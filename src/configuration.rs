@@ -1,7 +1,16 @@
+use std::collections::{BTreeSet, HashMap};
 use std::env;
-use config::{Config, Value};
+use std::fmt;
+use std::path::Path;
+use config::builder::{ConfigBuilder, DefaultState};
+use config::{Config, FileFormat, FileSourceString, Map, Value, ValueKind};
+use ipnet::IpNet;
 use serde_aux::prelude::deserialize_number_from_string;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::filter::LevelFilter;
+
+/// Environment variable holding a JSON object of overrides, see `load_configuration`.
+const CONFIG_JSON_VAR: &str = "APP_CONFIG_JSON";
 
 /// Global settings.
 #[derive(Deserialize, Clone, Debug)]
@@ -13,7 +22,8 @@ pub struct Settings {
 /// Application-specific settings.
 /// 
 /// Set default values in the `get_configuration` function.
-#[derive(Deserialize, Clone, Debug)]
+// Note: `Serialize`, so a reload can tell which settings changed.
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ApplicationSettings {
     pub host: String,
     #[serde(deserialize_with = "deserialize_number_from_string")]
@@ -21,9 +31,343 @@ pub struct ApplicationSettings {
     /// Maximum number of in-flight requests before throttling.
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub max_concurrent_requests: usize,
+    /// Maximum number of in-flight read-only API requests (`GET`, `HEAD`, `OPTIONS`).
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_concurrent_reads: usize,
+    /// Maximum number of in-flight mutating API requests.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_concurrent_writes: usize,
+    /// Milliseconds a request waits for a slot when a concurrency limit is reached, before it's shed with `503`.
+    /// Requests are shed immediately when 0.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub shed_after_ms: u64,
+    /// Maximum number of requests waiting for a slot at once, see `shed_after_ms`. Requests beyond it are
+    /// rejected immediately with `503`. The queue isn't limited when unset.
+    pub max_queued_requests: Option<usize>,
+    /// Seconds after which clients are told to retry requests rejected with `429` or `503` by a concurrency limit,
+    /// in the `Retry-After` header and the `retry_after_s` field of the JSON body.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_after_s: u64,
+    /// Maximum number of in-flight requests per client, identified by its `X-API-Key` header or IP address.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_concurrent_per_client: usize,
+    /// API keys accepted in the `X-API-Key` header to identify a client for `max_concurrent_per_client`, e.g.
+    /// one per tenant behind a shared proxy. Clients sending any other key are identified by their IP address,
+    /// so they can't bypass the limit with a new key per request.
+    pub client_api_keys: Vec<String>,
     /// Request timeout in seconds.
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub request_timeout_s: u64,
+    /// Seconds connections are given to drain on graceful shutdown, after which the remaining ones, e.g.
+    /// long-lived streams, are force-closed. Shutdown waits for all connections when 0.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub force_shutdown_after_s: u64,
+    /// Seconds without any bytes read or written after which a connection is closed, e.g. an idle keep-alive
+    /// connection, to free its file descriptor. Connections are never closed for being idle when 0.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub idle_timeout_s: u64,
+    /// Timeouts in seconds of specific routes, e.g. `{"GET /api": 60}` for listing, overriding
+    /// `request_timeout_s`. Patterns have the same format as `trace_exclude`, and a pattern with a method takes
+    /// precedence over the same route without one.
+    pub route_timeouts_s: HashMap<String, u64>,
+    /// Route patterns exempt from the request timeout, e.g. long-polling or streaming endpoints.
+    /// Patterns have the same format as `trace_exclude`.
+    pub timeout_exempt_routes: Vec<String>,
+    /// Maximum length of a key in bytes.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_key_length: usize,
+    /// Maximum length of a stored value in bytes.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_value_length: usize,
+    /// Maximum number of elements in an array built with `POST /api/{key}/append`. The oldest elements are
+    /// dropped beyond it. Arrays aren't limited when unset.
+    pub max_append_length: Option<usize>,
+    /// Maximum total size in bytes of the request header names and values. Larger header sets, e.g. with
+    /// many cookies, are rejected with `431`.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_header_bytes: usize,
+    /// Maximum number of query parameters of a request. Requests with more are rejected with `400`.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_query_params: usize,
+    /// Maximum size in bytes of the query string of a request, still percent-encoded. Longer query strings are
+    /// rejected with `400`.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_query_bytes: usize,
+    /// Maximum nesting depth of arrays and objects in a JSON request body. Deeper bodies are rejected with `400`.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_json_depth: usize,
+    /// Window in milliseconds during which an identical upsert (same key and value) is suppressed.
+    /// Set to 0 to disable de-duplication.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub dedup_window_ms: u64,
+    /// Seconds after which keys written without their own TTL expire. Keys never expire by default (0).
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub default_ttl_s: u64,
+    /// Interval in seconds between sweeps that remove expired keys, which otherwise keep using memory until
+    /// they're written again. Each sweep holds the database write lock. Expired keys are never swept when 0.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub expiry_sweep_interval_s: u64,
+    /// `max-age` in seconds of the `Cache-Control` header of reads. Clients must revalidate with the `ETag`
+    /// before reusing a read (`no-cache`) when 0.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub read_cache_max_age_s: u64,
+    /// Seconds after startup during which `GET /ready` responds with `503`, to let dependencies stabilize.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub warmup_delay_s: u64,
+    /// Approximate memory in bytes that stored keys and values may use. Writes beyond it are handled according
+    /// to `memory_limit_policy`. Memory isn't limited when unset.
+    pub max_memory_bytes: Option<u64>,
+    /// What happens to a write that would exceed `max_memory_bytes`.
+    pub memory_limit_policy: MemoryLimitPolicy,
+    /// Number of entries at which `GET /healthz` reports memory pressure as degraded. Entries aren't limited
+    /// when unset.
+    pub max_entries: Option<usize>,
+    /// Bearer token required by the admin API. The admin API is disabled when unset.
+    pub admin_token: Option<String>,
+    /// How stored values are represented in read responses.
+    pub value_type: ValueType,
+    /// Whether stored values that are JSON objects or arrays are returned as JSON on reads with
+    /// `Accept: application/json`. Other values, including invalid JSON, are returned as-is.
+    pub auto_parse_json_values: bool,
+    /// Route patterns for which no request span is created, to save tracing overhead on hot paths.
+    /// A pattern is a route template, optionally prefixed with a method, e.g. `/health` or `GET /api/{key}`.
+    pub trace_exclude: Vec<String>,
+    /// Maximum level of the logs written, e.g. `debug`, or `off`. Defaults to `trace` in local and `info` in
+    /// prod. Reloadable without a restart, see `ApplicationState::reload`.
+    pub log_level: String,
+    /// Logs only 1 in N requests of the routes matching a pattern, e.g. `{"GET /api/{key}": 100}`, in the same
+    /// format as `trace_exclude`. A pattern with a method takes precedence over the same route without one.
+    /// Requests are sampled by trace ID, and the span is still created.
+    pub log_sample_rate: HashMap<String, u64>,
+    /// Milliseconds after which a request is always logged, regardless of `log_sample_rate`.
+    /// Requests failing with a server error are always logged too.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub log_slow_ms: u64,
+    /// Route patterns that aren't recorded in the metrics, e.g. noisy probes, in the same format as
+    /// `trace_exclude`. Defaults to the metrics and health check routes.
+    pub metrics_exclude: Vec<String>,
+    /// Tracing levels (e.g. `debug`) of the request span and its events, by route template.
+    /// Routes not listed use `TRACE` spans in local and `INFO` spans in prod, with `INFO` events.
+    pub trace_levels: HashMap<String, String>,
+    /// How a request with multiple `X-Trace-ID` headers is handled. A warning is logged in any case.
+    pub duplicate_trace_id: DuplicateTraceId,
+    /// Whether keys are hashed in logs, for keys that may contain PII, i.e. the path parameters (e.g. the key)
+    /// in the request span's `uri` field and the keys in log events. Handlers still see the real key.
+    pub hash_keys_in_logs: bool,
+    /// Percentage of requests, from 0 to 100, tagged for canary behavior by a hash of their trace ID.
+    /// Handlers read the tag from `RequestContext::canary`, and it's recorded on the request span.
+    pub canary_percent: f64,
+    /// Whether responses carry an `X-Response-Time-ms` header with the server processing time.
+    pub response_time_header: bool,
+    /// Whether response bodies are wrapped as `{"data": ..., "error": ..., "trace_id": ...}`.
+    /// JSON bodies are embedded as JSON, text bodies as strings, and error bodies go in `error` instead.
+    pub envelope_responses: bool,
+    /// Route patterns whose responses are never wrapped by `envelope_responses`, in the same format as
+    /// `trace_exclude`. Defaults to the metrics route, whose format scrapers expect as-is.
+    pub envelope_exclude: Vec<String>,
+    /// Whether JSON response bodies are pretty-printed for readability. Defaults to on in `Local` only.
+    pub pretty_json: bool,
+    /// Fixed JSON responses served by path, like a config server, e.g. `{"/flags": {"dark_mode": true}}`.
+    /// Static routes are read-only and never touch the backend. Paths are fixed, i.e. without `{` or `*`, and
+    /// can't collide with the built-in routes, e.g. `/health`, or be under `/api`, `/admin` or `/debug`.
+    pub static_routes: HashMap<String, serde_json::Value>,
+    /// Whether values can be upserted with `PUT /api/{key}?value=...`, e.g. from a browser during manual
+    /// testing. Defaults to on in `Local` only.
+    pub query_upserts: bool,
+    /// Path of the file the in-memory backend is saved to on graceful shutdown, and preloaded from on startup.
+    /// Snapshots are disabled when unset.
+    pub snapshot_path: Option<String>,
+    /// HTTP methods accepted on any route, e.g. `["GET", "HEAD"]` for a read-only deployment. Other methods are
+    /// rejected with `405`. All methods are allowed when empty.
+    pub allowed_methods: Vec<String>,
+    /// Default and maximum number of items returned by a page of a listing endpoint.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_page_size: usize,
+    /// Hard cap on the number of items in a listing response, regardless of the requested page size.
+    /// Listings beyond it are truncated with `206` and `X-Truncated: true`, and resume from their cursor.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_response_entries: usize,
+    /// Value reads and listings larger than this many bytes are streamed in chunks rather than buffered into
+    /// a single response body, to reduce peak memory. Streamed listings are always compact JSON.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub stream_threshold_bytes: usize,
+    /// Number of recent requests captured for `GET /debug/requests`, which only exists in `Local`.
+    /// Capturing is disabled when 0.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub debug_capture_size: usize,
+    /// Compression level of gzip and brotli responses, from 0 (fastest) to 9 (smallest).
+    /// Each algorithm's default level is used when unset.
+    pub compression_level: Option<i32>,
+    /// CIDR ranges of reverse proxies, e.g. `10.0.0.0/8`, whose `X-Forwarded-For` and `X-Forwarded-Proto` headers
+    /// are trusted. The headers are ignored on requests from any other peer, so clients can't spoof them.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Key prefix reserved for internal entries stored alongside user data. User requests for keys with this
+    /// prefix are rejected with `400`. Nothing is reserved when empty.
+    pub reserved_key_prefix: String,
+    /// Encoding of the keys of the `/api/b64/{encoded_key}` routes, which address keys that can't be used as
+    /// a path segment as-is, e.g. with slashes.
+    pub key_encoding: KeyEncoding,
+    /// Whether keys are lowercased before they reach the backend, so e.g. `Foo` and `foo` refer to the same entry.
+    pub case_insensitive_keys: bool,
+    /// Name of the payload field that holds the value of an upsert, e.g. `data` for `{"data": "text"}`.
+    pub value_field: String,
+    /// Normalizations applied in order to values before they are validated and written.
+    pub value_normalization: Vec<Normalization>,
+    /// How values are transformed before they're stored, e.g. compressed or encrypted at rest. Reads decode
+    /// them transparently.
+    pub value_codec: ValueCodecKind,
+    /// Base64-encoded 32-byte key of the `aes` value codec.
+    pub value_codec_key: Option<String>,
+    /// Upper bounds of the request latency histogram buckets in milliseconds, in increasing order.
+    pub latency_buckets_ms: Vec<f64>,
+    /// Whether request latencies are also tracked with an HDR histogram, to report accurate p50, p90, p99 and
+    /// p99.9 quantiles in `GET /metrics`.
+    pub hdr_latency: bool,
+}
+
+/// Encoding of keys in the `/api/b64/{encoded_key}` routes.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyEncoding {
+    /// URL-safe base64, with or without padding, e.g. `YS9i` for `a/b`.
+    Base64Url,
+    /// Percent-encoding, e.g. `a%2Fb` for `a/b`.
+    Percent,
+}
+
+/// Server-side normalization of written values.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Normalization {
+    /// Removes leading and trailing whitespace.
+    Trim,
+    /// Replaces each run of whitespace with a single space.
+    CollapseWhitespace,
+    /// Applies Unicode Normalization Form C.
+    Nfc,
+}
+
+/// Transformation applied to values at rest, see `crate::repo::codec`.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueCodecKind {
+    /// Values are stored as-is.
+    Identity,
+    /// Values are stored gzip-compressed.
+    Gzip,
+    /// Values are stored encrypted with AES-256-GCM, using `ApplicationSettings::value_codec_key`.
+    Aes,
+}
+
+/// Handling of writes that would exceed `ApplicationSettings::max_memory_bytes`.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryLimitPolicy {
+    /// Rejects the write with `507 Insufficient Storage`.
+    Reject,
+    /// Evicts the least recently used entries until the write fits.
+    EvictLru,
+}
+
+/// Handling of requests with multiple `X-Trace-ID` headers.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateTraceId {
+    /// Uses the first header.
+    First,
+    /// Rejects the request with `400`.
+    Reject,
+    /// Ignores the headers and generates a new trace ID.
+    Generate,
+}
+
+/// Representation of stored values in read responses.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    /// Values are returned as plain text.
+    String,
+    /// Values that are valid JSON numbers are returned as JSON numbers, others as plain text.
+    /// The number's literal text is stored as-is, so large integers don't lose precision.
+    Number,
+}
+
+/// Paths of the built-in routes, which static routes can't replace, see `ApplicationRoute::add_routes`.
+const BUILT_IN_ROUTES: [&str; 5] = ["/", "/metrics", "/health", "/ready", "/healthz"];
+
+/// Prefixes of the nested built-in routes, under which static routes would shadow e.g. a key of `/api/{key}`.
+const BUILT_IN_PREFIXES: [&str; 3] = ["/api", "/admin", "/debug"];
+
+impl Settings {
+    /// Checks constraints between and within settings that deserialization can't express.
+    pub fn validate(&self) -> Result<(), config::ConfigError> {
+        let buckets = &self.application.latency_buckets_ms;
+        if buckets.is_empty() {
+            return Err(config::ConfigError::Message(
+                "application.latency_buckets_ms must not be empty.".into(),
+            ));
+        }
+        if !buckets.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(config::ConfigError::Message(
+                "application.latency_buckets_ms must be sorted in strictly increasing order.".into(),
+            ));
+        }
+        if let Some(level) = self.application.compression_level
+            && !(0..=9).contains(&level)
+        {
+            return Err(config::ConfigError::Message(format!(
+                "application.compression_level must be between 0 and 9, got {}.",
+                level
+            )));
+        }
+        let canary_percent = self.application.canary_percent;
+        if !(0.0..=100.0).contains(&canary_percent) {
+            return Err(config::ConfigError::Message(format!(
+                "application.canary_percent must be between 0 and 100, got {}.",
+                canary_percent
+            )));
+        }
+        if self.application.log_level.parse::<LevelFilter>().is_err() {
+            return Err(config::ConfigError::Message(format!(
+                "Invalid application.log_level '{}'.",
+                self.application.log_level
+            )));
+        }
+        for (route, level) in &self.application.trace_levels {
+            if level.parse::<tracing::Level>().is_err() {
+                return Err(config::ConfigError::Message(format!(
+                    "Invalid tracing level '{}' for route '{}' in application.trace_levels.",
+                    level, route
+                )));
+            }
+        }
+        let mut static_routes: Vec<_> = self.application.static_routes.keys().collect();
+        static_routes.sort();
+        for path in static_routes {
+            let prefix = BUILT_IN_PREFIXES
+                .iter()
+                .find(|prefix| path == *prefix || path.starts_with(&format!("{}/", prefix)));
+            let problem = if !path.starts_with('/') {
+                "must start with '/'".to_string()
+            } else if path.contains(['{', '*']) {
+                "must not contain '{' or '*', since it's a fixed path".to_string()
+            } else if BUILT_IN_ROUTES.contains(&path.as_str()) {
+                "collides with a built-in route".to_string()
+            } else if let Some(prefix) = prefix {
+                format!("must not be under the built-in '{}' prefix", prefix)
+            } else {
+                continue;
+            };
+            return Err(config::ConfigError::Message(format!(
+                "Static route '{}' in application.static_routes {}.",
+                path, problem
+            )));
+        }
+        if let Err(err) = crate::repo::codec::codec_from_settings(&self.application) {
+            return Err(config::ConfigError::Message(err.to_string()));
+        }
+        Ok(())
+    }
 }
 
 /// Runtime environment
@@ -77,35 +421,528 @@ impl From<Environment> for Value {
 /// Reads and parses configurations from either YAML files or environment variables.
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
     let base_path = env::current_dir().expect("Failed to determine the current directory");
-    let configuration_directory = base_path.join("configuration");
-
-    // Detect the running environment.
-    // Default to `local` if unspecified.
-    let environment: Environment = env::var("APP_ENVIRONMENT")
-        .unwrap_or_else(|_| Environment::Local.into())
-        .try_into()
-        .expect("Failed to parse APP_ENVIRONMENT.");  // Note: Safe to panic as it's not supposed to happen
+    load_configuration(&base_path.join("configuration"), env::vars().collect())
+}
+
+/// Reads and parses configurations from the YAML files in `configuration_directory` and the given
+/// environment variables.
+///
+/// Sources are applied in increasing order of precedence:
+/// 1. Default values set in `set_defaults`.
+/// 2. `base.yaml`.
+/// 3. `<environment>.yaml`, e.g. `local.yaml`.
+/// 4. The JSON object in the `APP_CONFIG_JSON` environment variable, e.g. `{"application": {"port": 8080}}`,
+///    to pass many overrides at once in CI.
+/// 5. `APP_`-prefixed environment variables, e.g. `APP_APPLICATION__PORT`.
+/// 6. The bare `PORT` environment variable injected by PaaS platforms (Heroku, Render, etc.), which
+///    overrides `application.port` since the platform routes traffic to that port only.
+///
+/// With `CONFIG_FROM_ENV=1`, the YAML files are skipped and the configuration directory doesn't need
+/// to exist, for 12-factor deployments that ship no config files.
+///
+/// With `CONFIG_REQUIRED_KEYS`, a comma-separated list of keys such as `application.port,application.host`,
+/// loading fails unless each listed key is set by a source other than the defaults, so a typo in a key
+/// name doesn't silently fall back to its default.
+/// # Arguments
+/// * `configuration_directory`: Directory containing the YAML configuration files.
+/// * `env_vars`: Environment variables to read settings from.
+pub fn load_configuration(
+    configuration_directory: &Path,
+    mut env_vars: Map<String, String>,
+) -> Result<Settings, config::ConfigError> {
+    let json_overrides = take_json_overrides(&mut env_vars)?;
+    let environment = detect_environment(&env_vars)?;
     let environment_filename = format!("{}.yaml", environment.as_str());
-    let settings = Config::builder()
-        .add_source(config::File::from(
-            configuration_directory.join("base.yaml"),
-        ))
-        .add_source(config::File::from(
-            configuration_directory.join(environment_filename),
-        ))
+    let port = env_vars.get("PORT").cloned();
+    let from_env_only = env_vars.get("CONFIG_FROM_ENV").is_some_and(|value| value == "1");
+    let required_keys: Vec<String> = env_vars
+        .get("CONFIG_REQUIRED_KEYS")
+        .map(|keys| {
+            keys.split(',')
+                .map(|key| key.trim().to_lowercase())
+                .filter(|key| !key.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut builder = Config::builder();
+    if !from_env_only {
+        builder = builder
+            .add_source(config::File::from(
+                configuration_directory.join("base.yaml"),
+            ))
+            .add_source(config::File::from(
+                configuration_directory.join(environment_filename),
+            ));
+    }
+    if let Some(json) = json_overrides {
+        builder = builder.add_source(json);
+    }
+    let builder = builder
         // Add in settings from environment variables (with a prefix of APP and '__' as separator)
         // E.g. `APP_APPLICATION__PORT=8080 would set `Settings.application.port` to 8080.
         .add_source(
             config::Environment::with_prefix("APP")
                 .prefix_separator("_")
-                .separator("__"),
+                .separator("__")
+                .source(Some(env_vars)),
         )
-        // Setting default setting values.
+        // PaaS convention: a bare `PORT` takes precedence over every other source.
+        .set_override_option("application.port", port)?;
+    if !required_keys.is_empty() {
+        check_required_keys(builder.clone().build()?, &required_keys)?;
+    }
+    let settings = set_defaults(builder, &environment)?.build()?;
+
+    let settings = settings.try_deserialize::<Settings>()?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// Environment used when `APP_ENVIRONMENT` is unset. Builds with the `default-prod` feature default to `Prod`,
+/// so a deployment that forgets to set it doesn't run with local settings, e.g. debug routes.
+pub const DEFAULT_ENVIRONMENT: Environment = if cfg!(feature = "default-prod") {
+    Environment::Prod
+} else {
+    Environment::Local
+};
+
+/// Detects the running environment from `APP_ENVIRONMENT`, defaulting to `DEFAULT_ENVIRONMENT` if unspecified.
+fn detect_environment(env_vars: &Map<String, String>) -> Result<Environment, config::ConfigError> {
+    match env_vars.get("APP_ENVIRONMENT") {
+        Some(environment) => environment
+            .clone()
+            .try_into()
+            .map_err(|err| config::ConfigError::Message(format!("Invalid APP_ENVIRONMENT: {}", err))),
+        None => Ok(DEFAULT_ENVIRONMENT),
+    }
+}
+
+/// Sets the default value of each setting, which any other source overrides.
+fn set_defaults(
+    builder: ConfigBuilder<DefaultState>,
+    environment: &Environment,
+) -> Result<ConfigBuilder<DefaultState>, config::ConfigError> {
+    let log_level = if *environment == Environment::Local { "trace" } else { "info" };
+    builder
+        .set_default("environment", environment.as_str())?
         .set_default("application.host", "127.0.0.1")?
         .set_default("application.port", 8080)?
         .set_default("application.max_concurrent_requests", 10240)?
+        .set_default("application.max_concurrent_reads", 10240)?
+        .set_default("application.max_concurrent_writes", 10240)?
+        .set_default("application.max_concurrent_per_client", 10240)?
+        .set_default("application.client_api_keys", Vec::<String>::new())?
+        .set_default("application.shed_after_ms", 0)?
+        .set_default("application.retry_after_s", 1)?
         .set_default("application.request_timeout_s", 20)?
+        .set_default("application.force_shutdown_after_s", 30)?
+        .set_default("application.idle_timeout_s", 0)?
+        .set_default("application.timeout_exempt_routes", Vec::<String>::new())?
+        .set_default("application.route_timeouts_s", Map::<String, u64>::new())?
+        .set_default("application.max_key_length", 256)?
+        .set_default("application.max_value_length", 1024 * 1024)?
+        .set_default("application.max_json_depth", 32)?
+        .set_default("application.max_header_bytes", 16 * 1024)?
+        .set_default("application.max_query_params", 32)?
+        .set_default("application.max_query_bytes", 16 * 1024)?
+        .set_default("application.dedup_window_ms", 0)?
+        .set_default("application.default_ttl_s", 0)?
+        .set_default("application.expiry_sweep_interval_s", 60)?
+        .set_default("application.warmup_delay_s", 0)?
+        .set_default("application.read_cache_max_age_s", 0)?
+        .set_default("application.value_type", "string")?
+        .set_default("application.auto_parse_json_values", false)?
+        .set_default("application.trace_exclude", Vec::<String>::new())?
+        .set_default("application.log_level", log_level)?
+        .set_default("application.log_sample_rate", Map::<String, u64>::new())?
+        .set_default("application.log_slow_ms", 1000)?
+        .set_default("application.metrics_exclude", vec!["/metrics", "/health", "/ready", "/healthz"])?
+        .set_default("application.trace_levels", Map::<String, String>::new())?
+        .set_default("application.duplicate_trace_id", "first")?
+        .set_default("application.hash_keys_in_logs", false)?
+        .set_default("application.response_time_header", false)?
+        .set_default("application.canary_percent", 0.0)?
+        .set_default("application.envelope_responses", false)?
+        .set_default("application.envelope_exclude", vec!["/metrics"])?
+        .set_default("application.pretty_json", *environment == Environment::Local)?
+        .set_default("application.query_upserts", *environment == Environment::Local)?
+        .set_default("application.allowed_methods", Vec::<String>::new())?
+        .set_default("application.static_routes", Map::<String, String>::new())?
+        .set_default("application.max_page_size", 1000)?
+        .set_default("application.max_response_entries", 10_000)?
+        .set_default("application.stream_threshold_bytes", 64 * 1024)?
+        .set_default("application.trusted_proxies", Vec::<String>::new())?
+        .set_default("application.debug_capture_size", 0)?
+        .set_default("application.case_insensitive_keys", false)?
+        .set_default("application.key_encoding", "base64_url")?
+        .set_default("application.reserved_key_prefix", "__sys:")?
+        .set_default("application.memory_limit_policy", "reject")?
+        .set_default("application.value_normalization", Vec::<String>::new())?
+        .set_default("application.value_field", "value")?
+        .set_default("application.value_codec", "identity")?
+        .set_default("application.hdr_latency", false)?
+        .set_default(
+            "application.latency_buckets_ms",
+            vec![1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0],
+        )
+}
+
+/// Source that set the effective value of a setting.
+#[derive(PartialEq, Clone, Debug)]
+pub enum ConfigSource {
+    /// The default value set in `set_defaults`.
+    Default,
+    /// A YAML file, e.g. `base.yaml`.
+    File(String),
+    /// The JSON object in `APP_CONFIG_JSON`.
+    JsonOverrides,
+    /// An `APP_`-prefixed environment variable, or `PORT`.
+    EnvironmentVariable,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(name) => write!(f, "{}", name),
+            ConfigSource::JsonOverrides => write!(f, "{}", CONFIG_JSON_VAR),
+            ConfigSource::EnvironmentVariable => write!(f, "environment variable"),
+        }
+    }
+}
+
+/// Reports which source set each setting, reading the same sources as `get_configuration`.
+pub fn get_configuration_sources() -> Result<Vec<(String, ConfigSource)>, config::ConfigError> {
+    let base_path = env::current_dir().expect("Failed to determine the current directory");
+    load_configuration_sources(&base_path.join("configuration"), env::vars().collect())
+}
+
+/// Reports which source set each setting, by building each source of `load_configuration` on its own and
+/// attributing every key to the highest-precedence source that sets it.
+/// # Arguments
+/// * `configuration_directory`: Directory containing the YAML configuration files.
+/// * `env_vars`: Environment variables to read settings from.
+/// # Returns
+/// * `Vec<(String, ConfigSource)>`: The dotted path of each setting, e.g. `application.port`, and its source,
+///   sorted by path.
+pub fn load_configuration_sources(
+    configuration_directory: &Path,
+    mut env_vars: Map<String, String>,
+) -> Result<Vec<(String, ConfigSource)>, config::ConfigError> {
+    let json_overrides = take_json_overrides(&mut env_vars)?;
+    let environment = detect_environment(&env_vars)?;
+    let port = env_vars.get("PORT").cloned();
+    let from_env_only = env_vars.get("CONFIG_FROM_ENV").is_some_and(|value| value == "1");
+
+    // Sources in increasing order of precedence.
+    let mut sources = Vec::new();
+    if !from_env_only {
+        for filename in ["base.yaml".to_string(), format!("{}.yaml", environment.as_str())] {
+            let file = Config::builder()
+                .add_source(config::File::from(configuration_directory.join(&filename)))
+                .build()?;
+            sources.push((ConfigSource::File(filename), file));
+        }
+    }
+    if let Some(json) = json_overrides {
+        sources.push((ConfigSource::JsonOverrides, Config::builder().add_source(json).build()?));
+    }
+    let env_source = Config::builder()
+        .add_source(
+            config::Environment::with_prefix("APP")
+                .prefix_separator("_")
+                .separator("__")
+                .source(Some(env_vars)),
+        )
+        .set_override_option("application.port", port)?
         .build()?;
+    sources.push((ConfigSource::EnvironmentVariable, env_source));
+
+    let mut keys = BTreeSet::new();
+    let defaults = set_defaults(Config::builder(), &environment)?.build()?;
+    collect_keys(defaults.try_deserialize()?, "", &mut keys);
+    for (_, source) in &sources {
+        collect_keys(source.clone().try_deserialize()?, "", &mut keys);
+    }
+    Ok(keys
+        .into_iter()
+        .map(|key| {
+            let source = sources
+                .iter()
+                .rev()
+                .find(|(_, source)| source.get::<Value>(&key).is_ok())
+                .map_or(ConfigSource::Default, |(name, _)| name.clone());
+            (key, source)
+        })
+        .collect())
+}
+
+/// Removes `APP_CONFIG_JSON` from the environment variables, so it isn't read as a `config_json` setting,
+/// and parses it as a configuration source.
+/// # Returns
+/// * `Option<File<..>>`: The source, or `None` if the variable isn't set. Fails if it isn't a JSON object.
+fn take_json_overrides(
+    env_vars: &mut Map<String, String>,
+) -> Result<Option<config::File<FileSourceString, FileFormat>>, config::ConfigError> {
+    let Some(json) = env_vars.remove(CONFIG_JSON_VAR) else {
+        return Ok(None);
+    };
+    // Note: Parsed upfront, so the error names the variable rather than an anonymous string source.
+    serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&json).map_err(|err| {
+        config::ConfigError::Message(format!("{} is not a valid JSON object: {}", CONFIG_JSON_VAR, err))
+    })?;
+    Ok(Some(config::File::from_str(&json, FileFormat::Json)))
+}
+
+/// Adds the dotted path of each leaf value in a configuration table to `keys`.
+fn collect_keys(table: Map<String, Value>, prefix: &str, keys: &mut BTreeSet<String>) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+        match value.kind {
+            ValueKind::Table(table) => collect_keys(table, &path, keys),
+            _ => {
+                keys.insert(path);
+            }
+        }
+    }
+}
+
+/// Fails if any of the required keys isn't set explicitly, i.e. would fall back to its default value.
+/// # Arguments
+/// * `explicit`: Configuration built from all sources except the defaults.
+/// * `required_keys`: Dotted paths of the required keys, e.g. `application.port`.
+fn check_required_keys(explicit: Config, required_keys: &[String]) -> Result<(), config::ConfigError> {
+    let missing: Vec<&str> = required_keys
+        .iter()
+        .filter(|key| explicit.get::<Value>(key).is_err())
+        .map(String::as_str)
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(config::ConfigError::Message(format!(
+            "Missing required configuration keys: {}.",
+            missing.join(", ")
+        )))
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the environment variables, in `Local` unless `APP_ENVIRONMENT` is given, whatever the build's
+    /// default environment.
+    fn env(env_vars: &[(&str, &str)]) -> Map<String, String> {
+        let mut vars: Map<String, String> = env_vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        vars.entry("APP_ENVIRONMENT".to_string()).or_insert_with(|| "local".to_string());
+        vars
+    }
+
+    fn load(env_vars: &[(&str, &str)]) -> Settings {
+        load_configuration(Path::new("configuration"), env(env_vars)).unwrap()
+    }
+
+    #[test]
+    #[cfg(not(feature = "default-prod"))]
+    fn test_default_environment_is_local() {
+        let settings = load_configuration(Path::new("configuration"), Map::new()).unwrap();
+        assert_eq!(settings.environment, "local");
+    }
+
+    #[test]
+    #[cfg(feature = "default-prod")]
+    fn test_default_environment_is_prod() {
+        let settings = load_configuration(Path::new("configuration"), Map::new()).unwrap();
+        assert_eq!(settings.environment, "prod");
+    }
+
+    #[test]
+    fn test_unknown_environment_is_rejected() {
+        let error = load_configuration(Path::new("configuration"), env(&[("APP_ENVIRONMENT", "staging")]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid APP_ENVIRONMENT: Unknown environment: staging. Use either `local` or `prod`."
+        );
+    }
+
+    #[test]
+    fn test_port_env_var() {
+        assert_eq!(load(&[]).application.port, 8080);
+        assert_eq!(load(&[("APP_APPLICATION__PORT", "9000")]).application.port, 9000);
+        assert_eq!(
+            load(&[("APP_APPLICATION__PORT", "9000"), ("PORT", "5000")]).application.port,
+            5000
+        );
+    }
+
+    #[tokio::test]
+    async fn test_port_env_var_is_bound() {
+        // Note: Binds an ephemeral port and releases it right away to find a free port.
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let settings = load(&[("PORT", &port.to_string())]);
 
-    settings.try_deserialize::<Settings>()
+        let (listener, _) = crate::app::bind(&settings.application).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_latency_buckets_are_validated() {
+        let mut settings = load(&[]);
+        assert!(settings.validate().is_ok());
+
+        settings.application.latency_buckets_ms = vec![];
+        assert!(settings.validate().is_err());
+
+        settings.application.latency_buckets_ms = vec![10.0, 5.0];
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_static_routes_are_validated() {
+        let mut settings = load(&[]);
+        let flags = serde_json::json!({ "dark_mode": true });
+        settings.application.static_routes = HashMap::from([("/flags/web".to_string(), flags.clone())]);
+        assert!(settings.validate().is_ok());
+
+        let mut error = |path: &str| {
+            settings.application.static_routes = HashMap::from([(path.to_string(), flags.clone())]);
+            settings.validate().unwrap_err().to_string()
+        };
+        assert!(error("flags").ends_with("must start with '/'."));
+        assert!(error("/{x").contains("must not contain '{' or '*'"));
+        assert!(error("/files/*rest").contains("must not contain '{' or '*'"));
+        for path in ["/", "/health", "/metrics"] {
+            assert!(error(path).ends_with("collides with a built-in route."), "{}", path);
+        }
+        for path in ["/api", "/api/foo", "/admin/reload"] {
+            assert!(error(path).contains("must not be under the built-in"), "{}", path);
+        }
+        // Only whole path segments are prefixes.
+        settings.application.static_routes = HashMap::from([("/apiary".to_string(), flags)]);
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn test_compression_level_is_validated() {
+        let error = load_configuration(
+            Path::new("configuration"),
+            env(&[("APP_APPLICATION__COMPRESSION_LEVEL", "10")]),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "application.compression_level must be between 0 and 9, got 10.");
+
+        let settings = load(&[("APP_APPLICATION__COMPRESSION_LEVEL", "9")]);
+        assert_eq!(settings.application.compression_level, Some(9));
+    }
+
+    #[test]
+    fn test_aes_codec_requires_a_key() {
+        let error = load_configuration(
+            Path::new("configuration"),
+            env(&[("APP_APPLICATION__VALUE_CODEC", "aes")]),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("application.value_codec_key"));
+
+        let settings = load(&[
+            ("APP_APPLICATION__VALUE_CODEC", "aes"),
+            ("APP_APPLICATION__VALUE_CODEC_KEY", "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="),
+        ]);
+        assert_eq!(settings.application.value_codec, ValueCodecKind::Aes);
+    }
+
+    #[test]
+    fn test_required_keys() {
+        let required_keys = (
+            "CONFIG_REQUIRED_KEYS",
+            "application.port, application.admin_token,application.dedup_window_ms",
+        );
+        let error = load_configuration(
+            Path::new("configuration"),
+            env(&[required_keys, ("APP_APPLICATION__ADMIN_TOKEN", "secret")]),
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Missing required configuration keys: application.dedup_window_ms.");
+
+        let settings = load(&[
+            required_keys,
+            ("APP_APPLICATION__ADMIN_TOKEN", "secret"),
+            ("APP_APPLICATION__DEDUP_WINDOW_MS", "0"),
+        ]);
+        assert_eq!(settings.application.admin_token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_from_env_only() {
+        let mut env_vars = env(&[
+            ("CONFIG_FROM_ENV", "1"),
+            ("APP_ENVIRONMENT", "prod"),
+            ("APP_APPLICATION__PORT", "9000"),
+        ]);
+
+        let settings = load_configuration(Path::new("missing"), env_vars.clone()).unwrap();
+        assert_eq!(settings.environment, "prod");
+        assert_eq!(settings.application.port, 9000);
+        assert_eq!(settings.application.host, "127.0.0.1");
+        assert!(!settings.application.pretty_json);
+        assert!(!settings.application.query_upserts);
+
+        env_vars.remove("CONFIG_FROM_ENV");
+        assert!(load_configuration(Path::new("missing"), env_vars).is_err());
+    }
+
+    #[test]
+    fn test_json_overrides() {
+        let json = (
+            CONFIG_JSON_VAR,
+            r#"{"application": {"port": 9000, "max_page_size": 10, "pretty_json": false}}"#,
+        );
+        let settings = load(&[json]);
+        assert_eq!(settings.application.port, 9000);
+        assert_eq!(settings.application.max_page_size, 10);
+        assert!(!settings.application.pretty_json);
+
+        // Individual environment variables take precedence over the JSON object.
+        assert_eq!(load(&[json, ("APP_APPLICATION__PORT", "9001")]).application.port, 9001);
+
+        let error =
+            load_configuration(Path::new("configuration"), env(&[(CONFIG_JSON_VAR, "[1]")])).unwrap_err();
+        assert!(error.to_string().starts_with("APP_CONFIG_JSON is not a valid JSON object:"), "{}", error);
+    }
+
+    #[test]
+    fn test_configuration_sources() {
+        let sources = load_configuration_sources(
+            Path::new("configuration"),
+            env(&[("APP_APPLICATION__PORT", "9000")]),
+        )
+        .unwrap();
+        let source = |key: &str| {
+            sources
+                .iter()
+                .find(|(path, _)| path == key)
+                .map(|(_, source)| source.clone())
+                .unwrap()
+        };
+
+        assert_eq!(source("application.port"), ConfigSource::EnvironmentVariable);
+        assert_eq!(source("application.host"), ConfigSource::File("local.yaml".to_string()));
+        assert_eq!(source("application.request_timeout_s"), ConfigSource::File("base.yaml".to_string()));
+        assert_eq!(source("application.dedup_window_ms"), ConfigSource::Default);
+
+        let sources = load_configuration_sources(
+            Path::new("configuration"),
+            env(&[(CONFIG_JSON_VAR, r#"{"application": {"max_page_size": 10}}"#)]),
+        )
+        .unwrap();
+        assert!(sources.contains(&("application.max_page_size".to_string(), ConfigSource::JsonOverrides)));
+    }
 }
@@ -0,0 +1,36 @@
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Request-scoped context, populated by the request context middleware and stored in the request
+/// extensions, so handlers don't need to re-parse headers.
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    /// Trace ID from the `X-Trace-ID` header, or a generated one.
+    pub trace_id: String,
+    /// Matched route template, e.g. `/api/{key}`.
+    pub route: Option<String>,
+    /// When the request entered the middleware stack.
+    pub started_at: Instant,
+    /// IP address of the client, if the server was started with connection info.
+    /// Behind a trusted proxy, this is the address from `X-Forwarded-For` rather than the proxy's.
+    pub client_ip: Option<IpAddr>,
+    /// Scheme used by the client, e.g. `https`. Behind a trusted proxy, this is taken from `X-Forwarded-Proto`.
+    pub scheme: String,
+    /// Whether the request is tagged for canary behavior, see `ApplicationSettings::canary_percent`.
+    pub canary: bool,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestContext {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<RequestContext>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Request context is missing."))
+    }
+}
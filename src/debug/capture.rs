@@ -0,0 +1,80 @@
+use axum::http::header::{AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use axum::http::{HeaderMap, HeaderName};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Headers whose values are never captured.
+const REDACTED_HEADERS: [HeaderName; 4] = [
+    AUTHORIZATION,
+    COOKIE,
+    PROXY_AUTHORIZATION,
+    HeaderName::from_static("x-api-key"),
+];
+
+/// A request recorded by `RequestCapture`.
+#[derive(Serialize, Clone, Debug)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub path: String,
+    /// Header names and values, with sensitive values redacted.
+    pub headers: Vec<(String, String)>,
+    /// The body as text, or `None` if it was too large, not valid UTF-8, or of unknown length.
+    pub body: Option<String>,
+}
+
+/// Ring buffer of the most recent requests, to help reproduce client issues in local.
+#[derive(Debug)]
+pub struct RequestCapture {
+    /// Maximum number of requests kept. Capturing is disabled when 0.
+    capacity: usize,
+    requests: Mutex<VecDeque<CapturedRequest>>,
+}
+
+impl RequestCapture {
+    /// Creates an empty buffer keeping up to `capacity` requests.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            requests: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Whether requests are captured at all.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Records a request, dropping the oldest one if the buffer is full.
+    pub fn record(&self, request: CapturedRequest) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut requests = self.requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if requests.len() == self.capacity {
+            requests.pop_front();
+        }
+        requests.push_back(request);
+    }
+
+    /// Returns the captured requests, oldest first.
+    pub fn requests(&self) -> Vec<CapturedRequest> {
+        let requests = self.requests.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        requests.iter().cloned().collect()
+    }
+}
+
+/// Converts headers to name-value pairs, replacing the values of sensitive headers.
+pub fn redact_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(name) {
+                "<redacted>".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
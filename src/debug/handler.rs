@@ -0,0 +1,121 @@
+use crate::debug::capture::CapturedRequest;
+use crate::dependency::ApplicationState;
+use crate::repo::lock::LockStats;
+use crate::response::JsonResponse;
+use axum::extract::State;
+use axum::routing::get;
+use axum::Router;
+use std::sync::PoisonError;
+
+/// Routes for debugging in local. Never added in other environments.
+pub fn get_debug_routes() -> Router<ApplicationState> {
+    Router::new()
+        .route("/locks", get(read_lock_stats))
+        .route("/requests", get(read_captured_requests))
+}
+
+/// Handler function to list the most recent requests, oldest first.
+/// # Arguments
+/// * `state`: The application state.
+async fn read_captured_requests(
+    State(state): State<ApplicationState>,
+) -> JsonResponse<Vec<CapturedRequest>> {
+    JsonResponse::new(state.request_capture.requests(), &state.config.application)
+}
+
+/// Handler function to report the contention of the database lock that handlers serialize on, e.g. to decide
+/// whether to shard it.
+///
+/// The lock is also reported as poisoned if the backend recovered from poisoning of its own lock.
+/// # Arguments
+/// * `state`: The application state.
+async fn read_lock_stats(State(state): State<ApplicationState>) -> JsonResponse<LockStats> {
+    let mut stats = state.db.stats();
+    let db = state.db.read().unwrap_or_else(PoisonError::into_inner);
+    stats.poisoned |= db.poison_recoveries().is_some_and(|recoveries| recoveries > 0);
+    JsonResponse::new(stats, &state.config.application)
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::build_app;
+    use crate::test_util::{body_string, send, test_settings};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_captured_requests() {
+        let mut settings = test_settings();
+        settings.application.debug_capture_size = 2;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        for key in ["a", "b", "c"] {
+            let request = Request::post(format!("/api/{}", key))
+                .header("Content-Type", "application/json")
+                .header("Authorization", "Bearer secret")
+                .header("Content-Length", "17")
+                .body(Body::from(r#"{"value": "test"}"#))
+                .unwrap();
+            assert_eq!(send(&app, request).await.status(), StatusCode::OK);
+        }
+
+        let response = send(&app, Request::get("/debug/requests").body(Body::empty()).unwrap()).await;
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        // The oldest request was dropped.
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {
+                    "method": "POST",
+                    "path": "/api/b",
+                    "headers": [
+                        ["content-type", "application/json"],
+                        ["authorization", "<redacted>"],
+                        ["content-length", "17"]
+                    ],
+                    "body": r#"{"value": "test"}"#
+                },
+                {
+                    "method": "POST",
+                    "path": "/api/c",
+                    "headers": [
+                        ["content-type", "application/json"],
+                        ["authorization", "<redacted>"],
+                        ["content-length", "17"]
+                    ],
+                    "body": r#"{"value": "test"}"#
+                }
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lock_stats() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let request = Request::post("/api/key")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"value": "test"}"#))
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::OK);
+
+        let response = send(&app, Request::get("/debug/locks").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert!(body["acquisitions"].as_u64().unwrap() > 0);
+        assert_eq!(body["poisoned"], false);
+    }
+
+    #[tokio::test]
+    async fn test_debug_routes_are_local_only() {
+        let mut settings = test_settings();
+        settings.environment = "prod".to_string();
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        let response = send(&app, Request::get("/debug/requests").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
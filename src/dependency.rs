@@ -1,7 +1,30 @@
-use std::sync::{Arc, RwLock};
-use tracing::debug;
-use crate::configuration::Settings;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+use crate::api::dedup::DedupWindow;
+use crate::configuration::{ApplicationSettings, Settings, ValueCodecKind};
+use crate::debug::capture::RequestCapture;
+use crate::limiter::{ClientConcurrencyLimiter, ConcurrencyLimiter};
+use crate::metrics::Metrics;
+use crate::repo::codec::{codec_from_settings, EncodedDatabase};
 use crate::repo::db::{InMemoryDatabase, KVDatabase};
+use crate::repo::lock::InstrumentedRwLock;
+
+/// Handle to change the maximum level of the tracing subscriber at runtime.
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// Settings that changed in `ApplicationState::reload`.
+#[derive(Debug, PartialEq)]
+pub struct ReloadOutcome {
+    /// Names of the settings that were applied.
+    pub changed: Vec<&'static str>,
+    /// Names of the settings that only take effect on restart, in alphabetical order.
+    pub restart_required: Vec<String>,
+}
 
 /// Application state that holds all the app dependency singletons.
 #[derive(Clone)]
@@ -12,17 +35,306 @@ pub struct ApplicationState {
     //   - Bitwise copyable, i.e. it only clones pointers to the connection pool.
     //   - Allows you to get a pointer to the shared underlying resource with e.g. `get_ref()` or `get_mut()`.
     // Library documentation typically states this clearly.
-    pub db: Arc<RwLock<dyn KVDatabase<String, String>>>,
+    // Note: The lock keeps contention statistics, exposed by `GET /debug/locks` in local.
+    pub db: Arc<InstrumentedRwLock<dyn KVDatabase<String, String>>>,
     /// Global configurations.
     pub config: Arc<Settings>,
+    /// Limiter for in-flight requests, shared with the admin API so the limit can be changed at runtime.
+    pub limiter: Arc<ConcurrencyLimiter>,
+    /// Limiter for in-flight read-only API requests.
+    pub read_limiter: Arc<ConcurrencyLimiter>,
+    /// Limiter for in-flight mutating API requests.
+    pub write_limiter: Arc<ConcurrencyLimiter>,
+    /// Limiter for in-flight requests of each client.
+    pub client_limiter: Arc<ClientConcurrencyLimiter>,
+    /// Recent writes used to suppress duplicate upserts.
+    pub dedup: Arc<DedupWindow>,
+    /// Application metrics exposed by `GET /metrics`.
+    pub metrics: Arc<Metrics>,
+    /// Recent requests exposed by `GET /debug/requests` in local.
+    pub request_capture: Arc<RequestCapture>,
+    /// When the state was created, i.e. roughly when the application started.
+    pub started_at: Instant,
+    /// Whether the backend has finished initializing. Until then, application routes respond with `503`.
+    ready: Arc<AtomicBool>,
+    /// The settings last read by `reload`, initially `config`.
+    loaded: Arc<Mutex<ApplicationSettings>>,
+    /// Handle to reload the log level, if set with `with_log_level`.
+    log_level: Option<LogLevelHandle>,
 }
 
 impl ApplicationState {
     pub fn new(config: Arc<Settings>) -> Self {
+        let hash_keys_in_logs = config.application.hash_keys_in_logs;
+        let metrics = Arc::new(Metrics::new(
+            &config.application.latency_buckets_ms,
+            config.application.hdr_latency,
+        ));
+        let backend = InMemoryDatabase::new()
+            .hash_keys_in_logs(hash_keys_in_logs)
+            .observe_value_sizes(metrics.value_size_bytes.clone());
+        let db: Arc<InstrumentedRwLock<dyn KVDatabase<String, String>>> = match config.application.value_codec {
+            ValueCodecKind::Identity => Arc::new(InstrumentedRwLock::new(backend)),
+            _ => {
+                // Note: The codec was checked by `Settings::validate` when the configuration was loaded.
+                let codec = codec_from_settings(&config.application).expect("Value codec is valid");
+                let db = EncodedDatabase::new(Box::new(backend), codec).hash_keys_in_logs(hash_keys_in_logs);
+                Arc::new(InstrumentedRwLock::new(db))
+            }
+        };
+        Self::with_metrics(config, db, metrics)
+    }
+
+    /// Creates the state around the given backend, e.g. a mock that fails or counts calls in tests.
+    pub fn with_db(config: Arc<Settings>, db: Arc<InstrumentedRwLock<dyn KVDatabase<String, String>>>) -> Self {
+        let metrics = Arc::new(Metrics::new(
+            &config.application.latency_buckets_ms,
+            config.application.hdr_latency,
+        ));
+        Self::with_metrics(config, db, metrics)
+    }
+
+    fn with_metrics(
+        config: Arc<Settings>,
+        db: Arc<InstrumentedRwLock<dyn KVDatabase<String, String>>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         debug!("Creating new AppState...");
         Self {
-            db: Arc::new(RwLock::new(InMemoryDatabase::new())),
+            db,
+            limiter: Arc::new(ConcurrencyLimiter::new(config.application.max_concurrent_requests)),
+            read_limiter: Arc::new(ConcurrencyLimiter::new(config.application.max_concurrent_reads)),
+            write_limiter: Arc::new(ConcurrencyLimiter::new(config.application.max_concurrent_writes)),
+            client_limiter: Arc::new(ClientConcurrencyLimiter::new(
+                config.application.max_concurrent_per_client,
+            )),
+            dedup: Arc::new(DedupWindow::new(Duration::from_millis(config.application.dedup_window_ms))),
+            metrics,
+            request_capture: Arc::new(RequestCapture::new(config.application.debug_capture_size)),
+            // Note: The backend is assumed to be ready as soon as it's created, see `set_ready`.
+            ready: Arc::new(AtomicBool::new(true)),
+            started_at: Instant::now(),
+            loaded: Arc::new(Mutex::new(config.application.clone())),
+            log_level: None,
             config,
         }
     }
+
+    /// Applies the hot-reloadable subset of the settings without a restart, i.e. the concurrency limits, and
+    /// the log level if the state has its handle, see `with_log_level`.
+    ///
+    /// A reloadable setting is only applied if it changed since the last load, so a limit changed at runtime
+    /// through the admin API isn't reset by a reload that doesn't touch it. Other settings keep the values the
+    /// application started with, and each one that changed is logged since it only takes effect on restart.
+    /// # Arguments
+    /// * `settings`: The re-read settings.
+    /// # Returns
+    /// * `ReloadOutcome`: The names of the settings that changed, by whether they were applied.
+    pub fn reload(&self, settings: &Settings) -> ReloadOutcome {
+        let application = &settings.application;
+        let mut loaded = self.loaded.lock().unwrap_or_else(PoisonError::into_inner);
+        let limits = [
+            (
+                "max_concurrent_requests",
+                &self.limiter,
+                loaded.max_concurrent_requests,
+                application.max_concurrent_requests,
+            ),
+            (
+                "max_concurrent_reads",
+                &self.read_limiter,
+                loaded.max_concurrent_reads,
+                application.max_concurrent_reads,
+            ),
+            (
+                "max_concurrent_writes",
+                &self.write_limiter,
+                loaded.max_concurrent_writes,
+                application.max_concurrent_writes,
+            ),
+        ];
+
+        let mut changed = Vec::new();
+        for (name, limiter, previous, limit) in limits {
+            if previous != limit {
+                limiter.set_limit(limit);
+                info!("Reloaded {} from {} to {}.", name, previous, limit);
+                changed.push(name);
+            }
+        }
+        if let Some(handle) = &self.log_level
+            && application.log_level != loaded.log_level
+        {
+            // Note: The level was checked by `Settings::validate` when the configuration was loaded.
+            let level: LevelFilter = application.log_level.parse().expect("Log level is valid");
+            match handle.reload(level) {
+                Ok(()) => {
+                    info!("Reloaded log_level from {} to {}.", loaded.log_level, level);
+                    changed.push("log_level");
+                }
+                Err(err) => warn!("Failed to reload log_level: {}", err),
+            }
+        }
+
+        let restart_required: Vec<String> = changed_settings(&self.config.application, application)
+            .into_iter()
+            .filter(|name| !changed.contains(&name.as_str()) && !self.is_reloadable(name))
+            .collect();
+        for name in &restart_required {
+            warn!("Setting {} changed, which only takes effect on restart.", name);
+        }
+        *loaded = application.clone();
+        ReloadOutcome {
+            changed,
+            restart_required,
+        }
+    }
+
+    /// Whether `reload` applies the setting without a restart.
+    fn is_reloadable(&self, name: &str) -> bool {
+        match name {
+            "max_concurrent_requests" | "max_concurrent_reads" | "max_concurrent_writes" => true,
+            "log_level" => self.log_level.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Lets `reload` change the log level, through the handle of the filter the tracing subscriber was built
+    /// with.
+    pub fn with_log_level(mut self, handle: LogLevelHandle) -> Self {
+        self.log_level = Some(handle);
+        self
+    }
+
+    /// Periodically removes the expired entries of the backend, every
+    /// `ApplicationSettings::expiry_sweep_interval_s`.
+    /// # Returns
+    /// * `Option<JoinHandle<()>>`: The sweeper task, or `None` if sweeping is disabled.
+    pub fn spawn_expiry_sweeper(&self) -> Option<JoinHandle<()>> {
+        let period = Duration::from_secs(self.config.application.expiry_sweep_interval_s);
+        if period.is_zero() {
+            return None;
+        }
+        let db = self.db.clone();
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                let purged = db.write().unwrap().purge_expired();
+                if purged > 0 {
+                    debug!("Purged {} expired entries.", purged);
+                }
+            }
+        }))
+    }
+
+    /// Whether the backend has finished initializing.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Sets the readiness flag, e.g. to `false` while a remote backend is still connecting so the server
+    /// can accept connections before the backend is usable.
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+    }
+}
+
+/// Names of the settings whose values differ, in alphabetical order.
+fn changed_settings(old: &ApplicationSettings, new: &ApplicationSettings) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+    new.into_iter()
+        .filter(|(name, value)| old.get(name) != Some(value))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::build_app;
+    use crate::test_util::{send, test_settings, TestDatabase, TraceCapture};
+    use tracing_subscriber::layer::SubscriberExt;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn test_injected_backend() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counter = reads.clone();
+        let backend = TestDatabase::default().on_read(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let db = Arc::new(InstrumentedRwLock::new(backend));
+        let app = build_app(ApplicationState::with_db(Arc::new(test_settings()), db));
+
+        for _ in 0..3 {
+            let response = send(&app, Request::get("/api/key").body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_reload() {
+        let settings = test_settings();
+        let capture = TraceCapture::default();
+        let (filter, handle) = reload::Layer::new(LevelFilter::INFO);
+        let _guard = tracing::subscriber::set_default(Registry::default().with(filter).with(capture.clone()));
+        let state = ApplicationState::new(Arc::new(settings.clone())).with_log_level(handle);
+        // A limit changed at runtime through the admin API.
+        state.write_limiter.set_limit(3);
+
+        let mut edited = settings.clone();
+        edited.application.log_level = "debug".to_string();
+        edited.application.max_concurrent_requests = 5;
+        edited.application.port = 9090;
+        edited.application.default_ttl_s = 60;
+        debug!("Before the reload.");
+        let outcome = state.reload(&edited);
+        debug!("After the reload.");
+
+        assert_eq!(outcome.changed, ["max_concurrent_requests", "log_level"]);
+        assert_eq!(outcome.restart_required, ["default_ttl_s", "port"]);
+        assert_eq!(state.limiter.limit(), 5);
+        assert_eq!(state.write_limiter.limit(), 3);
+        let messages: Vec<_> = capture
+            .events()
+            .into_iter()
+            .map(|event| event.fields["message"].clone())
+            .collect();
+        assert!(!messages.contains(&"Before the reload.".to_string()));
+        assert!(messages.contains(&"After the reload.".to_string()));
+
+        // Reloading the same settings again only repeats the settings that still need a restart.
+        let outcome = state.reload(&edited);
+        assert!(outcome.changed.is_empty());
+        assert_eq!(outcome.restart_required, ["default_ttl_s", "port"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expiry_sweeper_purges_expired_entries() {
+        let mut settings = test_settings();
+        settings.application.expiry_sweep_interval_s = 10;
+        let state = ApplicationState::new(Arc::new(settings));
+        {
+            let mut db = state.db.write().unwrap();
+            db.upsert(&"key".to_string(), "value".to_string());
+            db.touch(&"key".to_string(), Duration::from_secs(1));
+        }
+        let sweeper = state.spawn_expiry_sweeper().unwrap();
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(state.db.read().unwrap().memory_bytes(), Some(8));
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(state.db.read().unwrap().memory_bytes(), Some(0));
+        sweeper.abort();
+    }
 }
@@ -0,0 +1,210 @@
+use crate::dependency::ApplicationState;
+use crate::response::JsonResponse;
+use axum::extract::State;
+use axum::http::StatusCode;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Health of the application or one of its components, in increasing order of severity.
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    /// Still serving requests, but needs attention.
+    Degraded,
+    /// Unable to serve requests.
+    Unhealthy,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ComponentHealth {
+    status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl ComponentHealth {
+    fn new(status: HealthStatus, detail: Option<String>) -> Self {
+        ComponentHealth { status, detail }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct Components {
+    /// Whether the backend is able to serve requests.
+    backend: ComponentHealth,
+    /// Whether a handler panicked while holding the database lock.
+    locks: ComponentHealth,
+    /// Number of entries compared to `ApplicationSettings::max_entries`.
+    memory: ComponentHealth,
+    /// Time since the application state was created.
+    uptime: ComponentHealth,
+}
+
+#[derive(Serialize)]
+pub(crate) struct HealthReport {
+    /// The most severe status of all components.
+    status: HealthStatus,
+    components: Components,
+}
+
+/// Handler function to report the health of each component and the overall status.
+///
+/// Responds with `503` when the overall status is `unhealthy`, so load balancers can act on the status
+/// code alone.
+/// # Arguments
+/// * `state`: The application state.
+pub(crate) async fn read_health(
+    State(state): State<ApplicationState>,
+) -> (StatusCode, JsonResponse<HealthReport>) {
+    let poisoned = state.db.is_poisoned();
+    let db = state.db.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let backend = if db.is_healthy() {
+        ComponentHealth::new(HealthStatus::Ok, Some(db.name().to_string()))
+    } else {
+        ComponentHealth::new(HealthStatus::Unhealthy, Some(format!("{} is unavailable", db.name())))
+    };
+    // Note: Handlers fail on a poisoned lock, so the application can't serve requests until it's restarted.
+    let locks = if poisoned {
+        ComponentHealth::new(HealthStatus::Unhealthy, Some("database lock is poisoned".to_string()))
+    } else {
+        ComponentHealth::new(HealthStatus::Ok, None)
+    };
+    let entries = db.len();
+    let memory = match state.config.application.max_entries {
+        Some(max_entries) if entries >= max_entries => ComponentHealth::new(
+            HealthStatus::Degraded,
+            Some(format!("{} of {} entries", entries, max_entries)),
+        ),
+        Some(max_entries) => {
+            ComponentHealth::new(HealthStatus::Ok, Some(format!("{} of {} entries", entries, max_entries)))
+        }
+        None => ComponentHealth::new(HealthStatus::Ok, Some(format!("{} entries", entries))),
+    };
+    drop(db);
+    let uptime = ComponentHealth::new(
+        HealthStatus::Ok,
+        Some(format!("{}s", state.started_at.elapsed().as_secs())),
+    );
+
+    let status = [&backend, &locks, &memory, &uptime]
+        .iter()
+        .map(|component| component.status)
+        .max()
+        .unwrap_or(HealthStatus::Ok);
+    let code = if status == HealthStatus::Unhealthy {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    let components = Components {
+        backend,
+        locks,
+        memory,
+        uptime,
+    };
+    (code, JsonResponse::new(HealthReport { status, components }, &state.config.application))
+}
+
+/// Handler function for liveness probes, which succeeds as long as the server is running.
+pub(crate) async fn read_liveness() -> &'static str {
+    "OK"
+}
+
+/// Handler function for readiness probes.
+///
+/// Responds with `503` until the backend is ready and `ApplicationSettings::warmup_delay_s` has passed since
+/// startup, so orchestrators hold back traffic while dependencies stabilize.
+/// # Arguments
+/// * `state`: The application state.
+pub(crate) async fn read_readiness(State(state): State<ApplicationState>) -> (StatusCode, &'static str) {
+    let warmup = Duration::from_secs(state.config.application.warmup_delay_s);
+    if !state.is_ready() || state.started_at.elapsed() < warmup {
+        (StatusCode::SERVICE_UNAVAILABLE, "Not ready.")
+    } else {
+        (StatusCode::OK, "Ready.")
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use crate::app::build_app;
+    use crate::dependency::ApplicationState;
+    use crate::test_util::{body_string, send, test_settings};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::Router;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn health(app: &Router) -> (StatusCode, serde_json::Value) {
+        let response = send(app, Request::get("/healthz").body(Body::empty()).unwrap()).await;
+        let status = response.status();
+        (status, serde_json::from_str(&body_string(response).await).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_healthz_reports_components() {
+        let mut settings = test_settings();
+        settings.application.max_entries = Some(1);
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+
+        let (status, report) = health(&app).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["status"], "ok");
+        assert_eq!(report["components"]["backend"]["status"], "ok");
+        assert_eq!(report["components"]["locks"]["status"], "ok");
+        assert_eq!(report["components"]["memory"]["detail"], "0 of 1 entries");
+        assert!(report["components"]["uptime"]["detail"].as_str().unwrap().ends_with('s'));
+
+        state.db.write().unwrap().upsert(&"key".to_string(), "value".to_string());
+        let (status, report) = health(&app).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["status"], "degraded");
+        assert_eq!(report["components"]["memory"]["status"], "degraded");
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_lock_is_unhealthy() {
+        let state = ApplicationState::new(Arc::new(test_settings()));
+        let app = build_app(state.clone());
+        let db = state.db.clone();
+        // Simulate a handler that panicked while holding the write lock.
+        std::thread::spawn(move || {
+            let _guard = db.write().unwrap();
+            panic!("forced failure");
+        })
+        .join()
+        .unwrap_err();
+
+        let (status, report) = health(&app).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["status"], "unhealthy");
+        assert_eq!(report["components"]["locks"]["status"], "unhealthy");
+    }
+
+    #[tokio::test]
+    async fn test_ready_after_warmup() {
+        let mut settings = test_settings();
+        settings.application.warmup_delay_s = 60;
+        let settings = Arc::new(settings);
+        let state = ApplicationState::new(settings.clone());
+        let app = build_app(state);
+
+        let response = send(&app, Request::get("/ready").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = send(&app, Request::get("/health").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Simulate a server that started before the warmup delay.
+        let mut state = ApplicationState::new(settings);
+        state.started_at -= Duration::from_secs(61);
+        let app = build_app(state);
+        let response = send(&app, Request::get("/ready").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
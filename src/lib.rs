@@ -1,6 +1,17 @@
+pub mod admin;
 pub mod api;
+pub mod app;
 pub mod configuration;
+pub mod context;
+pub mod debug;
 pub mod repo;
 pub mod dependency;
+pub mod health;
+pub mod limiter;
+pub mod metrics;
 pub mod middleware;
+pub mod response;
 pub mod route;
+
+#[cfg(test)]
+pub(crate) mod test_util;
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// Concurrency limiter whose limit can be adjusted at runtime.
+///
+/// Unlike tower's `ConcurrencyLimit`, which fixes the number of permits when the layer is built,
+/// the limit here is read on every request, so changes take effect immediately.
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    /// Maximum number of in-flight requests.
+    limit: AtomicUsize,
+    /// Number of requests currently holding a permit.
+    in_flight: AtomicUsize,
+    /// Number of requests waiting for a slot.
+    queued: AtomicUsize,
+    /// Wakes up requests waiting for a slot when one is released.
+    released: Notify,
+}
+
+/// Reasons for failing to acquire a slot of a `ConcurrencyLimiter`.
+#[derive(Debug, Error, PartialEq)]
+pub enum AcquireError {
+    /// Too many requests were already waiting for a slot, so this one wasn't queued.
+    #[error("The queue of requests waiting for a slot is full.")]
+    QueueFull,
+    /// No slot was released while the request waited.
+    #[error("No slot was released in time.")]
+    TimedOut,
+}
+
+/// A slot held by an in-flight request. The slot is released when the permit is dropped.
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+}
+
+impl ConcurrencyLimiter {
+    /// Creates a new limiter allowing up to `limit` in-flight requests.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit),
+            in_flight: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    /// Returns the current limit.
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::SeqCst)
+    }
+
+    /// Changes the limit. Requests already in flight are not affected, and requests waiting for a slot are
+    /// woken up to take the slots added by a higher limit.
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::SeqCst);
+        self.released.notify_waiters();
+    }
+
+    /// Returns the number of requests currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of requests currently waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Tries to acquire a slot.
+    /// # Returns
+    /// * `Option<ConcurrencyPermit>`: The permit, or `None` if the limit has been reached.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConcurrencyPermit> {
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < self.limit()).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| ConcurrencyPermit {
+                limiter: self.clone(),
+            })
+    }
+
+    /// Acquires a slot, waiting up to `wait` for one to be released if the limit has been reached,
+    /// so brief bursts are absorbed rather than rejected.
+    /// # Arguments
+    /// * `wait`: How long to wait for a slot.
+    /// * `max_queued`: Maximum number of requests waiting for a slot at once, unbounded if `None`.
+    /// # Returns
+    /// * `Result<ConcurrencyPermit, AcquireError>`: The permit, or why none was acquired.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        wait: Duration,
+        max_queued: Option<usize>,
+    ) -> Result<ConcurrencyPermit, AcquireError> {
+        if let Some(permit) = self.try_acquire() {
+            return Ok(permit);
+        }
+        if wait.is_zero() {
+            return Err(AcquireError::TimedOut);
+        }
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                max_queued.is_none_or(|max_queued| count < max_queued).then_some(count + 1)
+            })
+            .map_err(|_| AcquireError::QueueFull)?;
+        let _queued = QueuedGuard { limiter: self };
+
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            // Note: Registered before checking for a slot, so neither a release nor a raised limit between
+            //       the check and the wait is missed.
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if let Some(permit) = self.try_acquire() {
+                // Pass the wake-up on while slots are left, e.g. after the limit was raised.
+                if self.in_flight() < self.limit() {
+                    self.released.notify_one();
+                }
+                return Ok(permit);
+            }
+            tokio::time::timeout_at(deadline, released)
+                .await
+                .map_err(|_| AcquireError::TimedOut)?;
+        }
+    }
+}
+
+/// Leaves the queue of a `ConcurrencyLimiter` when dropped, whether a slot was acquired or not.
+struct QueuedGuard<'a> {
+    limiter: &'a ConcurrencyLimiter,
+}
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.limiter.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.limiter.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.limiter.released.notify_one();
+    }
+}
+
+/// Concurrency limiter that caps the in-flight requests of each client separately, so one client
+/// can't take all the slots of the global limiter.
+#[derive(Debug)]
+pub struct ClientConcurrencyLimiter {
+    /// Maximum number of in-flight requests per client.
+    limit: usize,
+    /// Number of in-flight requests by client. Clients without in-flight requests are removed.
+    in_flight: Mutex<HashMap<String, usize>>,
+}
+
+/// A slot held by an in-flight request of a client. The slot is released when the permit is dropped.
+#[derive(Debug)]
+pub struct ClientConcurrencyPermit {
+    limiter: Arc<ClientConcurrencyLimiter>,
+    client: String,
+}
+
+impl ClientConcurrencyLimiter {
+    /// Creates a new limiter allowing up to `limit` in-flight requests per client.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the number of requests of the client currently in flight.
+    pub fn in_flight(&self, client: &str) -> usize {
+        self.lock().get(client).copied().unwrap_or(0)
+    }
+
+    /// Tries to acquire a slot for the client.
+    /// # Arguments
+    /// * `client`: Identifier of the client, e.g. its IP address.
+    /// # Returns
+    /// * `Option<ClientConcurrencyPermit>`: The permit, or `None` if the client reached the limit.
+    pub fn try_acquire(self: &Arc<Self>, client: &str) -> Option<ClientConcurrencyPermit> {
+        let mut in_flight = self.lock();
+        // Note: Rejected clients aren't inserted, so only clients with requests in flight have an entry.
+        let count = in_flight.get(client).copied().unwrap_or(0);
+        if count >= self.limit {
+            return None;
+        }
+        in_flight.insert(client.to_string(), count + 1);
+        Some(ClientConcurrencyPermit {
+            limiter: self.clone(),
+            client: client.to_string(),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, usize>> {
+        self.in_flight.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for ClientConcurrencyPermit {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.lock();
+        if let Some(count) = in_flight.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.client);
+            }
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrency_limiter() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(1));
+
+        let permit = limiter.try_acquire();
+        assert!(permit.is_some());
+        assert!(limiter.try_acquire().is_none());
+
+        limiter.set_limit(2);
+        let second = limiter.try_acquire();
+        assert!(second.is_some());
+        assert_eq!(limiter.in_flight(), 2);
+
+        drop(permit);
+        drop(second);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_client_concurrency_limiter() {
+        let limiter = Arc::new(ClientConcurrencyLimiter::new(1));
+
+        let permit = limiter.try_acquire("a");
+        assert!(permit.is_some());
+        assert!(limiter.try_acquire("a").is_none());
+        assert!(limiter.try_acquire("b").is_some());
+
+        drop(permit);
+        assert_eq!(limiter.in_flight("a"), 0);
+        assert!(limiter.lock().is_empty());
+
+        // Rejections don't leave an entry behind.
+        let limiter = Arc::new(ClientConcurrencyLimiter::new(0));
+        assert!(limiter.try_acquire("a").is_none());
+        assert!(limiter.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_a_release() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(1));
+        let permit = limiter.try_acquire().unwrap();
+        assert_eq!(limiter.acquire(Duration::from_millis(10), None).await.err(), Some(AcquireError::TimedOut));
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(permit);
+        });
+        assert!(limiter.acquire(Duration::from_secs(5), None).await.is_ok());
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_rejects_when_queue_is_full() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(1));
+        let _permit = limiter.try_acquire().unwrap();
+        let waiting = limiter.clone();
+        let queued = tokio::spawn(async move { waiting.acquire(Duration::from_secs(5), Some(1)).await });
+        while limiter.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let result = limiter.acquire(Duration::from_secs(5), Some(1)).await;
+        assert_eq!(result.err(), Some(AcquireError::QueueFull));
+        queued.abort();
+    }
+
+    #[tokio::test]
+    async fn test_raising_the_limit_admits_queued_requests() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(1));
+        let _permit = limiter.try_acquire().unwrap();
+        let queued: Vec<_> = (0..4)
+            .map(|_| {
+                let waiting = limiter.clone();
+                tokio::spawn(async move { waiting.acquire(Duration::from_secs(5), None).await })
+            })
+            .collect();
+        while limiter.queued() < 4 {
+            tokio::task::yield_now().await;
+        }
+
+        limiter.set_limit(5);
+        let started_at = tokio::time::Instant::now();
+        let mut permits = Vec::new();
+        for request in queued {
+            permits.push(request.await.unwrap().unwrap());
+        }
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+        assert_eq!(limiter.in_flight(), 5);
+    }
+}
@@ -1,39 +1,130 @@
+use std::env;
+use std::process;
 use std::sync::Arc;
-use axum::Router;
-use axum_demo::configuration::{get_configuration, Environment, Settings};
-use axum_demo::dependency::ApplicationState;
-use axum_demo::middleware::Middleware;
-use axum_demo::route::ApplicationRoute;
-use tokio::net::TcpListener;
-use tracing::{debug, Level};
-use tracing_subscriber::fmt;
+use axum_demo::app::{bind, build_app, serve};
+use axum_demo::configuration::{get_configuration, get_configuration_sources, Environment, Settings};
+use axum_demo::dependency::{ApplicationState, LogLevelHandle};
+use axum_demo::repo::snapshot;
+use std::path::Path;
+use tokio::signal;
+use tracing::{debug, error, info};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload};
 
 // Axum reference code: https://github.com/tokio-rs/axum/tree/main/examples
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // `--check-config` validates the configuration and exits without serving, e.g. in CI pipelines.
+    if env::args().skip(1).any(|arg| arg == "--check-config") {
+        match get_configuration() {
+            Ok(_) => {
+                println!("Configuration is valid.");
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!("Invalid configuration: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+
     let config = Arc::new(get_configuration().expect("Failed to read configuration."));
-    init_tracing(config.clone());
+    let log_level = init_tracing(config.clone());
+    match get_configuration_sources() {
+        Ok(sources) => {
+            for (key, source) in sources {
+                debug!("Configuration {} is set by {}.", key, source);
+            }
+        }
+        Err(err) => error!("Failed to determine configuration sources: {}", err),
+    }
 
     // Using the State extractor: https://docs.rs/axum/latest/axum/#using-the-state-extractor
-    let global_state = ApplicationState::new(config.clone());
-    let address = format!("{}:{}", config.application.host, config.application.port);
-
+    let global_state = ApplicationState::new(config.clone()).with_log_level(log_level);
     // Build application with routes
-    let router = Router::new()
-        .add_middleware(config.clone())
-        .add_routes(config.clone())
-        // Ref: https://docs.rs/axum/latest/axum/struct.Router.html#returning-routers-with-states-from-functions
-        .with_state(global_state);
+    let router = build_app(global_state.clone());
+
+    // Accept connections right away, and respond with `503` until the backend is initialized, so load
+    // balancers don't see refused connections during a slow startup.
+    let (listener, _) = bind(&config.application).await?;
+    global_state.set_ready(false);
+    let initialization = tokio::spawn(initialize_backend(global_state.clone()));
+    let _sweeper = global_state.spawn_expiry_sweeper();
 
     // Run server
-    let listener = TcpListener::bind(address).await?;
-    debug!("Listening on {}...", listener.local_addr()?);
-    axum::serve(listener, router).await?;
+    serve(listener, router, shutdown_signal(), &config.application).await?;
+
+    // Note: A snapshot that wasn't fully loaded yet must not replace the one on disk.
+    initialization.abort();
+    if let Some(path) = &config.application.snapshot_path
+        && global_state.is_ready()
+        && let Err(err) = snapshot::save(&*global_state.db.read().unwrap(), Path::new(path))
+    {
+        error!("Failed to save snapshot to {}: {}", path, err);
+    }
     Ok(())
 }
 
+/// Initializes the backend, i.e. loads the snapshot if configured, then marks the application as ready.
+///
+/// Exits the process if the snapshot can't be loaded, since serving without it would lose its entries on the
+/// next save.
+async fn initialize_backend(state: ApplicationState) {
+    let loading = state.clone();
+    // Note: Loading reads the file and holds the database lock throughout, so it runs off the async workers.
+    let loaded = tokio::task::spawn_blocking(move || match &loading.config.application.snapshot_path {
+        Some(path) => snapshot::load(&mut *loading.db.write().unwrap(), Path::new(path)).map(|_| ()),
+        None => Ok(()),
+    })
+    .await;
+    match loaded {
+        Ok(Ok(())) => {
+            state.set_ready(true);
+            info!("Backend is ready.");
+        }
+        Ok(Err(err)) => {
+            error!("Failed to load the snapshot: {}", err);
+            process::exit(1);
+        }
+        Err(err) => {
+            error!("Backend initialization failed: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Completes on Ctrl+C, or on `SIGTERM` on Unix, to start a graceful shutdown.
+// Ref: https://github.com/tokio-rs/axum/blob/main/examples/graceful-shutdown/src/main.rs
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("Failed to install the Ctrl+C handler.");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install the SIGTERM handler.")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down...");
+}
+
 /// Initializes the tracing subscriber for logging.
-fn init_tracing(config: Arc<Settings>) {
+fn init_tracing(config: Arc<Settings>) -> LogLevelHandle {
+    // Note: The level was checked by `Settings::validate` when the configuration was loaded.
+    let level: LevelFilter = config.application.log_level.parse().expect("Log level is valid");
+    // Note: The filter is wrapped in a reload layer, so `POST /admin/reload` can change the level.
+    let (filter, handle) = reload::Layer::new(level);
     if config.environment == Environment::Local.as_str() {
         let format = fmt::format()
             .with_level(true)
@@ -42,9 +133,9 @@ fn init_tracing(config: Arc<Settings>) {
             .with_thread_names(true)
             .compact();
 
-        tracing_subscriber::fmt()
-            .event_format(format)
-            .with_max_level(Level::TRACE)
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt::layer().event_format(format))
             .init()
     } else {
         let format = fmt::format()
@@ -52,9 +143,10 @@ fn init_tracing(config: Arc<Settings>) {
             .with_target(true)
             .compact();
 
-        tracing_subscriber::fmt()
-            .event_format(format)
-            .with_max_level(Level::INFO)
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt::layer().event_format(format))
             .init()
     }
+    handle
 }
@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use hdrhistogram::Histogram as HdrHistogram;
+
+/// Upper bounds of the value size histogram buckets in bytes, from 64 B to 16 MiB.
+const VALUE_SIZE_BUCKETS: [f64; 10] = [
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+
+/// Quantiles of the request latency reported with `ApplicationSettings::hdr_latency`.
+const LATENCY_QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+/// Application metrics, rendered in the Prometheus text format by `GET /metrics`.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Latency of requests to the application routes, in milliseconds.
+    pub request_latency_ms: Histogram,
+    /// Latency of requests to the application routes for accurate quantiles, if enabled.
+    pub request_latency_quantiles: Option<Quantiles>,
+    /// Number of requests by method and route template.
+    pub requests: RequestCounter,
+    /// Size of each value written, in bytes, recorded by the backend it's shared with.
+    pub value_size_bytes: Arc<Histogram>,
+    /// Approximate size of the stored keys and values in bytes, refreshed from the backend when the metrics are
+    /// rendered.
+    pub stored_bytes: AtomicU64,
+    /// Number of times the backend recovered from a poisoned lock, refreshed from the backend when the metrics
+    /// are rendered.
+    pub poison_recoveries: AtomicU64,
+}
+
+impl Metrics {
+    /// Creates empty metrics.
+    /// # Arguments
+    /// * `latency_buckets_ms`: Upper bounds of the latency histogram buckets, in increasing order.
+    /// * `hdr_latency`: Whether to also track latency quantiles with an HDR histogram.
+    pub fn new(latency_buckets_ms: &[f64], hdr_latency: bool) -> Self {
+        Self {
+            request_latency_ms: Histogram::new(latency_buckets_ms),
+            request_latency_quantiles: hdr_latency.then(Quantiles::new),
+            requests: RequestCounter::default(),
+            value_size_bytes: Arc::new(Histogram::new(&VALUE_SIZE_BUCKETS)),
+            stored_bytes: AtomicU64::new(0),
+            poison_recoveries: AtomicU64::new(0),
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        self.request_latency_ms.render(
+            &mut output,
+            "http_request_duration_ms",
+            "Latency of HTTP requests in milliseconds.",
+        );
+        if let Some(quantiles) = &self.request_latency_quantiles {
+            quantiles.render(
+                &mut output,
+                "http_request_duration_quantiles_ms",
+                "Quantiles of the latency of HTTP requests in milliseconds.",
+            );
+        }
+        self.requests.render(
+            &mut output,
+            "http_requests_total",
+            "Number of HTTP requests by method and route.",
+        );
+        self.value_size_bytes.render(
+            &mut output,
+            "kv_value_size_bytes",
+            "Size of written values in bytes.",
+        );
+        let _ = writeln!(output, "# HELP kv_stored_bytes Approximate size of stored keys and values in bytes.");
+        let _ = writeln!(output, "# TYPE kv_stored_bytes gauge");
+        let _ = writeln!(output, "kv_stored_bytes {}", self.stored_bytes.load(Ordering::Relaxed));
+        let _ = writeln!(
+            output,
+            "# HELP kv_lock_poison_recoveries_total Number of operations that recovered from a poisoned lock."
+        );
+        let _ = writeln!(output, "# TYPE kv_lock_poison_recoveries_total counter");
+        let _ = writeln!(
+            output,
+            "kv_lock_poison_recoveries_total {}",
+            self.poison_recoveries.load(Ordering::Relaxed)
+        );
+        output
+    }
+}
+
+/// Histogram with fixed bucket upper bounds.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    data: Mutex<HistogramData>,
+}
+
+#[derive(Debug, Default)]
+struct HistogramData {
+    /// Non-cumulative count per bucket, with a trailing overflow bucket.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    /// Creates an empty histogram.
+    /// # Arguments
+    /// * `bounds`: Upper bounds of the buckets, in increasing order.
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            data: Mutex::new(HistogramData {
+                counts: vec![0; bounds.len() + 1],
+                sum: 0.0,
+            }),
+        }
+    }
+
+    /// Records an observation in the first bucket whose upper bound is at least the value.
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        let mut data = self.data.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        data.counts[bucket] += 1;
+        data.sum += value;
+    }
+
+    /// Returns the cumulative count per bucket upper bound, ending with `+Inf`.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let data = self.data.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let bounds = self.bounds.iter().copied().chain([f64::INFINITY]);
+        bounds
+            .zip(data.counts.iter().scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            }))
+            .collect()
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let buckets = self.buckets();
+        let sum = self.data.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).sum;
+
+        // Note: Writing to a `String` never fails.
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} histogram", name);
+        for (bound, count) in &buckets {
+            let bound = if bound.is_infinite() { "+Inf".to_string() } else { bound.to_string() };
+            let _ = writeln!(output, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(output, "{}_sum {}", name, sum);
+        let _ = writeln!(output, "{}_count {}", name, buckets.last().map_or(0, |(_, count)| *count));
+    }
+}
+
+/// Counter labeled by request method and route template.
+///
+/// Labels are route templates such as `/api/{key}` rather than paths, so the number of series stays bounded.
+#[derive(Debug, Default)]
+pub struct RequestCounter {
+    counts: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+impl RequestCounter {
+    /// Counts a request.
+    /// # Arguments
+    /// * `method`: The request method, e.g. `GET`.
+    /// * `route`: The matched route template, e.g. `/api/{key}`.
+    pub fn increment(&self, method: &str, route: &str) {
+        let mut counts = self.counts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *counts.entry((method.to_string(), route.to_string())).or_insert(0) += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let counts = self.counts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        for ((method, route), count) in counts.iter() {
+            let _ = writeln!(output, "{}{{method=\"{}\",route=\"{}\"}} {}", name, method, route, count);
+        }
+    }
+}
+
+/// High dynamic range histogram for accurate quantiles, e.g. p99, which fixed buckets can only approximate.
+///
+/// Values are recorded with microsecond resolution and 3 significant digits, so quantiles are within 0.1%.
+#[derive(Debug)]
+pub struct Quantiles {
+    data: Mutex<HdrHistogram<u64>>,
+}
+
+impl Quantiles {
+    /// Creates an empty histogram that grows to fit any value.
+    pub fn new() -> Self {
+        Self {
+            data: Mutex::new(HdrHistogram::new(3).expect("3 significant digits are supported")),
+        }
+    }
+
+    /// Records an observation in milliseconds.
+    pub fn observe(&self, value_ms: f64) {
+        let mut data = self.data.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let value_us = (value_ms * 1000.0).round() as u64;
+        // Note: Unlike `saturating_record`, `record` grows the histogram to fit the value.
+        if data.record(value_us).is_err() {
+            data.saturating_record(value_us);
+        }
+    }
+
+    /// Returns the value in milliseconds below which the given fraction of observations fall.
+    /// # Arguments
+    /// * `quantile`: The fraction, e.g. `0.99` for the 99th percentile.
+    pub fn quantile(&self, quantile: f64) -> f64 {
+        let data = self.data.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        data.value_at_quantile(quantile) as f64 / 1000.0
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let count = self.data.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).len();
+
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} summary", name);
+        for quantile in LATENCY_QUANTILES {
+            let _ = writeln!(output, "{}{{quantile=\"{}\"}} {}", name, quantile, self.quantile(quantile));
+        }
+        let _ = writeln!(output, "{}_count {}", name, count);
+    }
+}
+
+impl Default for Quantiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::new(&[10.0, 100.0]);
+        for value in [1.0, 10.0, 50.0, 500.0] {
+            histogram.observe(value);
+        }
+
+        assert_eq!(histogram.buckets(), vec![(10.0, 2), (100.0, 3), (f64::INFINITY, 4)]);
+
+        let mut output = String::new();
+        histogram.render(&mut output, "latency", "Latency.");
+        assert!(output.contains("latency_bucket{le=\"10\"} 2\n"));
+        assert!(output.contains("latency_bucket{le=\"+Inf\"} 4\n"));
+        assert!(output.contains("latency_sum 561\n"));
+        assert!(output.contains("latency_count 4\n"));
+    }
+
+    #[test]
+    fn test_latency_quantiles() {
+        let metrics = Metrics::new(&[10.0], true);
+        let quantiles = metrics.request_latency_quantiles.as_ref().unwrap();
+        // 1 ms to 1000 ms in 1 ms steps, so the N-th percentile is N * 10 ms.
+        for value in 1..=1000 {
+            quantiles.observe(value as f64);
+        }
+
+        for (quantile, expected) in [(0.5, 500.0), (0.9, 900.0), (0.99, 990.0), (0.999, 999.0)] {
+            let actual = quantiles.quantile(quantile);
+            assert!((actual - expected).abs() / expected < 0.001, "p{}: {}", quantile, actual);
+        }
+        let output = metrics.render();
+        assert!(output.contains("http_request_duration_quantiles_ms{quantile=\"0.99\"} 990"));
+        assert!(output.contains("http_request_duration_quantiles_ms_count 1000\n"));
+
+        assert!(Metrics::new(&[10.0], false).request_latency_quantiles.is_none());
+    }
+
+    #[test]
+    fn test_poison_recoveries_are_exported() {
+        let metrics = Metrics::new(&[10.0], false);
+        metrics.poison_recoveries.store(2, Ordering::Relaxed);
+
+        let output = metrics.render();
+        assert!(output.contains("# TYPE kv_lock_poison_recoveries_total counter\n"));
+        assert!(output.contains("kv_lock_poison_recoveries_total 2\n"));
+    }
+}
@@ -1,17 +1,31 @@
-use crate::configuration::{Environment, Settings};
+use crate::configuration::{ApplicationSettings, DuplicateTraceId, Environment, Settings};
+use crate::context::RequestContext;
+use crate::debug::capture::{redact_headers, CapturedRequest, RequestCapture};
 use crate::dependency::ApplicationState;
-use axum::body::Body;
-use axum::error_handling::HandleErrorLayer;
-use axum::http::{Request, StatusCode};
-use axum::response::IntoResponse;
+use crate::limiter::{AcquireError, ConcurrencyLimiter};
+use crate::response::{Envelope, ErrorBody, JsonResponse};
+use axum::body::{Body, HttpBody};
+use axum::extract::{ConnectInfo, MatchedPath, State};
+use axum::Json;
+use axum::http::header::{ALLOW, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode, Uri};
+use axum::middleware::{from_fn, from_fn_with_state, Next};
+use axum::response::{IntoResponse, Response};
 use axum::Router;
-use std::borrow::Cow;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 use tower::{BoxError, ServiceBuilder};
-use tower_http::trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer};
+use tower_http::trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, OnRequest, OnResponse, TraceLayer};
+use tower_http::compression::{CompressionLayer, CompressionLevel};
 use tower_http::LatencyUnit;
-use tracing::{Level, Span};
+use ipnet::IpNet;
+use tracing::field::Empty;
+use tracing::{error, warn, Level, Span};
+use futures_util::{stream, StreamExt};
 use uuid::Uuid;
 
 /// Extension trait for adding middleware to the Axum router.
@@ -22,82 +36,1355 @@ pub trait Middleware {
 
 impl Middleware for Router<ApplicationState> {
     fn add_middleware(self, config: Arc<Settings>) -> Self {
+        let request_config = config.clone();
+        let response_config = config.clone();
+        let methods_config = config.clone();
+        let context_config = config.clone();
+        let timeout_config = config.clone();
+        let response_time_config = config.clone();
+        let sampling_config = config.clone();
+        let header_config = config.clone();
+        let query_config = config.clone();
+        let envelope_config = config.clone();
+        let compression_level = match config.application.compression_level {
+            Some(level) => CompressionLevel::Precise(level),
+            None => CompressionLevel::Default,
+        };
         self.layer(
             ServiceBuilder::new()
-                .layer(HandleErrorLayer::new(handle_tower_error))
-                .load_shed()
-                .concurrency_limit(config.application.max_concurrent_requests)
-                .timeout(Duration::from_secs(config.application.request_timeout_s))
+                // Outermost, so oversized headers are rejected before any other work.
+                .layer(from_fn_with_state(header_config, limit_header_size))
+                .layer(from_fn_with_state(query_config, limit_query_size))
+                // Note: Compression is negotiated with the `Accept-Encoding` request header.
+                .layer(CompressionLayer::new().quality(compression_level))
+                // Inside compression to compress envelopes, and outside the timeout to wrap `408`s.
+                .layer(from_fn_with_state(envelope_config, wrap_in_envelope))
+                .layer(from_fn_with_state(response_time_config, add_response_time))
+                .layer(from_fn_with_state(timeout_config, apply_timeout))
+                // Must run before the trace layer, which reads the trace ID from the context.
+                .layer(from_fn_with_state(context_config, attach_request_context))
+                // Between the two, since sampling hashes the trace ID and the trace layer reads the decision.
+                .layer(from_fn_with_state(sampling_config, decide_log_sampling))
                 // TODO: How do I add a trace layer for non-HTTP logs?
                 // tower-http middleware for logging
                 // Ref: https://docs.rs/tower-http/latest/tower_http/trace/index.html
                 .layer(
                     TraceLayer::new_for_http()
                         .make_span_with(move |request: &Request<Body>| build_trace_span(request, config.clone()))
-                        .on_request(DefaultOnRequest::new().level(Level::INFO))
-                        .on_response(
+                        .on_request(move |request: &Request<Body>, span: &Span| {
+                            // Skip logging for routes excluded from tracing, and requests sampled out.
+                            if span.is_disabled() || request.extensions().get::<LogSampledOut>().is_some() {
+                                return;
+                            }
+                            let route = request.extensions().get::<MatchedPath>();
+                            let level = route_level(route.map(MatchedPath::as_str), &request_config);
+                            DefaultOnRequest::new()
+                                .level(level.unwrap_or(Level::INFO))
+                                .on_request(request, span)
+                        })
+                        .on_response(move |response: &Response, latency: Duration, span: &Span| {
+                            if span.is_disabled() {
+                                return;
+                            }
+                            record_response_size(response, span);
+                            // Requests sampled out are only logged if they failed or were slow.
+                            let slow_after = Duration::from_millis(response_config.application.log_slow_ms);
+                            if response.extensions().get::<LogSampledOut>().is_some()
+                                && !response.status().is_server_error()
+                                && latency < slow_after
+                            {
+                                return;
+                            }
+                            let route = response.extensions().get::<MatchedPath>();
+                            let level = route_level(route.map(MatchedPath::as_str), &response_config);
                             DefaultOnResponse::new()
-                                .level(Level::INFO)
-                                .latency_unit(LatencyUnit::Micros),
-                        )
+                                .level(level.unwrap_or(Level::INFO))
+                                .latency_unit(LatencyUnit::Micros)
+                                .on_response(response, latency, span)
+                        })
                         .on_failure(
                             DefaultOnFailure::new()
                                 .level(Level::ERROR)
                                 .latency_unit(LatencyUnit::Micros),
                         ),
-                ),
+                )
+                .layer(from_fn_with_state(methods_config, reject_disallowed_methods))
+                .layer(from_fn(expose_matched_path))
+                .layer(from_fn(expose_log_sampling))
+                .layer(from_fn(enforce_content_length)),
         )
     }
 }
 
+/// Creates the request span at a level chosen at runtime.
+// Note: `tracing::span!` needs a constant level, since it's part of the callsite's static metadata.
+macro_rules! request_span {
+    ($level:expr, $($fields:tt)*) => {
+        match $level {
+            Level::ERROR => tracing::span!(Level::ERROR, "request", $($fields)*),
+            Level::WARN => tracing::span!(Level::WARN, "request", $($fields)*),
+            Level::INFO => tracing::span!(Level::INFO, "request", $($fields)*),
+            Level::DEBUG => tracing::span!(Level::DEBUG, "request", $($fields)*),
+            Level::TRACE => tracing::span!(Level::TRACE, "request", $($fields)*),
+        }
+    };
+}
+
 fn build_trace_span(request: &Request<Body>, config: Arc<Settings>) -> Span {
-    // Extract the trace ID from the request headers, or generate a new one.
-    let trace_id = request
-        .headers()
-        .get("X-Trace-ID")
-        .and_then(|value| value.to_str().ok().map(|val| val.to_string()))
-        .unwrap_or(Uuid::new_v4().to_string());
+    if matches_route(request, &config.application.trace_exclude) {
+        return Span::none();
+    }
+
+    let context = request.extensions().get::<RequestContext>();
+    let trace_id = context
+        .map(|context| context.trace_id.clone())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let canary = context.is_some_and(|context| context.canary);
+    let sampled = request.extensions().get::<LogSampledOut>().is_none();
+    // The route template (e.g. `/api/{key}`) is available since the layer wraps the matched route.
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+
+    let level = route_level(route.as_deref(), &config).unwrap_or(
+        if config.environment == Environment::Local.as_str() {
+            Level::TRACE
+        } else {
+            Level::INFO
+        },
+    );
+
+    let uri = uri_for_logs(request.uri(), route.as_deref(), &config.application);
 
     // Note: Doc for the `%` and `?` sigils: https://docs.rs/tracing/latest/tracing/#recording-fields
-    if config.environment == Environment::Local.as_str() {
-        tracing::span!(
-            Level::TRACE,
-            "request",
-            trace_id = %trace_id,
-            method = %request.method(),
-            uri = %request.uri(),
-            version = ?request.version(),
-            headers = ?request.headers()
-        )
+    request_span!(
+        level,
+        trace_id = %trace_id,
+        method = %request.method(),
+        uri = %uri,
+        route = route.as_deref(),
+        canary = canary,
+        sampled = sampled,
+        version = ?request.version(),
+        // Note: Credentials, e.g. the admin token, are redacted.
+        headers = ?redact_headers(request.headers()),
+        response_size = Empty
+    )
+}
+
+/// Whether the request and response of a request are logged, see `ApplicationSettings::log_sample_rate`.
+///
+/// The trace ID is hashed, so the decision is the same wherever it's made for a request.
+fn is_log_sampled(request: &Request<Body>, config: &Settings) -> bool {
+    let rate = route_setting(request, &config.application.log_sample_rate).copied();
+    let (Some(rate), Some(context)) = (rate, request.extensions().get::<RequestContext>()) else {
+        return true;
+    };
+    let mut hasher = DefaultHasher::new();
+    context.trace_id.hash(&mut hasher);
+    rate <= 1 || hasher.finish().is_multiple_of(rate)
+}
+
+/// Returns the tracing level configured for a route in `ApplicationSettings::trace_levels`, if any.
+fn route_level(route: Option<&str>, config: &Settings) -> Option<Level> {
+    config
+        .application
+        .trace_levels
+        .get(route?)
+        .and_then(|level| level.parse().ok())
+}
+
+/// Random keys of the hash of `hash_for_logs`, drawn once per process.
+static LOG_HASH_KEYS: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+
+/// Hashes a value, e.g. a key, so it can be correlated across log lines without being recorded in plain text.
+///
+/// The hash is keyed with a random salt per process, so short or guessable values can't be recovered from
+/// the logs by hashing candidates. Hashes are only comparable within the logs of the same process.
+pub(crate) fn hash_for_logs(value: &str) -> String {
+    format!("#{:016x}", LOG_HASH_KEYS.hash_one(value))
+}
+
+/// Returns a key, or any value derived from keys like a prefix, as it should be recorded in logs, i.e. hashed
+/// with `ApplicationSettings::hash_keys_in_logs`.
+pub(crate) fn key_for_logs(key: &str, config: &ApplicationSettings) -> String {
+    if config.hash_keys_in_logs {
+        hash_for_logs(key)
+    } else {
+        key.to_string()
+    }
+}
+
+/// Returns a request URI as it should be recorded in logs, i.e. with the path parameters of its route
+/// template hashed with `ApplicationSettings::hash_keys_in_logs`.
+/// # Arguments
+/// * `uri`: The request URI.
+/// * `route`: The matched route template, e.g. `/api/{key}`, if any.
+/// * `config`: The application settings.
+pub(crate) fn uri_for_logs(uri: &Uri, route: Option<&str>, config: &ApplicationSettings) -> String {
+    match route {
+        Some(route) if config.hash_keys_in_logs => hash_path_params(uri, route),
+        _ => uri.to_string(),
+    }
+}
+
+/// Replaces the path segments matching parameters of the route template (e.g. `{key}` in `/api/{key}`)
+/// with their hash, so they can be correlated across log lines without being recorded in plain text.
+fn hash_path_params(uri: &Uri, route: &str) -> String {
+    let path = uri
+        .path()
+        .split('/')
+        .zip(route.split('/'))
+        .map(|(segment, template)| {
+            if template.starts_with('{') {
+                hash_for_logs(segment)
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    }
+}
+
+/// Sets the `X-Response-Time-ms` header to the time the app took to produce the response head, when
+/// `ApplicationSettings::response_time_header` is enabled.
+///
+/// Writing the body to the network isn't included, so the value doesn't depend on the client's connection.
+async fn add_response_time(State(config): State<Arc<Settings>>, request: Request<Body>, next: Next) -> Response {
+    if !config.application.response_time_header {
+        return next.run(request).await;
+    }
+    let started_at = Instant::now();
+    let mut response = next.run(request).await;
+    let elapsed_ms = started_at.elapsed().as_secs_f64() * 1000.0;
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.3}", elapsed_ms)) {
+        response.headers_mut().insert("X-Response-Time-ms", value);
+    }
+    response
+}
+
+/// Wraps the response body in an `Envelope` with the request's trace ID, if
+/// `ApplicationSettings::envelope_responses` is enabled and the route isn't in
+/// `ApplicationSettings::envelope_exclude`.
+///
+/// Only JSON and text bodies are wrapped, so e.g. MessagePack bodies and `304` responses are left as-is.
+/// The body is buffered to be wrapped, so streamed responses are no longer streamed.
+async fn wrap_in_envelope(State(config): State<Arc<Settings>>, request: Request<Body>, next: Next) -> Response {
+    if !config.application.envelope_responses || matches_route(&request, &config.application.envelope_exclude) {
+        return next.run(request).await;
+    }
+    let response = next.run(request).await;
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    let is_json = content_type.is_some_and(|value| value.starts_with("application/json"));
+    let is_text = content_type.is_none_or(|value| value.starts_with("text/"));
+    if !(is_json || is_text) || response.status() == StatusCode::NOT_MODIFIED {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            error!("Failed to buffer the response body to wrap it: {}", err);
+            return handle_tower_error(err.into()).await.into_response();
+        }
+    };
+    let text = String::from_utf8_lossy(&bytes);
+    let body = match is_json.then(|| serde_json::from_slice(&bytes).ok()).flatten() {
+        Some(json) => json,
+        None if text.is_empty() => serde_json::Value::Null,
+        None => serde_json::Value::String(text.into_owned()),
+    };
+    let (data, error) = if parts.status.is_client_error() || parts.status.is_server_error() {
+        let error = match body {
+            serde_json::Value::Object(_) => body,
+            serde_json::Value::String(message) => serde_json::json!({ "message": message }),
+            _ => serde_json::json!({ "message": parts.status.canonical_reason().unwrap_or_default() }),
+        };
+        (serde_json::Value::Null, Some(error))
     } else {
-        tracing::span!(
-            Level::INFO,
-            "request",
-            trace_id = %trace_id,
-            method = %request.method(),
-            uri = %request.uri(),
-            version = ?request.version(),
-            headers = ?request.headers()
+        (body, None)
+    };
+    let trace_id = parts.extensions.get::<RequestContext>().map(|context| context.trace_id.clone());
+    let envelope = Envelope { data, error, trace_id };
+
+    let wrapped = JsonResponse::new(envelope, &config.application).into_response();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Response::from_parts(parts, wrapped.into_body())
+}
+
+/// Fails requests with `408` when the handler doesn't respond within the timeout of their route in
+/// `ApplicationSettings::route_timeouts_s`, or `ApplicationSettings::request_timeout_s` otherwise.
+/// Routes listed in `ApplicationSettings::timeout_exempt_routes` have no timeout.
+///
+/// Only the time until the response head is returned counts, so a streamed response body is never cut off.
+async fn apply_timeout(State(config): State<Arc<Settings>>, request: Request<Body>, next: Next) -> Response {
+    if matches_route(&request, &config.application.timeout_exempt_routes) {
+        return next.run(request).await;
+    }
+    let timeout_s = *route_setting(&request, &config.application.route_timeouts_s)
+        .unwrap_or(&config.application.request_timeout_s);
+    let timeout = Duration::from_secs(timeout_s);
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => handle_tower_error(tower::timeout::error::Elapsed::new().into())
+            .await
+            .into_response(),
+    }
+}
+
+/// Copies the matched route into the response extensions, so `on_response` can look up the route's
+/// tracing level.
+async fn expose_matched_path(request: Request<Body>, next: Next) -> Response {
+    let route = request.extensions().get::<MatchedPath>().cloned();
+    let mut response = next.run(request).await;
+    if let Some(route) = route {
+        response.extensions_mut().insert(route);
+    }
+    response
+}
+
+/// Marks a request, and its response, sampled out by `ApplicationSettings::log_sample_rate`.
+#[derive(Clone, Copy, Debug)]
+struct LogSampledOut;
+
+/// Decides once whether a request is logged, and records the decision in the request extensions, so the
+/// span and `on_request` can skip logging it.
+async fn decide_log_sampling(
+    State(config): State<Arc<Settings>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    if !is_log_sampled(&request, &config) {
+        request.extensions_mut().insert(LogSampledOut);
+    }
+    next.run(request).await
+}
+
+/// Copies the sampling decision of the request into the response extensions, so `on_response` can skip
+/// logging it.
+async fn expose_log_sampling(request: Request<Body>, next: Next) -> Response {
+    let sampled = request.extensions().get::<LogSampledOut>().is_none();
+    let mut response = next.run(request).await;
+    if !sampled {
+        response.extensions_mut().insert(LogSampledOut);
+    }
+    response
+}
+
+/// Returns the path of a request as it should be recorded in logs, see `uri_for_logs`.
+///
+/// The query string is left out, since it may be oversized, and may contain keys or values too.
+fn request_uri_for_logs(request: &Request<Body>, config: &Settings) -> String {
+    let route = request.extensions().get::<MatchedPath>().map(MatchedPath::as_str);
+    let path = Uri::try_from(request.uri().path()).unwrap_or_default();
+    uri_for_logs(&path, route, &config.application)
+}
+
+/// Responds with `431` if the total size of the request header names and values exceeds
+/// `ApplicationSettings::max_header_bytes`.
+async fn limit_header_size(
+    State(config): State<Arc<Settings>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let max_header_bytes = config.application.max_header_bytes;
+    let size: usize = request
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if size > max_header_bytes {
+        let uri = request_uri_for_logs(&request, &config);
+        warn!("Rejected request to {} with {} bytes of headers.", uri, size);
+        let message = format!("Request headers exceed the maximum size of {} bytes.", max_header_bytes);
+        return (StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, message).into_response();
+    }
+    next.run(request).await
+}
+
+/// Responds with `400` if the query string is longer than `ApplicationSettings::max_query_bytes`, or has more
+/// than `ApplicationSettings::max_query_params` parameters.
+async fn limit_query_size(
+    State(config): State<Arc<Settings>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let query = request.uri().query().unwrap_or_default();
+    let max_query_bytes = config.application.max_query_bytes;
+    if query.len() > max_query_bytes {
+        let uri = request_uri_for_logs(&request, &config);
+        warn!("Rejected request to {} with a {} bytes query string.", uri, query.len());
+        let message = format!("Query string exceeds the maximum size of {} bytes.", max_query_bytes);
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    let max_query_params = config.application.max_query_params;
+    let params = query.split('&').filter(|param| !param.is_empty()).count();
+    if params > max_query_params {
+        let uri = request_uri_for_logs(&request, &config);
+        warn!("Rejected request to {} with {} query parameters.", uri, params);
+        let message = format!("Query string exceeds the maximum of {} parameters.", max_query_params);
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    next.run(request).await
+}
+
+/// Responds with `405` to methods not listed in `ApplicationSettings::allowed_methods`, regardless of the route.
+async fn reject_disallowed_methods(
+    State(config): State<Arc<Settings>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let allowed_methods = &config.application.allowed_methods;
+    if !allowed_methods.is_empty()
+        && !allowed_methods
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(request.method().as_str()))
+    {
+        return (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(ALLOW, allowed_methods.join(", ").to_uppercase())],
+            format!("Method {} is not allowed.", request.method()),
         )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Fails reading the request body with `400` if its actual size doesn't match the declared `Content-Length`.
+///
+/// Hyper already enforces this on real connections, but checking it here doesn't rely on the server.
+/// The body is checked while it's streamed rather than buffered upfront, so a mismatch surfaces as a
+/// body error in the extractor that reads it, e.g. `Json`, which rejects it with `400`.
+async fn enforce_content_length(request: Request<Body>, next: Next) -> Response {
+    let declared = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .map(|value| value.to_str().ok().and_then(|value| value.parse::<usize>().ok()));
+    let declared = match declared {
+        None => return next.run(request).await,
+        Some(None) => return (StatusCode::BAD_REQUEST, "Invalid Content-Length header.").into_response(),
+        Some(Some(declared)) => declared,
+    };
+
+    let (parts, body) = request.into_parts();
+    let mismatch = move || {
+        axum::Error::new(format!(
+            "Body length does not match Content-Length of {} bytes.",
+            declared
+        ))
+    };
+    // State: the remaining body, the bytes received so far, and whether the stream has ended.
+    let checked = stream::unfold(
+        (body.into_data_stream(), 0, false),
+        move |(mut data, received, done)| async move {
+            if done {
+                return None;
+            }
+            match data.next().await {
+                Some(Ok(chunk)) if received + chunk.len() > declared => {
+                    Some((Err(mismatch()), (data, received, true)))
+                }
+                Some(Ok(chunk)) => {
+                    let received = received + chunk.len();
+                    Some((Ok(chunk), (data, received, false)))
+                }
+                Some(Err(err)) => Some((Err(err), (data, received, true))),
+                None if received < declared => Some((Err(mismatch()), (data, received, true))),
+                None => None,
+            }
+        },
+    );
+
+    next.run(Request::from_parts(parts, Body::from_stream(checked)))
+        .await
+}
+
+/// Populates the `RequestContext` in the request extensions.
+pub(crate) async fn attach_request_context(
+    State(config): State<Arc<Settings>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    // Extract the trace ID from the request headers, or generate a new one.
+    let mut trace_ids = request.headers().get_all("X-Trace-ID").iter();
+    let first = trace_ids.next();
+    let trace_id = if trace_ids.next().is_some() {
+        let policy = config.application.duplicate_trace_id;
+        warn!("Request has multiple X-Trace-ID headers, applying the `{:?}` policy.", policy);
+        match policy {
+            DuplicateTraceId::First => first,
+            DuplicateTraceId::Reject => {
+                return (StatusCode::BAD_REQUEST, "Multiple X-Trace-ID headers are not allowed.").into_response();
+            }
+            DuplicateTraceId::Generate => None,
+        }
+    } else {
+        first
+    };
+    let trace_id = trace_id
+        .and_then(|value| value.to_str().ok().map(|val| val.to_string()))
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    let trusted_proxies = &config.application.trusted_proxies;
+    let (client_ip, forwarded_scheme) = match peer {
+        Some(peer) => {
+            let (client_ip, scheme) = resolve_forwarded(request.headers(), peer, trusted_proxies);
+            (Some(client_ip), scheme)
+        }
+        None => (None, None),
+    };
+    let context = RequestContext {
+        route: request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| path.as_str().to_string()),
+        started_at: Instant::now(),
+        client_ip,
+        scheme: forwarded_scheme
+            .or_else(|| request.uri().scheme_str().map(str::to_string))
+            .unwrap_or_else(|| "http".to_string()),
+        canary: is_canary(&trace_id, config.application.canary_percent),
+        trace_id,
+    };
+    request.extensions_mut().insert(context.clone());
+
+    // Note: Also exposed on the response, for outer layers like `wrap_in_envelope`.
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(context);
+    response
+}
+
+/// Whether a request is tagged for the canary, which is the case for `percent`% of trace IDs.
+///
+/// The trace ID is hashed, so retries and requests across services with the same trace ID are tagged alike.
+fn is_canary(trace_id: &str, percent: f64) -> bool {
+    (canary_bucket(trace_id) as f64) < percent * 100.0
+}
+
+/// Bucket of a trace ID from 0 to 9999, i.e. of 0.01%, the finest granularity of the percentage that's honored.
+///
+/// Uses 64-bit FNV-1a rather than `DefaultHasher`, whose algorithm may change between Rust releases, so
+/// the buckets are stable across builds and services.
+fn canary_bucket(trace_id: &str) -> u64 {
+    let hash = trace_id.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    hash % 10_000
+}
+
+/// Resolves the client IP and scheme from the forwarded headers, if the peer is a trusted proxy.
+///
+/// `X-Forwarded-For` is walked from the right, skipping trusted proxies, since only the entries appended by
+/// trusted proxies can be relied upon. Entries further left may have been set by the client.
+/// # Returns
+/// * `(IpAddr, Option<String>)`: The client IP, which is the peer itself if it's not trusted, and the
+///   forwarded scheme, if any.
+fn resolve_forwarded(
+    headers: &HeaderMap,
+    peer: IpAddr,
+    trusted_proxies: &[IpNet],
+) -> (IpAddr, Option<String>) {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+    if !is_trusted(&peer) {
+        return (peer, None);
+    }
+
+    let forwarded_for: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    let mut client_ip = peer;
+    for hop in forwarded_for.iter().rev() {
+        let Ok(ip) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        client_ip = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+
+    let scheme = headers
+        .get("X-Forwarded-Proto")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase());
+    (client_ip, scheme)
+}
+
+/// Checks whether the request's route matches any of the patterns.
+/// # Arguments
+/// * `request`: The incoming request.
+/// * `patterns`: Route templates, optionally prefixed with a method, e.g. `GET /api/{key}`.
+fn matches_route(request: &Request<Body>, patterns: &[String]) -> bool {
+    patterns.iter().any(|pattern| matches_pattern(request, pattern))
+}
+
+/// Checks whether the request's route matches a route template, optionally prefixed with a method.
+fn matches_pattern(request: &Request<Body>, pattern: &str) -> bool {
+    let Some(route) = request.extensions().get::<MatchedPath>() else {
+        return false;
+    };
+    match pattern.split_once(' ') {
+        Some((method, path)) => method == request.method().as_str() && path == route.as_str(),
+        None => pattern == route.as_str(),
+    }
+}
+
+/// Looks up the value of the request's route in settings keyed by route pattern, e.g.
+/// `ApplicationSettings::route_timeouts_s`.
+///
+/// A pattern with a method, e.g. `GET /api/{key}`, takes precedence over the same route without one, so the
+/// most specific pattern wins when both match.
+fn route_setting<'a, T>(request: &Request<Body>, settings: &'a HashMap<String, T>) -> Option<&'a T> {
+    let route = request.extensions().get::<MatchedPath>()?.as_str();
+    settings
+        .get(&format!("{} {}", request.method(), route))
+        .or_else(|| settings.get(route))
+}
+
+/// Records the response body size on the request span, if known upfront.
+///
+/// Streamed bodies without a `Content-Length` header are not recorded.
+fn record_response_size(response: &Response, span: &Span) {
+    let size = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    if let Some(size) = size {
+        span.record("response_size", size);
     }
 }
 
-/// Error code mapping for tower middlewares.
+/// Sheds requests beyond the limiter's current limit, after waiting up to
+/// `ApplicationSettings::shed_after_ms` for a slot.
+///
+/// The limit is adjustable at runtime, see `crate::admin::handler`.
+pub(crate) async fn limit_concurrency(
+    State(state): State<ApplicationState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    run_with_permit(&state.limiter, &state, request, next).await
+}
+
+/// Responds with `429` to clients that already have `ApplicationSettings::max_concurrent_per_client`
+/// requests in flight, while other clients proceed.
+///
+/// Clients are identified by their `X-API-Key` header if it's one of `ApplicationSettings::client_api_keys`, or
+/// else their IP address. Requests from unidentified clients are only subject to the global limit.
+pub(crate) async fn limit_concurrency_per_client(
+    State(state): State<ApplicationState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let client = request
+        .headers()
+        .get("X-API-Key")
+        .and_then(|value| value.to_str().ok())
+        .filter(|key| state.config.application.client_api_keys.iter().any(|allowed| allowed == key))
+        .map(|key| format!("key:{}", key))
+        .or_else(|| {
+            let context = request.extensions().get::<RequestContext>()?;
+            context.client_ip.map(|ip| format!("ip:{}", ip))
+        });
+    let Some(client) = client else {
+        return next.run(request).await;
+    };
+
+    // Note: The permit is held until the inner service returns, then released on drop.
+    let Some(_permit) = state.client_limiter.try_acquire(&client) else {
+        let message = "Too many concurrent requests from this client, try again later.";
+        let body = ErrorBody::new("too_many_requests", message);
+        return retry_later(StatusCode::TOO_MANY_REQUESTS, body, "client_concurrency_limit", &state);
+    };
+    next.run(request).await
+}
+
+/// Responds with `503` until the backend has finished initializing.
+pub(crate) async fn reject_until_ready(
+    State(state): State<ApplicationState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if !state.is_ready() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Service is starting up, try again later.").into_response();
+    }
+    next.run(request).await
+}
+
+/// Bodies larger than this are not captured.
+const MAX_CAPTURED_BODY_SIZE: usize = 64 * 1024;
+
+/// Records each request in the debug capture buffer, if enabled.
+///
+/// The body is only captured if its `Content-Length` is known and at most `MAX_CAPTURED_BODY_SIZE`, since it
+/// has to be buffered before being passed on.
+pub(crate) async fn capture_request(
+    State(capture): State<Arc<RequestCapture>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if !capture.is_enabled() {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+    let (body, captured_body) = match content_length {
+        Some(length) if length <= MAX_CAPTURED_BODY_SIZE => {
+            match axum::body::to_bytes(body, MAX_CAPTURED_BODY_SIZE).await {
+                Ok(bytes) => {
+                    let text = String::from_utf8(bytes.to_vec()).ok();
+                    (Body::from(bytes), text)
+                }
+                Err(_) => {
+                    return (StatusCode::BAD_REQUEST, "Failed to read the request body.").into_response();
+                }
+            }
+        }
+        _ => (body, None),
+    };
+    capture.record(CapturedRequest {
+        method: parts.method.to_string(),
+        path: parts.uri.to_string(),
+        headers: redact_headers(&parts.headers),
+        body: captured_body,
+    });
+
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Records the latency of each request and counts it by method and route in the metrics, except for routes
+/// matching `ApplicationSettings::metrics_exclude`.
+pub(crate) async fn record_latency(
+    State(state): State<ApplicationState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if matches_route(&request, &state.config.application.metrics_exclude) {
+        return next.run(request).await;
+    }
+    let metrics = &state.metrics;
+    let method = request.method().clone();
+    let route = request.extensions().get::<MatchedPath>().cloned();
+
+    let started_at = Instant::now();
+    let response = next.run(request).await;
+    let latency_ms = started_at.elapsed().as_secs_f64() * 1000.0;
+    if let Some(route) = route {
+        metrics.requests.increment(method.as_str(), route.as_str());
+    }
+    metrics.request_latency_ms.observe(latency_ms);
+    if let Some(quantiles) = &metrics.request_latency_quantiles {
+        quantiles.observe(latency_ms);
+    }
+    response
+}
+
+/// Sheds requests beyond the read or write limit, depending on whether the request method is safe.
+pub(crate) async fn limit_concurrency_by_method(
+    State(state): State<ApplicationState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let limiter = if request.method().is_safe() {
+        &state.read_limiter
+    } else {
+        &state.write_limiter
+    };
+    run_with_permit(limiter, &state, request, next).await
+}
+
+async fn run_with_permit(
+    limiter: &Arc<ConcurrencyLimiter>,
+    state: &ApplicationState,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let wait = Duration::from_millis(state.config.application.shed_after_ms);
+    // Note: The permit is held until the inner service returns, then released on drop.
+    let _permit = match limiter.acquire(wait, state.config.application.max_queued_requests).await {
+        Ok(permit) => permit,
+        Err(AcquireError::QueueFull) => {
+            let (status, Json(body)) = handle_tower_error(AcquireError::QueueFull.into()).await;
+            return retry_later(status, body, "queue_limit", state);
+        }
+        Err(AcquireError::TimedOut) => {
+            let overloaded = tower::load_shed::error::Overloaded::new().into();
+            let (status, Json(body)) = handle_tower_error(overloaded).await;
+            return retry_later(status, body, "concurrency_limit", state);
+        }
+    };
+    next.run(request).await
+}
+
+/// Builds the response to a request rejected by a limit, with retry guidance after
+/// `ApplicationSettings::retry_after_s` in both the `Retry-After` header and the JSON body.
+/// # Arguments
+/// * `status`: The status of the rejection, e.g. `429` or `503`.
+/// * `body`: The error body.
+/// * `reason`: Stable name of the limit that rejected the request.
+/// * `state`: The application state.
+fn retry_later(
+    status: StatusCode,
+    body: ErrorBody,
+    reason: &'static str,
+    state: &ApplicationState,
+) -> Response {
+    let retry_after_s = state.config.application.retry_after_s;
+    let body = Json(body.with_retry(retry_after_s, reason));
+    (status, [(RETRY_AFTER, retry_after_s.to_string())], body).into_response()
+}
+
+/// Error code mapping for tower middlewares, with a JSON `ErrorBody`.
 // Ref: https://docs.rs/axum/latest/axum/error_handling/index.html
-async fn handle_tower_error(error: BoxError) -> impl IntoResponse {
+async fn handle_tower_error(error: BoxError) -> (StatusCode, Json<ErrorBody>) {
     if error.is::<tower::timeout::error::Elapsed>() {
-        return (StatusCode::REQUEST_TIMEOUT, Cow::from("Request timed out."));
+        return (StatusCode::REQUEST_TIMEOUT, Json(ErrorBody::new("timeout", "Request timed out.")));
     }
 
     if error.is::<tower::load_shed::error::Overloaded>() {
         return (
             StatusCode::SERVICE_UNAVAILABLE,
-            Cow::from("Service is overloaded, try again later."),
+            Json(ErrorBody::new("overloaded", "Service is overloaded, try again later.")),
         );
     }
 
+    if let Some(AcquireError::QueueFull) = error.downcast_ref::<AcquireError>() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorBody::new("queue_full", "Request queue is full, try again later.")),
+        );
+    }
+
+    error!("Unhandled middleware error: {}", error);
     (
         StatusCode::INTERNAL_SERVER_ERROR,
-        Cow::from("Internal server error."),
+        Json(ErrorBody::new("internal", "Internal server error.")),
     )
 }
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::build_app;
+    use crate::test_util::{body_string, send, test_settings, TraceCapture};
+    use axum::routing::get;
+    use http_body_util::BodyExt;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_request_span_records_route_and_response_size() {
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let spans = capture.spans("request");
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].fields["route"], "/");
+        // {"backend":"InMemory","healthy":true,"empty":true}
+        assert_eq!(spans[0].fields["response_size"], "50");
+    }
+
+    #[tokio::test]
+    async fn test_request_context_is_populated() {
+        let app = Router::new()
+            .route(
+                "/context/{id}",
+                get(|context: RequestContext| async move {
+                    format!("{} {:?} {:?}", context.trace_id, context.route, context.client_ip)
+                }),
+            )
+            .layer(from_fn_with_state(Arc::new(test_settings()), attach_request_context));
+
+        let mut request = Request::get("/context/1")
+            .header("X-Trace-ID", "trace-1")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+
+        let response = send(&app, request).await;
+        assert_eq!(
+            body_string(response).await,
+            r#"trace-1 Some("/context/{id}") Some(10.0.0.1)"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_trace_ids() {
+        let request = || {
+            Request::get("/")
+                .header("X-Trace-ID", "trace-1")
+                .header("X-Trace-ID", "trace-2")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let app = |policy: DuplicateTraceId| {
+            let mut settings = test_settings();
+            settings.application.duplicate_trace_id = policy;
+            Router::new()
+                .route("/", get(|context: RequestContext| async move { context.trace_id }))
+                .layer(from_fn_with_state(Arc::new(settings), attach_request_context))
+        };
+
+        let response = send(&app(DuplicateTraceId::First), request()).await;
+        assert_eq!(body_string(response).await, "trace-1");
+
+        let response = send(&app(DuplicateTraceId::Reject), request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(&app(DuplicateTraceId::Generate), request()).await;
+        let trace_id = body_string(response).await;
+        assert!(Uuid::parse_str(&trace_id).is_ok(), "{}", trace_id);
+    }
+
+    #[tokio::test]
+    async fn test_route_trace_level() {
+        let mut settings = test_settings();
+        settings.application.trace_levels = [("/".to_string(), "warn".to_string())].into();
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+
+        // Levels of the "started processing request" and "finished processing request" events.
+        let request_event_levels = || -> Vec<Level> {
+            let events = capture.events();
+            let events = events.iter().filter(|event| event.fields["message"].contains("processing request"));
+            events.map(|event| event.level).collect()
+        };
+
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(capture.spans("request")[0].level, Level::WARN);
+        assert_eq!(request_event_levels(), vec![Level::WARN, Level::WARN]);
+
+        // Other routes keep the default levels.
+        send(&app, Request::get("/api/key").body(Body::empty()).unwrap()).await;
+        assert_eq!(request_event_levels()[2..], [Level::INFO, Level::INFO]);
+    }
+
+    #[tokio::test]
+    async fn test_log_sampling() {
+        let mut settings = test_settings();
+        settings.application.log_sample_rate = [("/".to_string(), 4), ("/fail".to_string(), 1_000_000)].into();
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route("/fail", get(|| async { StatusCode::INTERNAL_SERVER_ERROR }))
+            .add_middleware(state.config.clone())
+            .with_state(state);
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let responses_logged = || {
+            let events = capture.events();
+            events.iter().filter(|event| event.fields["message"].contains("finished processing")).count()
+        };
+
+        for i in 0..400 {
+            let request = Request::get("/").header("X-Trace-ID", format!("trace-{}", i));
+            send(&app, request.body(Body::empty()).unwrap()).await;
+        }
+        let logged = responses_logged();
+        assert!((60..=140).contains(&logged), "{} of 400 logged", logged);
+        assert_eq!(capture.spans("request").len(), 400);
+
+        // Server errors are logged regardless of sampling.
+        for _ in 0..5 {
+            send(&app, Request::get("/fail").body(Body::empty()).unwrap()).await;
+        }
+        assert_eq!(responses_logged(), logged + 5);
+    }
+
+    #[tokio::test]
+    async fn test_excluded_route_has_no_span() {
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let mut settings = test_settings();
+        settings.application.trace_exclude = vec!["GET /api/{key}".to_string()];
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        send(&app, Request::get("/api/key").body(Body::empty()).unwrap()).await;
+        assert!(capture.spans("request").is_empty());
+
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(capture.spans("request").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_hashed_in_span_uri() {
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let mut settings = test_settings();
+        settings.application.hash_keys_in_logs = true;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        send(&app, Request::get("/api/jane.doe@example.com/exists").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/api/jane.doe@example.com").body(Body::empty()).unwrap()).await;
+
+        let mut uris: Vec<_> = capture
+            .spans("request")
+            .into_iter()
+            .map(|span| span.fields["uri"].clone())
+            .collect();
+        uris.sort();
+        let [read_uri, exists_uri] = &uris[..] else {
+            panic!("Expected 2 spans, got {:?}", uris);
+        };
+        assert!(!exists_uri.contains("jane.doe"));
+        assert!(exists_uri.starts_with("/api/#") && exists_uri.ends_with("/exists"));
+        // The same key hashes to the same value.
+        assert_eq!(read_uri, exists_uri.trim_end_matches("/exists"));
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_method_is_rejected() {
+        let mut settings = test_settings();
+        settings.application.allowed_methods = vec!["GET".to_string(), "HEAD".to_string()];
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        let request = Request::post("/api/key")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"value": "value"}"#))
+            .unwrap();
+        let response = send(&app, request).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, HEAD");
+
+        let response = send(&app, Request::get("/api/key").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_headers_are_rejected() {
+        let mut settings = test_settings();
+        settings.application.max_header_bytes = 1024;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        let request = |cookie: String| {
+            Request::get("/api/key").header("Cookie", cookie).body(Body::empty()).unwrap()
+        };
+
+        let response = send(&app, request("a".repeat(2048))).await;
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        assert_eq!(body_string(response).await, "Request headers exceed the maximum size of 1024 bytes.");
+
+        let response = send(&app, request("a".repeat(512))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_excessive_query_params_are_rejected() {
+        let mut settings = test_settings();
+        settings.application.max_query_params = 4;
+        settings.application.max_query_bytes = 64;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        let request = |query: String| Request::get(format!("/api?{}", query)).body(Body::empty()).unwrap();
+
+        let query = (0..5).map(|i| format!("p{}=1", i)).collect::<Vec<_>>().join("&");
+        let response = send(&app, request(query)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Query string exceeds the maximum of 4 parameters.");
+
+        let response = send(&app, request(format!("cursor={}", "a".repeat(64)))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_string(response).await, "Query string exceeds the maximum size of 64 bytes.");
+
+        let response = send(&app, request("limit=2&offset=0".to_string())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_compression_level() {
+        // Compressible, but not so repetitive that every level yields the same output.
+        let value: String = (0..20_000).map(|i| format!("{} ", (i * 7919) % 1000)).collect();
+        let compressed_size = |level: i32| {
+            let value = value.clone();
+            async move {
+                let mut settings = test_settings();
+                settings.application.compression_level = Some(level);
+                let state = ApplicationState::new(Arc::new(settings));
+                state.db.write().unwrap().upsert(&"key".to_string(), value);
+                let request = Request::get("/api/key")
+                    .header("Accept-Encoding", "gzip")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = send(&build_app(state), request).await;
+                assert_eq!(response.headers()["Content-Encoding"], "gzip");
+                response.into_body().collect().await.unwrap().to_bytes().len()
+            }
+        };
+
+        let fastest = compressed_size(0).await;
+        let smallest = compressed_size(9).await;
+        assert!(smallest < fastest, "level 9: {} bytes, level 0: {} bytes", smallest, fastest);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_headers_from_trusted_proxy() {
+        let mut settings = test_settings();
+        settings.application.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        let app = Router::new()
+            .route(
+                "/",
+                get(|context: RequestContext| async move {
+                    format!("{:?} {}", context.client_ip, context.scheme)
+                }),
+            )
+            .layer(from_fn_with_state(Arc::new(settings), attach_request_context));
+        let request = |peer: [u8; 4]| {
+            let mut request = Request::get("/")
+                // The client prepended a spoofed address, then two trusted proxies appended theirs.
+                .header("X-Forwarded-For", "1.1.1.1, 203.0.113.7, 10.0.0.2")
+                .header("X-Forwarded-Proto", "https")
+                .body(Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from((peer, 4000))));
+            request
+        };
+
+        let response = send(&app, request([10, 0, 0, 1])).await;
+        assert_eq!(body_string(response).await, "Some(203.0.113.7) https");
+
+        // Headers from an untrusted peer are ignored.
+        let response = send(&app, request([198, 51, 100, 1])).await;
+        assert_eq!(body_string(response).await, "Some(198.51.100.1) http");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_api_keys_are_limited_by_ip() {
+        let mut settings = test_settings();
+        settings.application.max_concurrent_per_client = 1;
+        settings.application.client_api_keys = vec!["tenant".to_string()];
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let request = |api_key: &str| {
+            let mut request =
+                Request::get("/api/key").header("X-API-Key", api_key).body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([198, 51, 100, 1], 4000))));
+            request
+        };
+        let _permit = state.client_limiter.try_acquire("ip:198.51.100.1").unwrap();
+
+        let response = send(&app, request(&Uuid::new_v4().to_string())).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = send(&app, request("tenant")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(state.client_limiter.in_flight("key:tenant"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_content_length_mismatch_is_rejected() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let body = r#"{"value": "value"}"#;
+        let upsert = |content_length: usize| {
+            Request::post("/api/key")
+                .header("Content-Type", "application/json")
+                .header("Content-Length", content_length)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        for content_length in [body.len() - 1, body.len() + 1] {
+            let response = send(&app, upsert(content_length)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert!(body_string(response).await.contains("Body length does not match Content-Length"));
+        }
+        assert_eq!(send(&app, upsert(body.len())).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_exempt_routes() {
+        let mut settings = test_settings();
+        settings.application.request_timeout_s = 1;
+        settings.application.timeout_exempt_routes = vec!["GET /events".to_string()];
+        let state = ApplicationState::new(Arc::new(settings));
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(1_200)).await;
+            "done"
+        };
+        let app = Router::new()
+            .route("/slow", get(slow))
+            .route("/events", get(slow))
+            .add_middleware(state.config.clone())
+            .with_state(state);
+
+        let (slow, events) = tokio::join!(
+            send(&app, Request::get("/slow").body(Body::empty()).unwrap()),
+            send(&app, Request::get("/events").body(Body::empty()).unwrap()),
+        );
+        assert_eq!(slow.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(events.status(), StatusCode::OK);
+        assert_eq!(body_string(events).await, "done");
+    }
+
+    #[tokio::test]
+    async fn test_response_time_header() {
+        let mut settings = test_settings();
+        settings.application.response_time_header = true;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        let value = response.headers()["x-response-time-ms"].to_str().unwrap();
+        assert!(value.parse::<f64>().unwrap() >= 0.0);
+
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert!(!response.headers().contains_key("x-response-time-ms"));
+    }
+
+    #[tokio::test]
+    async fn test_envelope_responses() {
+        let mut settings = test_settings();
+        settings.application.envelope_responses = true;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        let request = |method: &str, uri: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .header("X-Trace-ID", "trace-1")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let envelope = |response: Response| async move {
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+            serde_json::from_str::<serde_json::Value>(&body_string(response).await).unwrap()
+        };
+
+        // JSON bodies are embedded as JSON.
+        let response = send(&app, request("POST", "/api/key?return_prev=true", r#"{"value":"first"}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            envelope(response).await,
+            serde_json::json!({ "data": { "previous": null }, "error": null, "trace_id": "trace-1" })
+        );
+
+        // Text bodies are embedded as strings.
+        let response = send(&app, request("GET", "/api/key", "")).await;
+        assert_eq!(
+            envelope(response).await,
+            serde_json::json!({ "data": "first", "error": null, "trace_id": "trace-1" })
+        );
+
+        // Errors carry their body, or the status reason if empty, in `error`.
+        let response = send(&app, request("POST", "/api/key", "")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = envelope(response).await;
+        assert_eq!(body["data"], serde_json::Value::Null);
+        assert_eq!(body["error"]["message"], "Request body is required.");
+        assert_eq!(body["trace_id"], "trace-1");
+
+        let response = send(&app, request("GET", "/api/missing", "")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            envelope(response).await,
+            serde_json::json!({ "data": null, "error": { "message": "Not Found" }, "trace_id": "trace-1" })
+        );
+
+        // Excluded routes are left as-is.
+        let response = send(&app, request("GET", "/metrics", "")).await;
+        assert!(!body_string(response).await.starts_with(r#"{"data""#));
+    }
+
+    #[tokio::test]
+    async fn test_route_timeouts() {
+        let mut settings = test_settings();
+        settings.application.request_timeout_s = 1;
+        // The pattern with a method wins over the same route without one.
+        settings.application.route_timeouts_s =
+            HashMap::from([("/export".to_string(), 1), ("GET /export".to_string(), 5)]);
+        let state = ApplicationState::new(Arc::new(settings));
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(1_200)).await;
+            "done"
+        };
+        let app = Router::new()
+            .route("/read", get(slow))
+            .route("/export", get(slow))
+            .add_middleware(state.config.clone())
+            .with_state(state);
+
+        let (read, export) = tokio::join!(
+            send(&app, Request::get("/read").body(Body::empty()).unwrap()),
+            send(&app, Request::get("/export").body(Body::empty()).unwrap()),
+        );
+        assert_eq!(read.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(export.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_canary_fraction() {
+        let tagged = (0..10_000)
+            .filter(|_| is_canary(&Uuid::new_v4().to_string(), 25.0))
+            .count();
+        assert!((2_200..=2_800).contains(&tagged), "{} of 10000 tagged", tagged);
+
+        assert!(!is_canary("trace-1", 0.0));
+        assert!(is_canary("trace-1", 100.0));
+    }
+
+    #[test]
+    fn test_canary_bucket_is_stable() {
+        // FNV-1a of "trace-1" is 0xd6949429a97ccbb8.
+        assert_eq!(canary_bucket("trace-1"), 3496);
+        assert!(is_canary("trace-1", 34.97));
+        assert!(!is_canary("trace-1", 34.96));
+    }
+
+    #[tokio::test]
+    async fn test_canary_flag_is_exposed() {
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let mut settings = test_settings();
+        settings.application.canary_percent = 100.0;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = Router::new()
+            .route("/", get(|context: RequestContext| async move { context.canary.to_string() }))
+            .add_middleware(state.config.clone())
+            .with_state(state);
+
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, "true");
+        assert_eq!(capture.spans("request")[0].fields["canary"], "true");
+    }
+
+    #[tokio::test]
+    async fn test_tower_error_codes() {
+        for (error, status, code) in [
+            (BoxError::from(tower::timeout::error::Elapsed::new()), StatusCode::REQUEST_TIMEOUT, "timeout"),
+            (tower::load_shed::error::Overloaded::new().into(), StatusCode::SERVICE_UNAVAILABLE, "overloaded"),
+            (AcquireError::QueueFull.into(), StatusCode::SERVICE_UNAVAILABLE, "queue_full"),
+            ("unexpected".into(), StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+        ] {
+            let response = handle_tower_error(error).await.into_response();
+            assert_eq!(response.status(), status);
+            let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+            assert_eq!(body["code"], code);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use crate::repo::db::KVDatabase;
+use std::hash::Hash;
+use std::time::Duration;
+use tracing::warn;
+
+/// Backends a `ChainedDatabase` writes to.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ChainWrites {
+    /// Writes go to every available backend, so any of them can serve reads when the others fail.
+    All,
+    /// Writes only go to the first available backend.
+    Primary,
+}
+
+/// Database that chains an ordered list of backends, e.g. a remote primary with an in-memory fallback.
+///
+/// Operations skip backends that aren't healthy, logging which one failed. Reads are served by the first
+/// available backend, and writes go to the backends selected by `ChainWrites`.
+pub struct ChainedDatabase<K, V> {
+    backends: Vec<Box<dyn KVDatabase<K, V>>>,
+    writes: ChainWrites,
+}
+
+impl<K: Eq + Hash + Clone + Send + Sync, V: Clone + Send + Sync> ChainedDatabase<K, V> {
+    /// Creates a chain of backends.
+    /// # Arguments
+    /// * `backends`: The backends in order of preference, starting with the primary.
+    /// * `writes`: Which backends writes go to.
+    pub fn new(backends: Vec<Box<dyn KVDatabase<K, V>>>, writes: ChainWrites) -> Self {
+        ChainedDatabase { backends, writes }
+    }
+
+    /// Returns the first healthy backend, logging the ones that are skipped.
+    fn readable(&self, operation: &str) -> Option<&dyn KVDatabase<K, V>> {
+        for backend in &self.backends {
+            if backend.is_healthy() {
+                return Some(backend.as_ref());
+            }
+            warn!("Backend {} is unavailable, trying the next one for {}...", backend.name(), operation);
+        }
+        warn!("No backend is available for {}.", operation);
+        None
+    }
+
+    /// Returns the healthy backends that writes go to, logging the ones that are skipped.
+    fn writable(&mut self, operation: &str) -> Vec<&mut Box<dyn KVDatabase<K, V>>> {
+        let mut backends = Vec::new();
+        for backend in &mut self.backends {
+            if !backend.is_healthy() {
+                warn!("Backend {} is unavailable, skipping {}...", backend.name(), operation);
+                continue;
+            }
+            backends.push(backend);
+            if self.writes == ChainWrites::Primary {
+                break;
+            }
+        }
+        if backends.is_empty() {
+            warn!("No backend is available for {}.", operation);
+        }
+        backends
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send + Sync, V: Clone + Send + Sync> KVDatabase<K, V> for ChainedDatabase<K, V> {
+    fn upsert(&mut self, key: &K, value: V) {
+        for backend in self.writable("upsert") {
+            backend.upsert(key, value.clone());
+        }
+    }
+
+    // Note: The previous value is the one in the first backend written to, which also serves reads.
+    fn swap(&mut self, key: &K, value: V) -> Option<V> {
+        let mut previous = None;
+        for (i, backend) in self.writable("swap").into_iter().enumerate() {
+            let replaced = backend.swap(key, value.clone());
+            if i == 0 {
+                previous = replaced;
+            }
+        }
+        previous
+    }
+
+    fn read(&self, key: &K) -> Option<V> {
+        self.readable("read")?.read(key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.readable("contains_key").is_some_and(|backend| backend.contains_key(key))
+    }
+
+    fn len(&self) -> usize {
+        self.readable("len").map_or(0, |backend| backend.len())
+    }
+
+    fn remove(&self, key: &K) {
+        // Note: `remove` takes `&self`, so it always goes to every available backend.
+        for backend in self.backends.iter().filter(|backend| backend.is_healthy()) {
+            backend.remove(key);
+        }
+    }
+
+    fn update(&mut self, key: &K, new_value: V) {
+        for backend in self.writable("update") {
+            backend.update(key, new_value.clone());
+        }
+    }
+
+    fn touch(&mut self, key: &K, ttl: Duration) -> bool {
+        // Note: Every backend is touched, so this can't short-circuit like `any`.
+        let mut touched = false;
+        for backend in self.writable("touch") {
+            touched |= backend.touch(key, ttl);
+        }
+        touched
+    }
+
+    fn ttl(&self, key: &K) -> Option<Option<Duration>> {
+        self.readable("ttl")?.ttl(key)
+    }
+
+    fn get_many_ref(&self, keys: &[K], visit: &mut dyn FnMut(&K, Option<&V>)) {
+        match self.readable("get_many_ref") {
+            Some(backend) => backend.get_many_ref(keys, visit),
+            None => keys.iter().for_each(|key| visit(key, None)),
+        }
+    }
+
+    fn count_by_prefix(&self, prefix: &K) -> usize
+    where
+        K: AsRef<str>,
+    {
+        self.readable("count_by_prefix").map_or(0, |backend| backend.count_by_prefix(prefix))
+    }
+
+    // Note: The count is the one in the first backend written to, which also serves reads.
+    fn clear_prefix(&mut self, prefix: &K) -> usize
+    where
+        K: AsRef<str>,
+    {
+        let mut removed = 0;
+        for (i, backend) in self.writable("clear_prefix").into_iter().enumerate() {
+            let cleared = backend.clear_prefix(prefix);
+            if i == 0 {
+                removed = cleared;
+            }
+        }
+        removed
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.readable("keys").map(|backend| backend.keys()).unwrap_or_default()
+    }
+
+    fn entries(&self) -> Vec<(K, V, Option<Duration>)> {
+        self.readable("entries").map(|backend| backend.entries()).unwrap_or_default()
+    }
+
+    fn stored_entries(&self) -> Vec<(K, V, Option<Duration>)> {
+        self.readable("stored_entries").map(|backend| backend.stored_entries()).unwrap_or_default()
+    }
+
+    fn upsert_stored(&mut self, key: &K, value: V) {
+        for backend in self.writable("upsert_stored") {
+            backend.upsert_stored(key, value.clone());
+        }
+    }
+
+    fn stored_bytes(&self) -> u64
+    where
+        V: AsRef<[u8]>,
+    {
+        self.readable("stored_bytes").map_or(0, |backend| backend.stored_bytes())
+    }
+
+    fn memory_bytes(&self) -> Option<u64> {
+        self.readable("memory_bytes")?.memory_bytes()
+    }
+
+    fn poison_recoveries(&self) -> Option<u64> {
+        self.readable("poison_recoveries")?.poison_recoveries()
+    }
+
+    fn purge_expired(&mut self) -> usize {
+        // Note: Each backend holds its own expired entries, so every available one is purged.
+        let mut purged = 0;
+        for backend in self.backends.iter_mut().filter(|backend| backend.is_healthy()) {
+            purged += backend.purge_expired();
+        }
+        purged
+    }
+
+    // Note: Evicts from the first backend written to, which also serves reads.
+    fn evict_lru(&mut self) -> Option<K> {
+        self.writable("evict_lru").into_iter().next()?.evict_lru()
+    }
+
+    fn name(&self) -> &'static str {
+        "Chained"
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.backends.iter().any(|backend| backend.is_healthy())
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::db::InMemoryDatabase;
+    use crate::test_util::TestDatabase;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn chain(writes: ChainWrites) -> (ChainedDatabase<String, String>, Arc<AtomicBool>) {
+        let up = Arc::new(AtomicBool::new(true));
+        let flag = up.clone();
+        // Note: Taken down by the test, e.g. to simulate a lost connection.
+        let primary = TestDatabase::default().healthy_when(move || flag.load(Ordering::SeqCst));
+        let chain = ChainedDatabase::new(vec![Box::new(primary), Box::new(InMemoryDatabase::new())], writes);
+        (chain, up)
+    }
+
+    #[test]
+    fn test_reads_fall_back_to_secondary() {
+        let (mut db, primary_up) = chain(ChainWrites::All);
+        let key = String::from("key");
+        db.upsert(&key, String::from("value"));
+
+        primary_up.store(false, Ordering::SeqCst);
+        assert!(db.is_healthy());
+        assert_eq!(db.read(&key), Some(String::from("value")));
+
+        // Writes while the primary is down only reach the secondary.
+        db.upsert(&key, String::from("new"));
+        assert_eq!(db.read(&key), Some(String::from("new")));
+        primary_up.store(true, Ordering::SeqCst);
+        assert_eq!(db.read(&key), Some(String::from("value")));
+    }
+
+    #[test]
+    fn test_primary_only_writes() {
+        let (mut db, primary_up) = chain(ChainWrites::Primary);
+        let key = String::from("key");
+        db.upsert(&key, String::from("value"));
+        assert_eq!(db.read(&key), Some(String::from("value")));
+
+        // The secondary never received the write.
+        primary_up.store(false, Ordering::SeqCst);
+        assert_eq!(db.read(&key), None);
+    }
+}
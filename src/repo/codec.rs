@@ -0,0 +1,315 @@
+use crate::configuration::{ApplicationSettings, ValueCodecKind};
+use crate::middleware::hash_for_logs;
+use crate::repo::db::KVDatabase;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+use std::time::Duration;
+use thiserror::Error;
+use tracing::error;
+
+/// Length in bytes of the nonce prepended to values encrypted by `AesCodec`.
+const NONCE_LENGTH: usize = 12;
+
+/// Reasons for failing to set up a codec or to decode a stored value.
+#[derive(Debug, Error, PartialEq)]
+pub enum CodecError {
+    #[error("application.value_codec_key must be set to a base64-encoded 32-byte key for the aes codec.")]
+    MissingKey,
+    #[error("application.value_codec_key must be a base64-encoded 32-byte key.")]
+    InvalidKey,
+    #[error("Stored value is not valid base64.")]
+    InvalidEncoding,
+    #[error("Stored value can't be decompressed: {0}")]
+    Decompression(String),
+    #[error("Stored value can't be decrypted.")]
+    Decryption,
+}
+
+/// Transforms values before they're written to the backend, and back after they're read, e.g. to compress
+/// or encrypt values at rest.
+///
+/// The key of the value is passed along, so codecs can bind the stored form to it.
+pub trait ValueCodec: Send + Sync {
+    /// Transforms the value of a key into its stored form.
+    fn encode(&self, key: &str, value: &str) -> String;
+
+    /// Transforms the stored value of a key back into the value that was written.
+    fn decode(&self, key: &str, stored: &str) -> Result<String, CodecError>;
+}
+
+/// Codec that stores values as-is.
+pub struct IdentityCodec;
+
+impl ValueCodec for IdentityCodec {
+    fn encode(&self, _key: &str, value: &str) -> String {
+        value.to_string()
+    }
+
+    fn decode(&self, _key: &str, stored: &str) -> Result<String, CodecError> {
+        Ok(stored.to_string())
+    }
+}
+
+/// Codec that stores values gzip-compressed, in base64 since values are strings.
+pub struct GzipCodec;
+
+impl ValueCodec for GzipCodec {
+    fn encode(&self, _key: &str, value: &str) -> String {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        // Note: Writing to a `Vec` never fails.
+        let _ = encoder.write_all(value.as_bytes());
+        BASE64.encode(encoder.finish().unwrap_or_default())
+    }
+
+    fn decode(&self, _key: &str, stored: &str) -> Result<String, CodecError> {
+        let compressed = BASE64.decode(stored).map_err(|_| CodecError::InvalidEncoding)?;
+        let mut value = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut value)
+            .map_err(|err| CodecError::Decompression(err.to_string()))?;
+        Ok(value)
+    }
+}
+
+/// Codec that stores values encrypted with AES-256-GCM, as base64 of a random nonce followed by the ciphertext.
+///
+/// The key of the value is authenticated as associated data, so a stored value copied to another key fails to
+/// decrypt rather than being returned for it.
+pub struct AesCodec {
+    cipher: Aes256Gcm,
+}
+
+impl AesCodec {
+    /// Creates the codec from a base64-encoded 32-byte key.
+    pub fn new(key: &str) -> Result<Self, CodecError> {
+        let key = BASE64.decode(key).map_err(|_| CodecError::InvalidKey)?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| CodecError::InvalidKey)?;
+        Ok(AesCodec { cipher })
+    }
+}
+
+impl ValueCodec for AesCodec {
+    fn encode(&self, key: &str, value: &str) -> String {
+        // Note: A fresh nonce per value, since reusing one with the same key breaks GCM's confidentiality.
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: value.as_bytes(), aad: key.as_bytes() })
+            .expect("Encrypting an in-memory buffer doesn't fail");
+        BASE64.encode([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn decode(&self, key: &str, stored: &str) -> Result<String, CodecError> {
+        let bytes = BASE64.decode(stored).map_err(|_| CodecError::InvalidEncoding)?;
+        if bytes.len() < NONCE_LENGTH {
+            return Err(CodecError::Decryption);
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        let value = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: key.as_bytes() })
+            .map_err(|_| CodecError::Decryption)?;
+        String::from_utf8(value).map_err(|_| CodecError::Decryption)
+    }
+}
+
+/// Creates the codec selected by `ApplicationSettings::value_codec`.
+pub fn codec_from_settings(config: &ApplicationSettings) -> Result<Box<dyn ValueCodec>, CodecError> {
+    Ok(match config.value_codec {
+        ValueCodecKind::Identity => Box::new(IdentityCodec),
+        ValueCodecKind::Gzip => Box::new(GzipCodec),
+        ValueCodecKind::Aes => Box::new(AesCodec::new(
+            config.value_codec_key.as_deref().ok_or(CodecError::MissingKey)?,
+        )?),
+    })
+}
+
+/// Database that encodes values with a `ValueCodec` before writing them to a backend, and decodes them
+/// transparently on reads.
+///
+/// Stored values that fail to decode, e.g. after the key changed, are logged and treated as missing.
+/// Sizes, e.g. `stored_bytes`, are the sizes of the stored values. `entries` returns decoded values, while
+/// `stored_entries` returns the stored ones, so snapshots are written encoded.
+pub struct EncodedDatabase {
+    inner: Box<dyn KVDatabase<String, String>>,
+    codec: Box<dyn ValueCodec>,
+    /// Whether keys are hashed in logs, see `ApplicationSettings::hash_keys_in_logs`.
+    hash_keys_in_logs: bool,
+}
+
+impl EncodedDatabase {
+    pub fn new(inner: Box<dyn KVDatabase<String, String>>, codec: Box<dyn ValueCodec>) -> Self {
+        EncodedDatabase {
+            inner,
+            codec,
+            hash_keys_in_logs: false,
+        }
+    }
+
+    /// Sets whether keys are hashed in the logs of the database, see `ApplicationSettings::hash_keys_in_logs`.
+    pub fn hash_keys_in_logs(mut self, enabled: bool) -> Self {
+        self.hash_keys_in_logs = enabled;
+        self
+    }
+
+    fn decode(&self, key: &str, stored: &str) -> Option<String> {
+        self.codec
+            .decode(key, stored)
+            .inspect_err(|err| {
+                let key = if self.hash_keys_in_logs { hash_for_logs(key) } else { key.to_string() };
+                error!("Failed to decode the value of key '{}': {}", key, err)
+            })
+            .ok()
+    }
+}
+
+impl KVDatabase<String, String> for EncodedDatabase {
+    fn upsert(&mut self, key: &String, value: String) {
+        self.inner.upsert(key, self.codec.encode(key, &value));
+    }
+
+    fn swap(&mut self, key: &String, value: String) -> Option<String> {
+        let previous = self.inner.swap(key, self.codec.encode(key, &value))?;
+        self.decode(key, &previous)
+    }
+
+    fn read(&self, key: &String) -> Option<String> {
+        let stored = self.inner.read(key)?;
+        self.decode(key, &stored)
+    }
+
+    fn contains_key(&self, key: &String) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn remove(&self, key: &String) {
+        self.inner.remove(key)
+    }
+
+    fn update(&mut self, key: &String, new_value: String) {
+        self.inner.update(key, self.codec.encode(key, &new_value))
+    }
+
+    fn touch(&mut self, key: &String, ttl: Duration) -> bool {
+        self.inner.touch(key, ttl)
+    }
+
+    fn get_many_ref(&self, keys: &[String], visit: &mut dyn FnMut(&String, Option<&String>)) {
+        // Note: Values are decoded into owned strings, so only the references handed to `visit` are borrowed.
+        self.inner.get_many_ref(keys, &mut |key, stored| {
+            let value = stored.and_then(|stored| self.decode(key, stored));
+            visit(key, value.as_ref());
+        });
+    }
+
+    fn ttl(&self, key: &String) -> Option<Option<Duration>> {
+        self.inner.ttl(key)
+    }
+
+    fn count_by_prefix(&self, prefix: &String) -> usize {
+        self.inner.count_by_prefix(prefix)
+    }
+
+    fn clear_prefix(&mut self, prefix: &String) -> usize {
+        self.inner.clear_prefix(prefix)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.inner.keys()
+    }
+
+    fn entries(&self) -> Vec<(String, String, Option<Duration>)> {
+        self.inner
+            .entries()
+            .into_iter()
+            .filter_map(|(key, stored, ttl)| Some((key.clone(), self.decode(&key, &stored)?, ttl)))
+            .collect()
+    }
+
+    fn stored_entries(&self) -> Vec<(String, String, Option<Duration>)> {
+        self.inner.stored_entries()
+    }
+
+    fn upsert_stored(&mut self, key: &String, value: String) {
+        self.inner.upsert_stored(key, value)
+    }
+
+    fn stored_bytes(&self) -> u64 {
+        self.inner.stored_bytes()
+    }
+
+    fn memory_bytes(&self) -> Option<u64> {
+        self.inner.memory_bytes()
+    }
+
+    fn purge_expired(&mut self) -> usize {
+        self.inner.purge_expired()
+    }
+
+    fn evict_lru(&mut self) -> Option<String> {
+        self.inner.evict_lru()
+    }
+
+    fn poison_recoveries(&self) -> Option<u64> {
+        self.inner.poison_recoveries()
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.inner.is_healthy()
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDatabase;
+
+    fn round_trip(codec: Box<dyn ValueCodec>) {
+        let backend = TestDatabase::default();
+        let mut db = EncodedDatabase::new(Box::new(backend.clone()), codec);
+        let key = String::from("key");
+        let value = "a value that compresses, a value that compresses, a value that compresses".to_string();
+
+        db.upsert(&key, value.clone());
+        assert_eq!(db.read(&key), Some(value.clone()));
+        assert_ne!(backend.stored().read(&key), Some(value.clone()));
+        assert_eq!(db.swap(&key, "new".to_string()), Some(value));
+        assert_eq!(db.entries()[0].1, "new");
+    }
+
+    #[test]
+    fn test_gzip_codec() {
+        round_trip(Box::new(GzipCodec));
+    }
+
+    #[test]
+    fn test_aes_codec() {
+        let key = BASE64.encode([7u8; 32]);
+        round_trip(Box::new(AesCodec::new(&key).unwrap()));
+
+        // A different key can't decrypt the value.
+        let codec = AesCodec::new(&key).unwrap();
+        let stored = codec.encode("key", "secret");
+        let other = AesCodec::new(&BASE64.encode([8u8; 32])).unwrap();
+        assert_eq!(other.decode("key", &stored), Err(CodecError::Decryption));
+        // Nor can the value be moved to another key.
+        assert_eq!(codec.decode("other", &stored), Err(CodecError::Decryption));
+        assert!(AesCodec::new(&BASE64.encode([7u8; 16])).is_err());
+    }
+}
@@ -1,6 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
 use std::hash::Hash;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+// Note: Tokio's clock is the system clock, except in tests that pause it to control expiry.
+use tokio::time::Instant;
+use tracing::warn;
+use crate::metrics::Histogram;
+use crate::middleware::hash_for_logs;
 
 /// InMemoryDatabase is a simple in-memory key-value store for testing.
 #[derive(Default, Debug)]
@@ -12,12 +20,65 @@ pub struct InMemoryDatabase<K, V> {
     // Note:
     //  - `Arc`: Atomic reference counting, allowing shared ownership of the map across threads.
     //  - `RwLock`: Provides read-write locks, allowing multiple readers or one writer at a time.
-    map: Arc<RwLock<HashMap<K, V>>>, // Note: Fields are private by default
+    map: Arc<RwLock<HashMap<K, Entry<V>>>>, // Note: Fields are private by default
+    /// Number of times an operation recovered from a poisoned lock.
+    poison_recoveries: AtomicU64,
+    /// Approximate size of the stored keys and values in bytes, including expired entries not purged yet.
+    bytes: AtomicU64,
+    /// Logical clock that orders accesses, to find the least recently used entry.
+    clock: AtomicU64,
+    /// Keys by the logical time they were queued at, oldest first, so `evict_lru` doesn't scan the map.
+    // Note: Only changed under the write lock of the map, so the mutex is never contended. Reads don't requeue
+    //  their key, which would need a lock on the read path, so `evict_lru` requeues keys read since instead.
+    recency: Mutex<BTreeMap<u64, K>>,
+    /// Whether keys are hashed in logs, see `ApplicationSettings::hash_keys_in_logs`.
+    hash_keys_in_logs: bool,
+    /// Histogram that records the size of each written value, if any.
+    value_sizes: Option<Arc<Histogram>>,
+}
+
+/// A stored value and its expiry.
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    /// When the entry expires, or `None` if it never does.
+    expires_at: Option<Instant>,
+    /// Logical time of the last write or read.
+    // Note: Atomic, so reads can record their access under the read lock.
+    last_used: AtomicU64,
+    /// Logical time the key is queued at in `InMemoryDatabase::recency`.
+    queued_at: u64,
+}
+
+impl<V> Entry<V> {
+    fn new(value: V, now: u64) -> Self {
+        Entry {
+            value,
+            expires_at: None,
+            last_used: AtomicU64::new(now),
+            queued_at: now,
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| Instant::now() < expires_at)
+    }
 }
 
 // Note: `Send` and `Sync` traits are used to ensure that the database can be used across threads:
 //  - `Send`: Allows the type to be transferred between threads.
 //  - `Sync`: Allows the type to be referenced from multiple threads.
+/// Approximate size of a key or value in memory, for `KVDatabase::memory_bytes`.
+pub trait ByteSize {
+    fn byte_size(&self) -> usize;
+}
+
+impl ByteSize for String {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
 /// Database trait that defines the interface for accessing a key-value store.
 pub trait KVDatabase<K: Eq + Hash + Clone + Send + Sync, V: Clone + Send + Sync> : Send + Sync {
     /// Insert a key-value pair into the database, or update existing key with the new value.
@@ -26,6 +87,14 @@ pub trait KVDatabase<K: Eq + Hash + Clone + Send + Sync, V: Clone + Send + Sync>
     /// * `value`: The value to insert.
     fn upsert(&mut self, key: &K, value: V);
 
+    /// Insert a key-value pair into the database, returning the value it replaced.
+    /// # Arguments
+    /// * `key`: The key to insert.
+    /// * `value`: The value to insert.
+    /// # Returns
+    /// * `Option<V>`: The previous value associated with the key, or `None` if the key is new.
+    fn swap(&mut self, key: &K, value: V) -> Option<V>;
+
     /// Read a value by key from the database.
     /// # Arguments
     /// * `key`: The key to read.
@@ -33,6 +102,19 @@ pub trait KVDatabase<K: Eq + Hash + Clone + Send + Sync, V: Clone + Send + Sync>
     /// * `Option<V>`: The value associated with the key, or `None` if the key does not exist.
     fn read(&self, key: &K) -> Option<V>;
 
+    /// Check whether a key exists in the database, without cloning its value.
+    /// # Arguments
+    /// * `key`: The key to look up.
+    fn contains_key(&self, key: &K) -> bool;
+
+    /// Number of entries in the database.
+    fn len(&self) -> usize;
+
+    /// Whether the database has no entries.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Remove a key-value pair from the database.
     /// # Arguments
     /// * `key`: The key to remove.
@@ -43,6 +125,115 @@ pub trait KVDatabase<K: Eq + Hash + Clone + Send + Sync, V: Clone + Send + Sync>
     /// * `key`: The key to update.
     /// * `new_value`: The new value to associate with the key.
     fn update(&mut self, key: &K, new_value: V);
+
+    /// Reset the expiry of an existing key, without rewriting its value.
+    /// # Arguments
+    /// * `key`: The key to refresh.
+    /// * `ttl`: Time from now after which the key expires.
+    /// # Returns
+    /// * `bool`: Whether the key exists.
+    fn touch(&mut self, key: &K, ttl: Duration) -> bool;
+
+    /// Calls `visit` with each key and a reference to its value, or `None` if it doesn't exist, without
+    /// cloning the values, e.g. to serialize a multi-get directly into the response.
+    ///
+    /// Backends that hold a lock should visit all keys under a single acquisition, so `visit` must not call
+    /// back into the database.
+    /// # Arguments
+    /// * `keys`: The keys to read, in the order they are visited.
+    /// * `visit`: Called once per key.
+    fn get_many_ref(&self, keys: &[K], visit: &mut dyn FnMut(&K, Option<&V>)) {
+        for key in keys {
+            visit(key, self.read(key).as_ref());
+        }
+    }
+
+    /// Remaining time to live of a key.
+    /// # Returns
+    /// * `Option<Option<Duration>>`: `None` if the key doesn't exist, `Some(None)` if it never expires.
+    fn ttl(&self, key: &K) -> Option<Option<Duration>>;
+
+    /// Number of keys that haven't expired and start with a prefix.
+    /// # Arguments
+    /// * `prefix`: The prefix to match. An empty prefix matches all keys.
+    fn count_by_prefix(&self, prefix: &K) -> usize
+    where
+        K: AsRef<str>;
+
+    /// Remove all keys that start with a prefix, e.g. to clean up everything stored under a scope.
+    ///
+    /// Backends that hold a lock should remove all keys under a single acquisition.
+    /// # Arguments
+    /// * `prefix`: The prefix to match. An empty prefix matches all keys.
+    /// # Returns
+    /// * `usize`: Number of keys removed that hadn't expired.
+    fn clear_prefix(&mut self, prefix: &K) -> usize
+    where
+        K: AsRef<str>;
+
+    /// All keys that haven't expired, in no particular order.
+    fn keys(&self) -> Vec<K>;
+
+    /// All entries that haven't expired, e.g. to take a snapshot.
+    /// # Returns
+    /// * `Vec<(K, V, Option<Duration>)>`: The key, value and remaining time to live of each entry.
+    fn entries(&self) -> Vec<(K, V, Option<Duration>)>;
+
+    /// All entries that haven't expired, with values in the form they're stored in, e.g. encoded by a
+    /// `ValueCodec`, so a snapshot doesn't hold them in plain text. Restored with `upsert_stored`.
+    /// # Returns
+    /// * `Vec<(K, V, Option<Duration>)>`: The key, stored value and remaining time to live of each entry.
+    fn stored_entries(&self) -> Vec<(K, V, Option<Duration>)> {
+        self.entries()
+    }
+
+    /// Writes a value in the form it's stored in, as returned by `stored_entries`, e.g. to load a snapshot.
+    fn upsert_stored(&mut self, key: &K, value: V) {
+        self.upsert(key, value)
+    }
+
+    /// Total size in bytes of the values that haven't expired.
+    fn stored_bytes(&self) -> u64
+    where
+        V: AsRef<[u8]>;
+
+    /// Approximate memory used by the keys and values in bytes.
+    /// # Returns
+    /// * `Option<u64>`: The size, or `None` if the backend doesn't track it.
+    fn memory_bytes(&self) -> Option<u64> {
+        None
+    }
+
+    /// Removes the entries that expired, which are otherwise only dropped when their key is written, to free
+    /// their memory.
+    /// # Returns
+    /// * `usize`: Number of entries removed.
+    fn purge_expired(&mut self) -> usize {
+        0
+    }
+
+    /// Removes the least recently read or written entry, e.g. to make room under a memory limit.
+    /// # Returns
+    /// * `Option<K>`: The evicted key, or `None` if the database is empty or doesn't support eviction.
+    fn evict_lru(&mut self) -> Option<K> {
+        None
+    }
+
+    /// Number of times an operation recovered from a poisoned lock, i.e. ran on data a panicking writer may
+    /// have left inconsistent.
+    /// # Returns
+    /// * `Option<u64>`: The count, or `None` if the backend doesn't track it.
+    fn poison_recoveries(&self) -> Option<u64> {
+        None
+    }
+
+    /// Name of the backend, e.g. `InMemory`.
+    fn name(&self) -> &'static str;
+
+    /// Whether the backend is able to serve requests.
+    fn is_healthy(&self) -> bool {
+        true
+    }
 }
 
 // Note: Struct-specific methods are defined in the `impl` block. You can extend an external type / struct
@@ -50,55 +241,235 @@ pub trait KVDatabase<K: Eq + Hash + Clone + Send + Sync, V: Clone + Send + Sync>
 //       Generic bounds are defined in the `impl` block header. Rust emphases zero-cost abstractions
 //       and expressiveness, so generic definitions can be long. Trait objects (dyn Trait) is a slightly
 //       more costly way to
-impl<K: Eq + Hash + Clone + Send + Sync, V: Clone + Send + Sync> KVDatabase<K, V> for InMemoryDatabase<K, V> {
+impl<K, V> KVDatabase<K, V> for InMemoryDatabase<K, V>
+where
+    K: Eq + Hash + Clone + Debug + Send + Sync + ByteSize,
+    V: Clone + Send + Sync + ByteSize,
+{
     fn upsert(&mut self, key: &K, value: V) {
-        // Note: No need to clone `Arc<T>` explicitly as it implements the `Deref` trait:
-        //       https://doc.rust-lang.org/std/sync/struct.Arc.html#deref-behavior
-        let mut map = self
-            .map
-            .write()
-            // Note: This is just a hacky way to bypass mutex poisoning for demo purposes.
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut map = self.write_map("upsert", Some(key));
 
-        map.insert(key.clone(), value);
+        self.observe_value_size(&value);
+        self.add_bytes(key.byte_size() + value.byte_size());
+        // Note: Like a rewrite in Redis, this clears any expiry set on the key.
+        if let Some(old) = map.insert(key.clone(), self.new_entry(key, value)) {
+            self.removed(key, &old);
+        }
+    }
+
+    fn swap(&mut self, key: &K, value: V) -> Option<V> {
+        let mut map = self.write_map("swap", Some(key));
+
+        self.observe_value_size(&value);
+        self.add_bytes(key.byte_size() + value.byte_size());
+        let old = map.insert(key.clone(), self.new_entry(key, value));
+        if let Some(old) = &old {
+            self.removed(key, old);
+        }
+        old.filter(Entry::is_live).map(|entry| entry.value)
     }
 
     // Note: `Option<V>` is an enum that can be `Some(value)` or `None`. There's no `null` in Rust.
     fn read(&self, key: &K) -> Option<V> {
-        
-        
-        let map = self
-            .map
-            .read()
-            // Note: This is just a hacky way to bypass mutex poisoning for demo purposes.
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let map = self.read_map("read", Some(key));
+
+        // Note: Expired entries are treated as absent, and dropped on write or by `purge_expired`.
+        map.get(key)
+            .filter(|entry| entry.is_live())
+            .inspect(|entry| entry.last_used.store(self.tick(), Ordering::Relaxed))
+            .map(|entry| entry.value.clone()) // Note: Not having ending colon means the function returns this value.
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        let map = self.read_map("contains_key", Some(key));
+
+        map.get(key).is_some_and(Entry::is_live)
+    }
 
-        map.get(key).cloned() // Note: Not having ending colon means the function returns this value.
+    fn len(&self) -> usize {
+        self.read_map("len", None).values().filter(|entry| entry.is_live()).count()
     }
 
     fn remove(&self, key: &K) {
-        let mut map = self
-            .map
-            .write()
-            // Note: This is just a hacky way to bypass mutex poisoning for demo purposes.
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut map = self.write_map("remove", Some(key));
 
-        map.remove(key);
+        if let Some(old) = map.remove(key) {
+            self.removed(key, &old);
+        }
     }
 
     fn update(&mut self, key: &K, new_value: V) {
-        let mut map = self
-            .map
-            .write()
-            // Note: This is just a hacky way to bypass mutex poisoning for demo purposes.
-            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut map = self.write_map("update", Some(key));
 
-        // Update if the key exists.
-        // Note: Unstable API `raw_entry` to avoid cloning the key.
+        self.drop_expired(&mut map, key);
+        // Update if the key exists, keeping its expiry.
+        // Note: `get_mut` avoids cloning the key, unlike the `entry` API.
         //  https://users.rust-lang.org/t/avoid-unnecessary-key-clone-when-accessing-hashmap-entry/33642
-        map.entry(key.clone()).and_modify(|old| {
-            *old = new_value;
+        if let Some(old) = map.get_mut(key) {
+            self.observe_value_size(&new_value);
+            self.add_bytes(new_value.byte_size());
+            self.sub_bytes(old.value.byte_size());
+            old.value = new_value;
+            let now = self.tick();
+            old.last_used.store(now, Ordering::Relaxed);
+            let mut recency = self.recency();
+            recency.remove(&old.queued_at);
+            recency.insert(now, key.clone());
+            old.queued_at = now;
+        }
+    }
+
+    fn touch(&mut self, key: &K, ttl: Duration) -> bool {
+        let mut map = self.write_map("touch", Some(key));
+
+        self.drop_expired(&mut map, key);
+        match map.get_mut(key) {
+            Some(entry) => {
+                entry.expires_at = Some(Instant::now() + ttl);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn count_by_prefix(&self, prefix: &K) -> usize
+    where
+        K: AsRef<str>,
+    {
+        let map = self.read_map("count_by_prefix", Some(prefix));
+
+        map.iter()
+            .filter(|(key, entry)| key.as_ref().starts_with(prefix.as_ref()) && entry.is_live())
+            .count()
+    }
+
+    fn clear_prefix(&mut self, prefix: &K) -> usize
+    where
+        K: AsRef<str>,
+    {
+        let mut map = self.write_map("clear_prefix", Some(prefix));
+
+        let mut removed = 0;
+        map.retain(|key, entry| {
+            if !key.as_ref().starts_with(prefix.as_ref()) {
+                return true;
+            }
+            if entry.is_live() {
+                removed += 1;
+            }
+            self.removed(key, entry);
+            false
         });
+        removed
+    }
+
+    fn ttl(&self, key: &K) -> Option<Option<Duration>> {
+        let map = self.read_map("ttl", Some(key));
+        let now = Instant::now();
+
+        map.get(key)
+            .filter(|entry| entry.is_live())
+            .map(|entry| entry.expires_at.map(|expires_at| expires_at.saturating_duration_since(now)))
+    }
+
+    fn get_many_ref(&self, keys: &[K], visit: &mut dyn FnMut(&K, Option<&V>)) {
+        let map = self.read_map("get_many_ref", None);
+
+        for key in keys {
+            let entry = map.get(key).filter(|entry| entry.is_live());
+            if let Some(entry) = entry {
+                entry.last_used.store(self.tick(), Ordering::Relaxed);
+            }
+            visit(key, entry.map(|entry| &entry.value));
+        }
+    }
+
+    fn keys(&self) -> Vec<K> {
+        let map = self.read_map("keys", None);
+
+        map.iter()
+            .filter(|(_, entry)| entry.is_live())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn entries(&self) -> Vec<(K, V, Option<Duration>)> {
+        let map = self.read_map("entries", None);
+        let now = Instant::now();
+
+        map.iter()
+            .filter(|(_, entry)| entry.is_live())
+            .map(|(key, entry)| {
+                let ttl = entry.expires_at.map(|expires_at| expires_at.saturating_duration_since(now));
+                (key.clone(), entry.value.clone(), ttl)
+            })
+            .collect()
+    }
+
+    fn stored_bytes(&self) -> u64
+    where
+        V: AsRef<[u8]>,
+    {
+        let map = self.read_map("stored_bytes", None);
+
+        map.values()
+            .filter(|entry| entry.is_live())
+            .map(|entry| entry.value.as_ref().len() as u64)
+            .sum()
+    }
+
+    fn memory_bytes(&self) -> Option<u64> {
+        Some(self.bytes.load(Ordering::Relaxed))
+    }
+
+    fn purge_expired(&mut self) -> usize {
+        let mut map = self.write_map("purge_expired", None);
+
+        let before = map.len();
+        map.retain(|key, entry| {
+            if !entry.is_live() {
+                self.removed(key, entry);
+            }
+            entry.is_live()
+        });
+        before - map.len()
+    }
+
+    fn evict_lru(&mut self) -> Option<K> {
+        let mut map = self.write_map("evict_lru", None);
+        let mut recency = self.recency();
+
+        // Note: Each key is requeued at most once per read since it was queued, so this takes amortized
+        //  logarithmic time rather than a scan of the map.
+        loop {
+            let (queued_at, key) = recency.pop_first()?;
+            let Some(entry) = map.get_mut(&key) else {
+                continue;
+            };
+            let last_used = entry.last_used.load(Ordering::Relaxed);
+            if last_used > queued_at && entry.is_live() {
+                entry.queued_at = last_used;
+                recency.insert(last_used, key);
+                continue;
+            }
+            if let Some(old) = map.remove(&key) {
+                self.sub_bytes(key.byte_size() + old.value.byte_size());
+            }
+            return Some(key);
+        }
+    }
+
+    fn poison_recoveries(&self) -> Option<u64> {
+        Some(self.poison_recoveries.load(Ordering::Relaxed))
+    }
+
+    fn name(&self) -> &'static str {
+        "InMemory"
+    }
+
+    // Note: A poisoned lock means a writer panicked and the map may be in an inconsistent state.
+    fn is_healthy(&self) -> bool {
+        !self.map.is_poisoned()
     }
 }
 
@@ -110,8 +481,128 @@ impl<K, V> InMemoryDatabase<K, V> {
     pub fn new() -> Self {
         InMemoryDatabase {
             map: Arc::new(RwLock::new(HashMap::new())),
+            poison_recoveries: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+            recency: Mutex::new(BTreeMap::new()),
+            hash_keys_in_logs: false,
+            value_sizes: None,
+        }
+    }
+
+    /// Sets whether keys are hashed in the logs of the database, see `ApplicationSettings::hash_keys_in_logs`.
+    pub fn hash_keys_in_logs(mut self, enabled: bool) -> Self {
+        self.hash_keys_in_logs = enabled;
+        self
+    }
+
+    /// Records the size of each written value in the histogram, e.g. for the value size metric.
+    pub fn observe_value_sizes(mut self, histogram: Arc<Histogram>) -> Self {
+        self.value_sizes = Some(histogram);
+        self
+    }
+
+    fn observe_value_size(&self, value: &V)
+    where
+        V: ByteSize,
+    {
+        if let Some(histogram) = &self.value_sizes {
+            histogram.observe(value.byte_size() as f64);
         }
     }
+
+    /// Creates an entry used now, queued in `recency`, under the write lock.
+    fn new_entry(&self, key: &K, value: V) -> Entry<V>
+    where
+        K: Clone,
+    {
+        let now = self.tick();
+        self.recency().insert(now, key.clone());
+        Entry::new(value, now)
+    }
+
+    /// Accounts for an entry removed from the map, under the write lock.
+    fn removed(&self, key: &K, old: &Entry<V>)
+    where
+        K: ByteSize,
+        V: ByteSize,
+    {
+        self.sub_bytes(key.byte_size() + old.value.byte_size());
+        self.recency().remove(&old.queued_at);
+    }
+
+    fn recency(&self) -> MutexGuard<'_, BTreeMap<u64, K>> {
+        self.recency.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Advances the logical clock, returning the new time.
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn add_bytes(&self, size: usize) {
+        self.bytes.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    fn sub_bytes(&self, size: usize) {
+        self.bytes.fetch_sub(size as u64, Ordering::Relaxed);
+    }
+
+    /// Removes the entry of a key if it expired, under the write lock.
+    fn drop_expired(&self, map: &mut HashMap<K, Entry<V>>, key: &K)
+    where
+        K: Eq + Hash + ByteSize,
+        V: ByteSize,
+    {
+        if map.get(key).is_some_and(|entry| !entry.is_live())
+            && let Some(old) = map.remove(key)
+        {
+            self.removed(key, &old);
+        }
+    }
+
+    /// Acquires the read lock, recovering from poisoning.
+    fn read_map(&self, operation: &str, key: Option<&K>) -> RwLockReadGuard<'_, HashMap<K, Entry<V>>>
+    where
+        K: Debug,
+    {
+        // Note: No need to clone `Arc<T>` explicitly as it implements the `Deref` trait:
+        //       https://doc.rust-lang.org/std/sync/struct.Arc.html#deref-behavior
+        self.map
+            .read()
+            .unwrap_or_else(|poisoned| self.recover(poisoned, operation, key))
+    }
+
+    /// Acquires the write lock, recovering from poisoning.
+    fn write_map(&self, operation: &str, key: Option<&K>) -> RwLockWriteGuard<'_, HashMap<K, Entry<V>>>
+    where
+        K: Debug,
+    {
+        self.map
+            .write()
+            .unwrap_or_else(|poisoned| self.recover(poisoned, operation, key))
+    }
+
+    // Note: This is just a hacky way to bypass lock poisoning for demo purposes. A panic while
+    //       holding the write lock may have left the map inconsistent, so at least make it visible.
+    fn recover<G>(&self, poisoned: PoisonError<G>, operation: &str, key: Option<&K>) -> G
+    where
+        K: Debug,
+    {
+        self.poison_recoveries.fetch_add(1, Ordering::Relaxed);
+        match key.map(|key| format!("{:?}", key)) {
+            Some(key) => warn!(
+                "Recovered from a poisoned lock during '{}' for key {}, data may be inconsistent.",
+                operation,
+                if self.hash_keys_in_logs { hash_for_logs(&key) } else { key }
+            ),
+            None => warn!(
+                "Recovered from a poisoned lock during '{}', data may be inconsistent.",
+                operation
+            ),
+        }
+        poisoned.into_inner()
+    }
 }
 
 /////////////////////////////////////////////////////////////////////////////////
@@ -119,6 +610,8 @@ impl<K, V> InMemoryDatabase<K, V> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TraceCapture;
+    use tracing::Level;
 
     #[test]
     fn test_in_memory_database() {
@@ -137,4 +630,183 @@ mod tests {
         db.remove(&key1);
         assert_eq!(db.read(&key1), None);
     }
+
+    #[test]
+    fn test_swap() {
+        let mut db = InMemoryDatabase::new();
+        let key = String::from("key");
+
+        assert_eq!(db.swap(&key, String::from("first")), None);
+        assert_eq!(db.swap(&key, String::from("second")), Some("first".to_string()));
+        assert_eq!(db.read(&key), Some("second".to_string()));
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut db = InMemoryDatabase::new();
+        assert!(db.is_empty());
+
+        db.upsert(&String::from("key"), String::from("value"));
+        assert_eq!(db.len(), 1);
+        assert!(!db.is_empty());
+        assert_eq!(db.stored_bytes(), 5);
+    }
+
+    #[test]
+    fn test_poison_recovery_is_reported() {
+        let capture = TraceCapture::default();
+        let _guard = capture.install();
+        let mut db = InMemoryDatabase::new();
+        let key = String::from("key");
+        db.upsert(&key, String::from("value"));
+
+        // Poison the lock by panicking while holding it.
+        let map = db.map.clone();
+        let _ = std::thread::spawn(move || {
+            let _map = map.write().unwrap();
+            panic!("Panic while holding the write lock");
+        })
+        .join();
+
+        assert_eq!(db.read(&key), Some("value".to_string()));
+        assert_eq!(db.poison_recoveries(), Some(1));
+        assert!(!db.is_healthy());
+
+        let events = capture.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, Level::WARN);
+        assert!(events[0].fields["message"].contains("'read' for key \"key\""));
+    }
+
+    #[test]
+    fn test_count_by_prefix() {
+        let mut db = InMemoryDatabase::new();
+        for key in ["user:1", "user:2", "order:1"] {
+            db.upsert(&key.to_string(), String::from("value"));
+        }
+
+        assert_eq!(db.count_by_prefix(&"user:".to_string()), 2);
+        assert_eq!(db.count_by_prefix(&"order:".to_string()), 1);
+        assert_eq!(db.count_by_prefix(&"item:".to_string()), 0);
+        assert_eq!(db.count_by_prefix(&String::new()), 3);
+    }
+
+    #[test]
+    fn test_clear_prefix() {
+        let mut db = InMemoryDatabase::new();
+        for key in ["user:1", "user:1:settings", "user:2", "order:1"] {
+            db.upsert(&key.to_string(), String::from("value"));
+        }
+
+        assert_eq!(db.clear_prefix(&"user:1".to_string()), 2);
+        assert_eq!(db.keys().len(), 2);
+        assert!(db.contains_key(&"user:2".to_string()));
+        assert!(db.contains_key(&"order:1".to_string()));
+        assert_eq!(db.memory_bytes(), Some(("user:2".len() + "order:1".len() + 10) as u64));
+        assert_eq!(db.clear_prefix(&"item:".to_string()), 0);
+    }
+
+    #[test]
+    fn test_memory_bytes() {
+        let mut db = InMemoryDatabase::new();
+        let key = String::from("key");
+        assert_eq!(db.memory_bytes(), Some(0));
+
+        db.upsert(&key, String::from("value"));
+        assert_eq!(db.memory_bytes(), Some(8));
+        db.update(&key, String::from("longer value"));
+        assert_eq!(db.memory_bytes(), Some(15));
+        db.swap(&key, String::from("v"));
+        assert_eq!(db.memory_bytes(), Some(4));
+        db.remove(&key);
+        assert_eq!(db.memory_bytes(), Some(0));
+    }
+
+    #[test]
+    fn test_evict_lru() {
+        let mut db = InMemoryDatabase::new();
+        for key in ["a", "b", "c"] {
+            db.upsert(&key.to_string(), String::from("value"));
+        }
+        // Reading "a" makes "b" the least recently used.
+        db.read(&"a".to_string());
+
+        assert_eq!(db.evict_lru(), Some("b".to_string()));
+        // Rewriting "c" makes "a" the least recently used.
+        db.upsert(&"c".to_string(), String::from("value"));
+        assert_eq!(db.evict_lru(), Some("a".to_string()));
+        assert_eq!(db.memory_bytes(), Some(6));
+        db.remove(&"c".to_string());
+        assert_eq!(db.evict_lru(), None);
+        assert!(db.recency().is_empty());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut db = InMemoryDatabase::new();
+        let key = String::from("key");
+        assert_eq!(db.ttl(&key), None);
+
+        db.upsert(&key, String::from("value"));
+        assert_eq!(db.ttl(&key), Some(None));
+
+        db.touch(&key, Duration::from_secs(60));
+        let ttl = db.ttl(&key).flatten().unwrap();
+        assert!(ttl > Duration::from_secs(59) && ttl <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_get_many_ref() {
+        let mut db = InMemoryDatabase::new();
+        db.upsert(&"a".to_string(), String::from("1"));
+        db.upsert(&"b".to_string(), String::from("2"));
+
+        let mut visited = Vec::new();
+        db.get_many_ref(&["b".to_string(), "missing".to_string(), "a".to_string()], &mut |key, value| {
+            visited.push(format!("{}={:?}", key, value))
+        });
+        assert_eq!(visited, ["b=Some(\"2\")", "missing=None", "a=Some(\"1\")"]);
+    }
+
+    // Note: The paused clock only advances explicitly, so the expiry doesn't depend on scheduling delays.
+    #[tokio::test(start_paused = true)]
+    async fn test_touch_extends_expiry() {
+        let mut db = InMemoryDatabase::new();
+        let key = String::from("session");
+        assert!(!db.touch(&key, Duration::from_secs(1)));
+
+        db.upsert(&key, String::from("value"));
+        assert!(db.touch(&key, Duration::from_secs(2)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(db.touch(&key, Duration::from_secs(6)));
+
+        // Past the original expiry.
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(db.read(&key), Some("value".to_string()));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(db.read(&key), None);
+        assert!(!db.contains_key(&key));
+        assert!(db.is_empty());
+        assert!(!db.touch(&key, Duration::from_secs(1)));
+        // Touching the expired key dropped it.
+        assert_eq!(db.memory_bytes(), Some(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_purge_expired() {
+        let mut db = InMemoryDatabase::new();
+        for key in ["a", "b", "c"] {
+            db.upsert(&key.to_string(), String::from("value"));
+        }
+        db.touch(&"a".to_string(), Duration::from_secs(1));
+        db.touch(&"b".to_string(), Duration::from_secs(10));
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(db.memory_bytes(), Some(18));
+        assert_eq!(db.purge_expired(), 1);
+        assert_eq!(db.memory_bytes(), Some(12));
+        assert_eq!(db.keys().len(), 2);
+        assert_eq!(db.purge_expired(), 0);
+    }
 }
@@ -0,0 +1,125 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::time::Instant;
+
+/// Approximate lock contention statistics, to diagnose throughput issues.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct LockStats {
+    /// Number of times the lock was acquired.
+    pub acquisitions: u64,
+    /// Number of acquisitions that had to wait because the lock was held.
+    pub contended: u64,
+    /// Average time spent acquiring the lock in nanoseconds.
+    pub average_wait_ns: u64,
+    /// Whether the lock has been poisoned by a panic while it was held.
+    pub poisoned: bool,
+}
+
+/// `RwLock` that keeps contention statistics, e.g. around the database that all handlers serialize on, to
+/// decide whether to shard it or lock per key.
+pub struct InstrumentedRwLock<T: ?Sized> {
+    /// Number of times the lock was acquired.
+    acquisitions: AtomicU64,
+    /// Number of acquisitions that had to wait because the lock was held.
+    contended: AtomicU64,
+    /// Total time spent acquiring the lock in nanoseconds.
+    wait_ns: AtomicU64,
+    // Note: Last, so the lock can hold an unsized value, e.g. `dyn KVDatabase`.
+    lock: RwLock<T>,
+}
+
+impl<T> InstrumentedRwLock<T> {
+    pub fn new(value: T) -> Self {
+        InstrumentedRwLock {
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            wait_ns: AtomicU64::new(0),
+            lock: RwLock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> InstrumentedRwLock<T> {
+    /// Acquires the read lock, see `RwLock::read`.
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        let started = Instant::now();
+        let guard = match self.lock.try_read() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Err(poisoned),
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.lock.read()
+            }
+        };
+        self.record_wait(started);
+        guard
+    }
+
+    /// Acquires the write lock, see `RwLock::write`.
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        let started = Instant::now();
+        let guard = match self.lock.try_write() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Err(poisoned),
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.lock.write()
+            }
+        };
+        self.record_wait(started);
+        guard
+    }
+
+    /// Whether the lock has been poisoned by a panic while it was held, see `RwLock::is_poisoned`.
+    pub fn is_poisoned(&self) -> bool {
+        self.lock.is_poisoned()
+    }
+
+    /// Contention statistics since the lock was created.
+    pub fn stats(&self) -> LockStats {
+        let acquisitions = self.acquisitions.load(Ordering::Relaxed);
+        LockStats {
+            acquisitions,
+            contended: self.contended.load(Ordering::Relaxed),
+            average_wait_ns: self.wait_ns.load(Ordering::Relaxed).checked_div(acquisitions).unwrap_or(0),
+            poisoned: self.lock.is_poisoned(),
+        }
+    }
+
+    fn record_wait(&self, started: Instant) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.wait_ns.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_lock_stats_report_contention() {
+        let lock = InstrumentedRwLock::new(0);
+
+        std::thread::scope(|scope| {
+            // Hold the write lock while readers try to acquire it.
+            let guard = lock.write().unwrap();
+            let readers: Vec<_> = (0..4).map(|_| scope.spawn(|| *lock.read().unwrap())).collect();
+            std::thread::sleep(Duration::from_millis(50));
+            drop(guard);
+            for reader in readers {
+                assert_eq!(reader.join().unwrap(), 0);
+            }
+        });
+
+        let stats = lock.stats();
+        assert_eq!(stats.acquisitions, 5);
+        // Note: Readers that only start after the write lock is released don't wait, e.g. on a loaded machine.
+        assert!(stats.contended >= 1, "{:?}", stats);
+        assert!(stats.average_wait_ns > 0);
+        assert!(!stats.poisoned);
+    }
+}
@@ -1 +1,5 @@
+pub mod chained;
+pub mod codec;
 pub mod db;
+pub mod lock;
+pub mod snapshot;
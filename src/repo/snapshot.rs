@@ -0,0 +1,169 @@
+use crate::repo::db::KVDatabase;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+// Note: A snapshot only captures the state at shutdown, so writes since the last graceful shutdown
+//       are lost on a crash. In exchange, it adds no overhead to each write.
+// Note: Values are saved in their stored form, e.g. encrypted by the `aes` codec, so a snapshot can only be
+//       loaded with the codec and key it was saved with.
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    key: String,
+    value: String,
+    /// Expiry as milliseconds since the Unix epoch, so the time the server was down counts towards the TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at_ms: Option<u128>,
+}
+
+/// Writes all entries of the database to a snapshot file, replacing any existing one.
+/// # Arguments
+/// * `db`: The database to snapshot.
+/// * `path`: Path of the snapshot file.
+pub fn save(db: &dyn KVDatabase<String, String>, path: &Path) -> std::io::Result<()> {
+    let now = unix_time();
+    let entries: Vec<_> = db
+        .stored_entries()
+        .into_iter()
+        .map(|(key, value, ttl)| SnapshotEntry {
+            key,
+            value,
+            expires_at_ms: ttl.map(|ttl| (now + ttl).as_millis()),
+        })
+        .collect();
+    let count = entries.len();
+
+    // Write to a temporary file first, so a failed write doesn't corrupt the previous snapshot.
+    let temp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    serde_json::to_writer(&mut writer, &Snapshot { entries })?;
+    writer.flush()?;
+    // Note: Without syncing, a crash right after the rename can leave an empty or partial snapshot on disk.
+    writer.get_ref().sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    sync_parent_dir(path)?;
+
+    info!("Saved {} entries to snapshot {:?}.", count, path);
+    Ok(())
+}
+
+/// Loads the entries of a snapshot file into the database. Does nothing if the file doesn't exist.
+/// # Arguments
+/// * `db`: The database to load into.
+/// * `path`: Path of the snapshot file.
+/// # Returns
+/// * `usize`: Number of entries loaded. Entries that expired in the meantime are skipped.
+pub fn load(db: &mut dyn KVDatabase<String, String>, path: &Path) -> std::io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!("No snapshot found at {:?}, starting empty.", path);
+            return Ok(0);
+        }
+        Err(err) => return Err(err),
+    };
+    let snapshot: Snapshot = serde_json::from_reader(BufReader::new(file))?;
+
+    let now = unix_time().as_millis();
+    let mut count = 0;
+    for entry in snapshot.entries {
+        match entry.expires_at_ms {
+            Some(expires_at_ms) if expires_at_ms <= now => continue,
+            Some(expires_at_ms) => {
+                db.upsert_stored(&entry.key, entry.value);
+                db.touch(&entry.key, Duration::from_millis((expires_at_ms - now) as u64));
+            }
+            None => db.upsert_stored(&entry.key, entry.value),
+        }
+        count += 1;
+    }
+
+    info!("Loaded {} entries from snapshot {:?}.", count, path);
+    Ok(count)
+}
+
+/// Syncs the directory containing the path, so a rename into it survives a crash.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    match path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => File::open(".")?.sync_all(),
+    }
+}
+
+// Note: Directories can't be opened for syncing on other platforms, so only the file is synced there.
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the Unix epoch.")
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::codec::{AesCodec, EncodedDatabase};
+    use crate::repo::db::InMemoryDatabase;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", Uuid::new_v4()));
+        let mut db = InMemoryDatabase::new();
+        assert_eq!(load(&mut db, &path).unwrap(), 0);
+
+        db.upsert(&"persistent".to_string(), "value".to_string());
+        db.upsert(&"expiring".to_string(), "value".to_string());
+        db.touch(&"expiring".to_string(), Duration::from_secs(60));
+        db.upsert(&"expired".to_string(), "value".to_string());
+        db.touch(&"expired".to_string(), Duration::ZERO);
+        save(&db, &path).unwrap();
+
+        // Simulate a restart.
+        let mut restored = InMemoryDatabase::new();
+        assert_eq!(load(&mut restored, &path).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.read(&"persistent".to_string()), Some("value".to_string()));
+        assert_eq!(restored.read(&"expired".to_string()), None);
+        let entries = restored.entries();
+        let (_, _, ttl) = entries.iter().find(|(key, _, _)| key == "expiring").unwrap();
+        assert!(ttl.unwrap() > Duration::from_secs(50));
+    }
+
+    #[test]
+    fn test_encoded_snapshot_is_not_plain_text() {
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", Uuid::new_v4()));
+        let encoded = || {
+            let codec = AesCodec::new(&BASE64.encode([7u8; 32])).unwrap();
+            EncodedDatabase::new(Box::new(InMemoryDatabase::new()), Box::new(codec))
+        };
+        let mut db = encoded();
+        db.upsert(&"key".to_string(), "plain text value".to_string());
+        save(&db, &path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("plain text value"));
+
+        let mut restored = encoded();
+        assert_eq!(load(&mut restored, &path).unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.read(&"key".to_string()), Some("plain text value".to_string()));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::configuration::ApplicationSettings;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use std::borrow::Cow;
+use tracing::error;
+
+/// Body of error responses produced by the middleware, e.g. on a timeout.
+///
+/// `code` is stable, e.g. `timeout` or `overloaded`, so clients can branch on it rather than on the message.
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: Cow<'static, str>,
+    /// Retry guidance of rejections by a limit, matching the `Retry-After` header.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryHint>,
+}
+
+/// When and why a rejected request can be retried, e.g. `{"retry_after_s": 1, "reason": "concurrency_limit"}`.
+#[derive(Serialize, Debug)]
+pub struct RetryHint {
+    pub retry_after_s: u64,
+    /// Stable name of the limit that rejected the request.
+    pub reason: &'static str,
+}
+
+impl ErrorBody {
+    pub fn new(code: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        ErrorBody {
+            code,
+            message: message.into(),
+            retry: None,
+        }
+    }
+
+    /// Adds retry guidance to the body.
+    pub fn with_retry(self, retry_after_s: u64, reason: &'static str) -> Self {
+        ErrorBody {
+            retry: Some(RetryHint { retry_after_s, reason }),
+            ..self
+        }
+    }
+}
+
+/// Uniform wrapper of response bodies, see `ApplicationSettings::envelope_responses`.
+#[derive(Serialize, Debug)]
+pub struct Envelope {
+    /// Body of a successful response, or `null` on errors.
+    pub data: serde_json::Value,
+    /// Body of an error response, e.g. an `ErrorBody`, or `null` on success.
+    pub error: Option<serde_json::Value>,
+    /// Trace ID of the request, if it got that far.
+    pub trace_id: Option<String>,
+}
+
+/// JSON response body, pretty-printed when `ApplicationSettings::pretty_json` is enabled.
+///
+/// Use this instead of `axum::Json` for responses, since the latter always writes compact JSON.
+pub struct JsonResponse<T> {
+    value: T,
+    pretty: bool,
+}
+
+impl<T: Serialize> JsonResponse<T> {
+    pub fn new(value: T, config: &ApplicationSettings) -> Self {
+        JsonResponse {
+            value,
+            pretty: config.pretty_json,
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for JsonResponse<T> {
+    fn into_response(self) -> Response {
+        let body = if self.pretty {
+            serde_json::to_string_pretty(&self.value)
+        } else {
+            serde_json::to_string(&self.value)
+        };
+        match body {
+            Ok(body) => ([(CONTENT_TYPE, "application/json")], body).into_response(),
+            Err(err) => {
+                error!("Failed to serialize response: {}", err);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
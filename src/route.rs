@@ -1,22 +1,356 @@
-use std::sync::Arc;
+use crate::admin::handler::get_admin_routes;
 use crate::api::handler::get_api_routes;
-use crate::configuration::Settings;
+use crate::configuration::Environment;
+use crate::debug::handler::get_debug_routes;
 use crate::dependency::ApplicationState;
+use crate::health::{read_health, read_liveness, read_readiness};
+use crate::response::JsonResponse;
+use crate::middleware::{
+    capture_request, limit_concurrency, limit_concurrency_by_method, limit_concurrency_per_client, record_latency,
+    reject_until_ready,
+};
+use axum::body::Bytes;
 use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware::from_fn_with_state;
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::Router;
+use serde::Serialize;
+use std::future::{ready, Ready};
+use std::sync::atomic::Ordering;
 
 /// Extension trait for adding routes to the server router.
 pub trait ApplicationRoute {
     /// Adds application-specific routes to the server router.
     /// # Arguments
-    /// * `config`: The global settings.
-    fn add_routes(self, config: Arc<Settings>) -> Self;
+    /// * `state`: The application state.
+    fn add_routes(self, state: &ApplicationState) -> Self;
 }
 
 impl ApplicationRoute for Router<ApplicationState> {
-    fn add_routes(self, _config: Arc<Settings>) -> Self {
-        self.route("/", get(|_: State<ApplicationState>| async { "Root dir" }))
-            .nest("/api", get_api_routes())
+    fn add_routes(self, state: &ApplicationState) -> Self {
+        let router = state
+            .config
+            .application
+            .static_routes
+            .iter()
+            .fold(self, |router, (path, body)| router.route(path, get(static_response(body))))
+            .route("/", get(read_status))
+            .nest(
+                "/api",
+                get_api_routes()
+                    .route_layer(from_fn_with_state(state.clone(), limit_concurrency_by_method)),
+            )
+            // Note: Layers only wrap the routes added before them, so the routes below are not
+            //       throttled, and the admin API stays reachable when the limit is lowered.
+            .route_layer(from_fn_with_state(state.clone(), limit_concurrency_per_client))
+            .route_layer(from_fn_with_state(state.clone(), limit_concurrency))
+            // Note: Outside the global limiter, so requests rejected during startup don't take a slot.
+            .route_layer(from_fn_with_state(state.clone(), reject_until_ready))
+            .route_layer(from_fn_with_state(state.request_capture.clone(), capture_request))
+            .route("/metrics", get(read_metrics))
+            .route("/health", get(read_liveness))
+            .route("/ready", get(read_readiness))
+            .route("/healthz", get(read_health))
+            .nest("/admin", get_admin_routes())
+            // Note: Added last, so every route above is recorded unless excluded in the settings.
+            .route_layer(from_fn_with_state(state.clone(), record_latency));
+
+        // Captured requests may contain personal data, so they are never exposed outside local.
+        if state.config.environment == Environment::Local.as_str() {
+            router.nest("/debug", get_debug_routes())
+        } else {
+            router
+        }
+    }
+}
+
+/// Builds the handler of a static route, which always responds with the given JSON.
+fn static_response(body: &serde_json::Value) -> impl Fn() -> Ready<Response> + Clone + Send + Sync + 'static {
+    // Note: Serialized once, and `Bytes` clones share the buffer.
+    let body = Bytes::from(body.to_string());
+    move || ready(([(CONTENT_TYPE, "application/json")], body.clone()).into_response())
+}
+
+#[derive(Serialize)]
+struct Status {
+    backend: &'static str,
+    healthy: bool,
+    /// Whether the backend has no entries, e.g. to alert on an unexpectedly empty store after a deploy.
+    empty: bool,
+}
+
+/// Handler function to report the active backend, whether it is healthy, and whether it is empty.
+/// # Arguments
+/// * `state`: The application state.
+async fn read_status(State(state): State<ApplicationState>) -> JsonResponse<Status> {
+    let db = state.db.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    JsonResponse::new(
+        Status {
+            backend: db.name(),
+            healthy: db.is_healthy(),
+            empty: db.is_empty(),
+        },
+        &state.config.application,
+    )
+}
+
+/// Handler function to render the metrics in the Prometheus text format.
+/// # Arguments
+/// * `state`: The application state.
+async fn read_metrics(State(state): State<ApplicationState>) -> String {
+    let db = state.db.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Note: Backends that don't count their size are scanned instead.
+    let stored_bytes = db.memory_bytes().unwrap_or_else(|| db.stored_bytes());
+    state.metrics.stored_bytes.store(stored_bytes, Ordering::Relaxed);
+    state.metrics.poison_recoveries.store(db.poison_recoveries().unwrap_or(0), Ordering::Relaxed);
+    drop(db);
+    state.metrics.render()
+}
+
+/////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::build_app;
+    use crate::test_util::{body_string, send, test_settings};
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use axum::http::header::RETRY_AFTER;
+    use axum::http::{Request, StatusCode};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_root_reports_backend() {
+        let app = build_app(ApplicationState::new(Arc::new(test_settings())));
+
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, r#"{"backend":"InMemory","healthy":true,"empty":true}"#);
+    }
+
+    #[tokio::test]
+    async fn test_pretty_json() {
+        let mut settings = test_settings();
+        settings.application.pretty_json = true;
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(
+            body_string(response).await,
+            "{\n  \"backend\": \"InMemory\",\n  \"healthy\": true,\n  \"empty\": true\n}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_static_routes() {
+        let mut settings = test_settings();
+        let flags = serde_json::json!({ "dark_mode": true, "max_items": 10 });
+        settings.application.static_routes.insert("/feature-flags".to_string(), flags.clone());
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+
+        let response = send(&app, Request::get("/feature-flags").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body, flags);
+        assert!(state.db.read().unwrap().is_empty());
+
+        let request = Request::post("/feature-flags").body(Body::empty()).unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_until_backend_is_ready() {
+        let state = ApplicationState::new(Arc::new(test_settings()));
+        let app = build_app(state.clone());
+        // Simulate a backend that takes a while to connect.
+        state.set_ready(false);
+        let backend = state.clone();
+        let initialization = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            backend.set_ready(true);
+        });
+
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_string(response).await, "Service is starting up, try again later.");
+        // Rejected requests never take a slot of the global limiter, even with no slots at all.
+        state.limiter.set_limit(0);
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(body_string(response).await, "Service is starting up, try again later.");
+        state.limiter.set_limit(state.config.application.max_concurrent_requests);
+
+        initialization.await.unwrap();
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_use_configured_latency_buckets() {
+        let mut settings = test_settings();
+        settings.application.latency_buckets_ms = vec![60_000.0];
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let response = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        let body = body_string(response).await;
+        assert!(body.contains("http_request_duration_ms_bucket{le=\"60000\"} 1\n"));
+        assert!(body.contains("http_request_duration_ms_bucket{le=\"+Inf\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_exclude_routes() {
+        let mut settings = test_settings();
+        settings.application.metrics_exclude = vec!["/metrics".to_string(), "GET /api/{key}".to_string()];
+        let app = build_app(ApplicationState::new(Arc::new(settings)));
+        for uri in ["/", "/metrics", "/health", "/api/key"] {
+            send(&app, Request::get(uri).body(Body::empty()).unwrap()).await;
+        }
+
+        let response = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        let body = body_string(response).await;
+        assert!(body.contains("http_requests_total{method=\"GET\",route=\"/\"} 1\n"));
+        assert!(body.contains("http_requests_total{method=\"GET\",route=\"/health\"} 1\n"));
+        assert!(!body.contains("route=\"/metrics\""));
+        assert!(!body.contains("route=\"/api/{key}\""));
+        assert!(body.contains("http_request_duration_ms_count 2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_client_cannot_starve_others() {
+        let mut settings = test_settings();
+        settings.application.max_concurrent_per_client = 2;
+        settings.application.retry_after_s = 3;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let request = |client: [u8; 4]| {
+            let mut request = Request::get("/").body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from((client, 4000))));
+            request
+        };
+        // The aggressive client has as many requests in flight as it's allowed.
+        let _permits = [
+            state.client_limiter.try_acquire("ip:10.0.0.1").unwrap(),
+            state.client_limiter.try_acquire("ip:10.0.0.1").unwrap(),
+        ];
+
+        let response = send(&app, request([10, 0, 0, 1])).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "3");
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["retry_after_s"], 3);
+        assert_eq!(body["reason"], "client_concurrency_limit");
+        let response = send(&app, request([10, 0, 0, 2])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_overload_has_retry_hint() {
+        let mut settings = test_settings();
+        settings.application.max_concurrent_requests = 1;
+        settings.application.retry_after_s = 5;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        let _permit = state.limiter.try_acquire().unwrap();
+
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "5");
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["code"], "overloaded");
+        assert_eq!(body["retry_after_s"], 5);
+        assert_eq!(body["reason"], "concurrency_limit");
+    }
+
+    #[tokio::test]
+    async fn test_probes_bypass_concurrency_limits() {
+        let mut settings = test_settings();
+        settings.application.max_concurrent_requests = 1;
+        settings.application.max_concurrent_per_client = 1;
+        settings.application.client_api_keys = vec!["client".to_string()];
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        // Saturate the global and per-client limits, as an overload of `/api` would.
+        let _permit = state.limiter.try_acquire().unwrap();
+        let _client_permit = state.client_limiter.try_acquire("key:client").unwrap();
+        let request = |path: &str| {
+            Request::get(path).header("X-API-Key", "client").body(Body::empty()).unwrap()
+        };
+
+        assert_eq!(send(&app, request("/api/key")).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        for path in ["/health", "/ready", "/healthz", "/metrics"] {
+            assert_eq!(send(&app, request(path)).await.status(), StatusCode::OK, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_burst_waits_for_a_slot() {
+        let mut settings = test_settings();
+        settings.application.max_concurrent_requests = 1;
+        settings.application.shed_after_ms = 5_000;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        // A request in flight that completes shortly.
+        let permit = state.limiter.try_acquire().unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            drop(permit);
+        });
+
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_is_rejected() {
+        let mut settings = test_settings();
+        settings.application.max_concurrent_requests = 1;
+        settings.application.shed_after_ms = 5_000;
+        settings.application.max_queued_requests = Some(1);
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        // A request in flight, and another one queued behind it.
+        let _permit = state.limiter.try_acquire().unwrap();
+        let queued_app = app.clone();
+        let queued = tokio::spawn(async move {
+            send(&queued_app, Request::get("/").body(Body::empty()).unwrap()).await.status()
+        });
+        while state.limiter.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let response = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(body["code"], "queue_full");
+        assert_eq!(body["message"], "Request queue is full, try again later.");
+        queued.abort();
+    }
+
+    #[tokio::test]
+    async fn test_reads_succeed_while_writes_are_saturated() {
+        let mut settings = test_settings();
+        settings.application.max_concurrent_writes = 1;
+        let state = ApplicationState::new(Arc::new(settings));
+        let app = build_app(state.clone());
+        // Occupy the only write slot as an in-flight write would.
+        let _permit = state.write_limiter.try_acquire().unwrap();
+
+        let request = Request::post("/api/key")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"value": "value"}"#))
+            .unwrap();
+        assert_eq!(send(&app, request).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = send(&app, Request::get("/api/key").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 }
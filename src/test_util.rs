@@ -0,0 +1,235 @@
+use crate::configuration::{load_configuration, Settings};
+use crate::repo::db::{InMemoryDatabase, KVDatabase};
+use axum::body::Body;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+use config::Map;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::DefaultGuard;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::{Layer, Registry};
+use http_body_util::BodyExt;
+use tower::ServiceExt;
+
+/// Reads the settings for the local environment from the `configuration` directory,
+/// ignoring the environment variables of the test process.
+///
+/// JSON responses are compact, so tests can compare response bodies as strings.
+pub(crate) fn test_settings() -> Settings {
+    // Note: Explicit, since builds with the `default-prod` feature default to `Prod`.
+    let env_vars = Map::from([("APP_ENVIRONMENT".to_string(), "local".to_string())]);
+    let mut settings =
+        load_configuration(Path::new("configuration"), env_vars).expect("Failed to read configuration.");
+    settings.application.pretty_json = false;
+    settings
+}
+
+/// Sends a single request through the router.
+pub(crate) async fn send(app: &Router, request: Request<Body>) -> Response {
+    app.clone().oneshot(request).await.unwrap()
+}
+
+/// Collects the response body into a string.
+pub(crate) async fn body_string(response: Response) -> String {
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+type ReadHook = Arc<dyn Fn(&String) + Send + Sync>;
+
+/// In-memory backend that delegates to an `InMemoryDatabase` shared with the test, so it can inspect what's
+/// actually stored, with hooks that override single methods, e.g. to count calls or simulate an outage.
+#[derive(Clone, Default)]
+pub(crate) struct TestDatabase {
+    inner: Arc<RwLock<InMemoryDatabase<String, String>>>,
+    on_read: Option<ReadHook>,
+    is_healthy: Option<Arc<dyn Fn() -> bool + Send + Sync>>,
+}
+
+impl TestDatabase {
+    /// Calls the hook before each read, including the reads of a multi-get.
+    pub(crate) fn on_read(mut self, hook: impl Fn(&String) + Send + Sync + 'static) -> Self {
+        self.on_read = Some(Arc::new(hook));
+        self
+    }
+
+    /// Reports the backend as healthy only while the hook returns `true`.
+    pub(crate) fn healthy_when(mut self, hook: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        self.is_healthy = Some(Arc::new(hook));
+        self
+    }
+
+    /// The underlying database, as stored by the backend.
+    pub(crate) fn stored(&self) -> RwLockReadGuard<'_, InMemoryDatabase<String, String>> {
+        self.inner.read().unwrap()
+    }
+}
+
+impl KVDatabase<String, String> for TestDatabase {
+    fn upsert(&mut self, key: &String, value: String) {
+        self.inner.write().unwrap().upsert(key, value)
+    }
+
+    fn swap(&mut self, key: &String, value: String) -> Option<String> {
+        self.inner.write().unwrap().swap(key, value)
+    }
+
+    fn read(&self, key: &String) -> Option<String> {
+        if let Some(hook) = &self.on_read {
+            hook(key);
+        }
+        self.stored().read(key)
+    }
+
+    fn contains_key(&self, key: &String) -> bool {
+        self.stored().contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.stored().len()
+    }
+
+    fn remove(&self, key: &String) {
+        self.stored().remove(key)
+    }
+
+    fn update(&mut self, key: &String, new_value: String) {
+        self.inner.write().unwrap().update(key, new_value)
+    }
+
+    fn touch(&mut self, key: &String, ttl: Duration) -> bool {
+        self.inner.write().unwrap().touch(key, ttl)
+    }
+
+    fn ttl(&self, key: &String) -> Option<Option<Duration>> {
+        self.stored().ttl(key)
+    }
+
+    fn count_by_prefix(&self, prefix: &String) -> usize {
+        self.stored().count_by_prefix(prefix)
+    }
+
+    fn clear_prefix(&mut self, prefix: &String) -> usize {
+        self.inner.write().unwrap().clear_prefix(prefix)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.stored().keys()
+    }
+
+    fn entries(&self) -> Vec<(String, String, Option<Duration>)> {
+        self.stored().entries()
+    }
+
+    fn stored_bytes(&self) -> u64 {
+        self.stored().stored_bytes()
+    }
+
+    fn memory_bytes(&self) -> Option<u64> {
+        self.stored().memory_bytes()
+    }
+
+    fn purge_expired(&mut self) -> usize {
+        self.inner.write().unwrap().purge_expired()
+    }
+
+    fn evict_lru(&mut self) -> Option<String> {
+        self.inner.write().unwrap().evict_lru()
+    }
+
+    fn poison_recoveries(&self) -> Option<u64> {
+        self.stored().poison_recoveries()
+    }
+
+    fn name(&self) -> &'static str {
+        "Test"
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.is_healthy.as_ref().is_none_or(|hook| hook())
+    }
+}
+
+/// A span or event recorded by `TraceCapture`.
+#[derive(Clone, Debug)]
+pub(crate) struct CapturedTrace {
+    pub name: String,
+    pub level: Level,
+    pub fields: HashMap<String, String>,
+}
+
+/// Tracing layer that records spans and events so tests can assert on them.
+///
+/// Install it for the current thread with `TraceCapture::install`.
+#[derive(Clone, Default)]
+pub(crate) struct TraceCapture {
+    spans: Arc<Mutex<HashMap<Id, CapturedTrace>>>,
+    events: Arc<Mutex<Vec<CapturedTrace>>>,
+}
+
+impl TraceCapture {
+    /// Sets a subscriber with this layer as the default until the guard is dropped.
+    pub(crate) fn install(&self) -> DefaultGuard {
+        tracing::subscriber::set_default(Registry::default().with(self.clone()))
+    }
+
+    /// Returns the captured spans with the given name.
+    pub(crate) fn spans(&self, name: &str) -> Vec<CapturedTrace> {
+        let spans = self.spans.lock().unwrap();
+        spans.values().filter(|span| span.name == name).cloned().collect()
+    }
+
+    /// Returns all captured events.
+    pub(crate) fn events(&self) -> Vec<CapturedTrace> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+impl<S: Subscriber> Layer<S> for TraceCapture {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _ctx: Context<'_, S>) {
+        let mut fields = HashMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        let span = CapturedTrace {
+            name: attrs.metadata().name().to_string(),
+            level: *attrs.metadata().level(),
+            fields,
+        };
+        self.spans.lock().unwrap().insert(id.clone(), span);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(id) {
+            values.record(&mut FieldVisitor(&mut span.fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = HashMap::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        self.events.lock().unwrap().push(CapturedTrace {
+            name: event.metadata().name().to_string(),
+            level: *event.metadata().level(),
+            fields,
+        });
+    }
+}
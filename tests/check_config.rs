@@ -0,0 +1,28 @@
+use std::process::Command;
+
+fn check_config(env_vars: &[(&str, &str)]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_axumdemo"))
+        .arg("--check-config")
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env_clear()
+        .envs(env_vars.iter().copied())
+        .output()
+        .expect("Failed to run the binary.")
+}
+
+#[test]
+fn test_check_config_accepts_valid_config() {
+    let output = check_config(&[]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Configuration is valid.\n");
+}
+
+#[test]
+fn test_check_config_rejects_invalid_config() {
+    let output = check_config(&[("APP_APPLICATION__COMPRESSION_LEVEL", "10")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Invalid configuration: application.compression_level must be between 0 and 9, got 10.\n"
+    );
+}